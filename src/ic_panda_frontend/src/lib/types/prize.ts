@@ -1,8 +1,8 @@
 import { base64ToBytes } from '$lib/utils/crypto'
 import { decode } from 'cborg'
 
-//(Issuer code, Issue time, Expire, Claimable amount, Quantity)
-export type Prize = [number, number, number, number, number]
+//(Issuer code, Issue time, Expire, Claimable amount, Quantity, Nonce)
+export type Prize = [number, number, number, number, number, number?]
 
 export function decodePrize(prize: string): Prize | null {
   if (prize.startsWith('PRIZE:')) prize = prize.slice(6)
@@ -1,40 +1,217 @@
 use crate::{
-    icp_transfer_to, is_authenticated, is_controller, store, token_balance_of, types, ANONYMOUS,
-    DAO_CANISTER, ICP_1, ICP_CANISTER, SECOND, TRANS_FEE,
+    api_update, is_authenticated, is_controller, ledger_transfer_to, operation_memo, store,
+    token_balance_of, types, ANONYMOUS, DAO_CANISTER, ICP_CANISTER, SECOND, TOKEN_1,
+    TOKEN_CANISTER,
 };
 use base64::{engine::general_purpose, Engine};
 use candid::{Nat, Principal};
-use lib_panda::bytes32_from_base64;
-use std::collections::BTreeSet;
+use lib_panda::{bytes32_from_base64, sha3_256};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[ic_cdk::update(guard = "is_controller")]
 async fn admin_collect_icp(amount: Nat) -> Result<(), String> {
-    icp_transfer_to(DAO_CANISTER, amount)
-        .await
-        .map_err(|err| format!("failed to collect ICP, {}", err))?;
-    Ok(())
+    admin_collect_token(ICP_CANISTER, amount).await
 }
 
 #[ic_cdk::update]
 async fn validate_admin_collect_icp(amount: Nat) -> Result<(), String> {
-    if amount < ICP_1 {
-        return Err("amount must be at least 1 ICP".to_string());
+    validate_admin_collect_token(ICP_CANISTER, amount).await
+}
+
+// Collect the tokens of an accepted payment ledger to the DAO.
+#[ic_cdk::update(guard = "is_controller")]
+async fn admin_collect_token(ledger: Principal, amount: Nat) -> Result<(), String> {
+    validate_admin_collect_token(ledger, amount.clone()).await?;
+    let now_sec = ic_cdk::api::time() / SECOND;
+    ledger_transfer_to(
+        ledger,
+        DAO_CANISTER,
+        amount.clone(),
+        operation_memo("collect", store::state::next_operation_id()),
+        now_sec * SECOND,
+    )
+    .await
+    .map_err(|err| format!("failed to collect tokens, {}", err))?;
+    audit("admin_collect_token", &(ledger, amount), None::<&()>);
+    Ok(())
+}
+
+#[ic_cdk::update]
+async fn validate_admin_collect_token(ledger: Principal, amount: Nat) -> Result<(), String> {
+    // PANDA tokens paid for luckydraw are burned by the burn timer
+    if ledger == TOKEN_CANISTER {
+        return Err("PANDA tokens can not be collected".to_string());
+    }
+    let token =
+        store::payment_token::get(&ledger).ok_or("unsupported payment token".to_string())?;
+    if amount < token.per_icp {
+        return Err(format!(
+            "amount must be at least 1 ICP equivalent: {}",
+            token.per_icp
+        ));
     }
 
-    let balance = token_balance_of(ICP_CANISTER, ic_cdk::id())
+    let balance = token_balance_of(ledger, ic_cdk::id())
         .await
         .unwrap_or(Nat::from(0u64));
 
-    if amount + TRANS_FEE > balance {
-        return Err(format!("insufficient ICP balance: {}", balance));
+    if amount + token.fee > balance {
+        return Err(format!(
+            "insufficient {} balance: {}",
+            token.symbol, balance
+        ));
+    }
+
+    Ok(())
+}
+
+// Accept a token for luckydraw or update its price ratio.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_payment_token(ledger: Principal, token: types::PaymentToken) -> Result<(), String> {
+    validate_admin_set_payment_token(ledger, token.clone())?;
+    audit(
+        "admin_set_payment_token",
+        &(ledger, &token),
+        store::payment_token::get(&ledger).as_ref(),
+    );
+    store::payment_token::set(ledger, token);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_payment_token(
+    ledger: Principal,
+    token: types::PaymentToken,
+) -> Result<(), String> {
+    if ledger == ICP_CANISTER {
+        return Err("ICP is always accepted".to_string());
+    }
+    token.validate()
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_remove_payment_token(ledger: Principal) -> Result<(), String> {
+    let token =
+        store::payment_token::remove(&ledger).ok_or("payment token not found".to_string())?;
+    audit("admin_remove_payment_token", &ledger, Some(&token));
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_remove_payment_token(ledger: Principal) -> Result<(), String> {
+    if ledger == ICP_CANISTER {
+        return Err("ICP is always accepted".to_string());
+    }
+    store::payment_token::get(&ledger)
+        .map(|_| ())
+        .ok_or("payment token not found".to_string())
+}
+
+// Set a new payout table of luckydraw.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_luckydraw_table(args: types::LuckyDrawTable) -> Result<u32, String> {
+    args.validate(store::state::luckydraw_max_expected())?;
+    let (_, prev) = store::luckydraw_table::active();
+    audit("admin_set_luckydraw_table", &args, Some(&prev));
+    Ok(store::luckydraw_table::set(args))
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_luckydraw_table(args: types::LuckyDrawTable) -> Result<(), String> {
+    args.validate(store::state::luckydraw_max_expected())
+}
+
+// Set the max expected tokens for 1 ICP that a payout table can pay out.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_luckydraw_max_expected(args: u64) -> Result<(), String> {
+    validate_admin_set_luckydraw_max_expected(args)?;
+    let prev = store::state::with_mut(|r| r.luckydraw_max_expected.replace(args));
+    audit("admin_set_luckydraw_max_expected", &args, prev.as_ref());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_luckydraw_max_expected(args: u64) -> Result<(), String> {
+    let (_, table) = store::luckydraw_table::active();
+    if args < table.expected() {
+        return Err(format!(
+            "max expected should not be less than the active payout table: {}",
+            table.expected()
+        ));
     }
+    Ok(())
+}
+
+// Set the share of each draw's ICP in PANDA equivalent added to the progressive jackpot pool,
+// in basis points.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_luckydraw_jackpot_share(args: u16) -> Result<(), String> {
+    validate_admin_set_luckydraw_jackpot_share(args)?;
+    let prev = store::state::with_mut(|r| r.jackpot_share.replace(args));
+    audit("admin_set_luckydraw_jackpot_share", &args, prev.as_ref());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_luckydraw_jackpot_share(args: u16) -> Result<(), String> {
+    if args > 2_000 {
+        return Err("jackpot share should be at most 2000 basis points".to_string());
+    }
+    Ok(())
+}
 
+// Upload the wasm of the archive canister, it is installed when the archive is spawned.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_archive_wasm(wasm: serde_bytes::ByteBuf) -> Result<(), String> {
+    validate_admin_set_archive_wasm(wasm.clone())?;
+    let prev = sha3_256(&store::archive::wasm());
+    audit("admin_set_archive_wasm", &wasm, Some(&ByteBuf::from(prev)));
+    store::archive::set_wasm(wasm.into_vec());
     Ok(())
 }
 
+#[ic_cdk::update]
+fn validate_admin_set_archive_wasm(wasm: serde_bytes::ByteBuf) -> Result<(), String> {
+    // a wasm module or a gzipped one
+    if !wasm.starts_with(b"\0asm") && !wasm.starts_with(&[0x1f, 0x8b]) {
+        return Err("invalid wasm module".to_string());
+    }
+    Ok(())
+}
+
+// Set the number of local logs of each kind that triggers moving the old ones to the archive.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_archive_threshold(args: u64) -> Result<(), String> {
+    validate_admin_set_archive_threshold(args)?;
+    let prev = store::state::with_mut(|r| r.archive_threshold.replace(args));
+    audit("admin_set_archive_threshold", &args, prev.as_ref());
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_archive_threshold(args: u64) -> Result<(), String> {
+    if !(10_000..=1_000_000).contains(&args) {
+        return Err("archive threshold should be in [10_000, 1_000_000]".to_string());
+    }
+    Ok(())
+}
+
+// The time in seconds of the last checkpoint of the heap state into stable memory.
+#[ic_cdk::query(guard = "is_controller")]
+fn admin_last_checkpoint() -> Result<Option<u64>, String> {
+    Ok(store::state::with(|r| r.checkpointed_at))
+}
+
 // Set the managers.
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_set_managers(args: BTreeSet<Principal>) -> Result<(), String> {
+    audit(
+        "admin_set_managers",
+        &args,
+        store::state::with(|r| r.managers.clone()).as_ref(),
+    );
     store::state::with_mut(|r| {
         r.managers = Some(args);
     });
@@ -52,101 +229,219 @@ fn validate_admin_set_managers(args: BTreeSet<Principal>) -> Result<(), String>
     Ok(())
 }
 
+// Set the scoped roles of managers, the legacy managers are ignored once the roles are set.
+// None clears the roles and restores the legacy managers.
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_roles(args: Option<BTreeMap<Principal, BTreeSet<types::Role>>>) -> Result<(), String> {
+    validate_admin_set_roles(args.clone())?;
+    audit(
+        "admin_set_roles",
+        &args,
+        store::state::with(|r| r.roles.clone()).as_ref(),
+    );
+    store::state::with_mut(|r| {
+        r.roles = args;
+    });
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_roles(
+    args: Option<BTreeMap<Principal, BTreeSet<types::Role>>>,
+) -> Result<(), String> {
+    let args = match args {
+        Some(args) => args,
+        None => return Ok(()),
+    };
+    if args.is_empty() {
+        return Err("roles cannot be empty".to_string());
+    }
+    if args.contains_key(&ANONYMOUS) {
+        return Err("anonymous user is not allowed".to_string());
+    }
+    if args.values().any(|rs| rs.is_empty()) {
+        return Err("user should have at least one role".to_string());
+    }
+    Ok(())
+}
+
 #[ic_cdk::update(guard = "is_authenticated")]
 fn manager_update_airdrop_balance(airdrop_balance: u64) -> Result<(), String> {
-    if !store::state::is_manager(&ic_cdk::caller()) {
-        return Err("user is not a manager".to_string());
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Treasurer) {
+        return Err("user is not a treasurer".to_string());
     }
-    store::state::with_mut(|state| state.airdrop_balance = airdrop_balance);
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let prev = store::airdrop::adjust_balance(ic_cdk::caller(), now_sec, airdrop_balance)?;
+    audit(
+        "manager_update_airdrop_balance",
+        &airdrop_balance,
+        Some(&prev),
+    );
     Ok(())
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
 fn manager_update_airdrop_amount(airdrop_amount: u64) -> Result<(), String> {
-    if !store::state::is_manager(&ic_cdk::caller()) {
-        return Err("user is not a manager".to_string());
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Treasurer) {
+        return Err("user is not a treasurer".to_string());
     }
     if airdrop_amount > 100 {
         return Err("airdrop amount should be less than 100 tokens".to_string());
     }
 
-    store::state::with_mut(|state| state.airdrop_amount = Some(airdrop_amount));
+    let prev = store::state::with_mut(|state| state.airdrop_amount.replace(airdrop_amount));
+    audit(
+        "manager_update_airdrop_amount",
+        &airdrop_amount,
+        prev.as_ref(),
+    );
     Ok(())
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
 fn manager_add_notification(args: types::Notification) -> Result<(), String> {
-    if !store::state::is_manager(&ic_cdk::caller()) {
-        return Err("user is not a manager".to_string());
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Notifier) {
+        return Err("user is not a notifier".to_string());
     }
-    store::notification::add(args);
+    args.validate()?;
+    let prev = store::notification::list()
+        .into_iter()
+        .find(|n| n.id == args.id);
+    audit("manager_add_notification", &args, prev.as_ref());
+    store::notification::add(args, ic_cdk::api::time() / SECOND);
     Ok(())
 }
 
+// All notifications, including the scheduled ones and their audiences.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn manager_notifications() -> Result<Vec<types::Notification>, String> {
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Notifier) {
+        return Err("user is not a notifier".to_string());
+    }
+    Ok(store::notification::list())
+}
+
 #[ic_cdk::update(guard = "is_authenticated")]
 fn manager_remove_notifications(ids: Vec<u8>) -> Result<(), String> {
-    if !store::state::is_manager(&ic_cdk::caller()) {
-        return Err("user is not a manager".to_string());
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Notifier) {
+        return Err("user is not a notifier".to_string());
     }
+    let prev: Vec<types::Notification> = store::notification::list()
+        .into_iter()
+        .filter(|n| ids.contains(&n.id))
+        .collect();
+    audit("manager_remove_notifications", &ids, Some(&prev));
     store::notification::remove(ids);
     Ok(())
 }
 
-#[ic_cdk::update(guard = "is_authenticated")]
-fn manager_ban_users(ids: Vec<Principal>) -> Result<(), String> {
-    if !store::state::is_manager(&ic_cdk::caller()) {
-        return Err("user is not a manager".to_string());
+// The luckydraws whose payouts can not be resent after the deduplication window,
+// check them on the ledger and resolve them with manager_resolve_luckydraw.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn manager_unresolved_luckydraws() -> Result<Vec<types::LuckyDrawJournal>, String> {
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Treasurer) {
+        return Err("user is not a treasurer".to_string());
     }
-    store::airdrop::ban_users(ids)
+    Ok(api_update::unresolved_luckydraw_journal())
 }
 
-#[ic_cdk::query(guard = "is_authenticated")]
-fn manager_get_airdrop_key() -> Result<String, String> {
-    if !store::state::is_manager(&ic_cdk::caller()) {
-        return Err("user is not a manager".to_string());
+#[ic_cdk::update(guard = "is_authenticated")]
+fn manager_resolve_luckydraw(
+    id: u64,
+    resolution: types::LuckyDrawResolution,
+) -> Result<(), String> {
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Treasurer) {
+        return Err("user is not a treasurer".to_string());
     }
-    Ok(general_purpose::URL_SAFE_NO_PAD.encode(*store::keys::AIRDROP_KEY))
+    let prev = api_update::resolve_luckydraw_journal(id, resolution)?;
+    audit("manager_resolve_luckydraw", &(id, resolution), Some(&prev));
+    Ok(())
 }
 
+// Ban users with a reason and an optional expiry time in seconds.
 #[ic_cdk::update(guard = "is_authenticated")]
-fn manager_add_prize(args: types::AddPrizeInput) -> Result<String, String> {
+fn manager_ban_users(
+    ids: Vec<Principal>,
+    reason: Option<String>,
+    expire_at: Option<u64>,
+) -> Result<(), String> {
     let caller = ic_cdk::caller();
-    if !store::state::is_manager(&caller) {
-        return Err("user is not a manager".to_string());
+    if !store::state::has_role(&caller, types::Role::Moderator) {
+        return Err("user is not a moderator".to_string());
     }
     let now_sec = ic_cdk::api::time() / SECOND;
-    if args.expire < 10 {
-        return Err("expire should be at least 10 minutes".to_string());
+    if expire_at.is_some_and(|t| t <= now_sec) {
+        return Err("expire_at should be in the future".to_string());
     }
-    if args.claimable == 0 {
-        return Err("claimable should be at least 1 token".to_string());
+    let reason = reason.unwrap_or_default();
+    if reason.len() > 256 {
+        return Err("reason should be at most 256 bytes".to_string());
     }
-    if args.quantity == 0 {
-        return Err("quantity should be at least 1".to_string());
+    let prev: Vec<(Principal, Option<store::AirdropState>)> = ids
+        .iter()
+        .map(|id| (*id, store::airdrop::state_of(id)))
+        .collect();
+    audit(
+        "manager_ban_users",
+        &(&ids, &reason, &expire_at),
+        Some(&prev),
+    );
+    store::airdrop::ban_users(ids, reason, caller, expire_at, now_sec)
+}
+
+// Unban users and restore their lucky codes.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn manager_unban_users(ids: Vec<Principal>) -> Result<(), String> {
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::Moderator) {
+        return Err("user is not a moderator".to_string());
     }
-    if args.expire > 60 * 24 * 30 {
-        return Err("expire should be less than 60*24*30".to_string());
+    let prev: Vec<(Principal, Option<store::BanState>)> =
+        ids.iter().map(|id| (*id, store::ban::get(id))).collect();
+    audit("manager_unban_users", &ids, Some(&prev));
+    let failed = store::airdrop::unban_users(ids, ic_cdk::api::time() / SECOND);
+    if failed.is_empty() {
+        return Ok(());
     }
-    if args.claimable > 100_000 {
-        return Err("claimable should be less than 100_000".to_string());
+    Err(format!(
+        "failed to unban users: {}",
+        failed
+            .into_iter()
+            .map(|(user, err)| format!("{}: {}", user, err))
+            .collect::<Vec<_>>()
+            .join("; ")
+    ))
+}
+
+// A query can not be recorded in the audit log.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn manager_get_airdrop_key() -> Result<String, String> {
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::KeyAdmin) {
+        return Err("user is not a key admin".to_string());
     }
-    if args.quantity > 10_000 {
-        return Err("quantity should be less than 10_000".to_string());
+    Ok(general_purpose::URL_SAFE_NO_PAD.encode(*store::keys::AIRDROP_KEY))
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn manager_add_prize(args: types::AddPrizeInput) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !store::state::has_role(&caller, types::Role::PrizeIssuer) {
+        return Err("user is not a prize issuer".to_string());
     }
+    let now_sec = ic_cdk::api::time() / SECOND;
+    args.validate()?;
+    store::state::check_liability(args.claimable as u64 * TOKEN_1)?;
 
     match store::airdrop::state_of(&caller) {
         Some(store::AirdropState(code, _, _)) => {
             if code == 0 {
                 Err("user is banned".to_string())
             } else {
-                match store::prize::try_add(
-                    code,
-                    now_sec,
-                    args.expire,
-                    args.claimable,
-                    args.quantity,
-                ) {
-                    Some(cryptogram) => Ok(cryptogram),
+                match store::prize::try_add(code, now_sec, &args, false, None) {
+                    Some(cryptogram) => {
+                        store::state::reserve_prize(args.claimable as u64 * TOKEN_1);
+                        audit("manager_add_prize", &args, None::<&()>);
+                        Ok(cryptogram)
+                    }
                     None => Err("failed to add prize".to_string()),
                 }
             }
@@ -157,10 +452,27 @@ fn manager_add_prize(args: types::AddPrizeInput) -> Result<String, String> {
 
 #[ic_cdk::update(guard = "is_authenticated")]
 fn manager_set_challenge_pub_key(key: String) -> Result<(), String> {
-    if !store::state::is_manager(&ic_cdk::caller()) {
-        return Err("user is not a manager".to_string());
+    if !store::state::has_role(&ic_cdk::caller(), types::Role::KeyAdmin) {
+        return Err("user is not a key admin".to_string());
     }
     let key = bytes32_from_base64(&key)?;
+    let prev = store::keys::with_challenge_pub_key(|k| ByteBuf::from(k.to_vec()));
+    audit(
+        "manager_set_challenge_pub_key",
+        &ByteBuf::from(key.to_vec()),
+        Some(&prev),
+    );
     store::keys::set_challenge_pub_key(key);
     Ok(())
 }
+
+// record a privileged call with its arguments and the value it replaced in the audit log.
+fn audit<A: Serialize, P: Serialize>(method: &str, args: &A, prev_value: Option<&P>) {
+    store::audit::append(
+        ic_cdk::caller(),
+        method,
+        args,
+        prev_value,
+        ic_cdk::api::time() / SECOND,
+    );
+}
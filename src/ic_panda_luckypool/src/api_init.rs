@@ -1,26 +1,165 @@
-use crate::store;
+use crate::{api_update, store, SECOND};
 use std::time::Duration;
 
+const LUCKYDRAW_JOURNAL_INTERVAL: Duration = Duration::from_secs(60 * 10);
+const SERVER_SEED_ROTATION_INTERVAL: Duration = Duration::from_secs(3600 * 24);
+const LOG_INDEX_BACKFILL_INTERVAL: Duration = Duration::from_secs(1);
+const CERTIFIED_REBUILD_INTERVAL: Duration = Duration::from_secs(1);
+const PRIZE_CLAIMERS_BACKFILL_INTERVAL: Duration = Duration::from_secs(1);
+const LEGACY_BANS_BACKFILL_INTERVAL: Duration = Duration::from_secs(1);
+const ARCHIVE_INTERVAL: Duration = Duration::from_secs(3600);
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(60 * 10);
+const BAN_EXPIRY_INTERVAL: Duration = Duration::from_secs(60 * 10);
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(3600);
+const PRIZE_SWEEP_INTERVAL: Duration = Duration::from_secs(3600);
+const BURN_INTERVAL: Duration = Duration::from_secs(3600);
+const PRIZE_REFUND_INTERVAL: Duration = Duration::from_secs(60 * 10);
+const LOG_COMPACTION_INTERVAL: Duration = Duration::from_secs(60);
+
 #[ic_cdk::init]
 fn init() {
     store::state::save();
+    store::certified::init();
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), rebuild_certified_tree);
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), backfill_prize_claimers);
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), backfill_legacy_bans);
 
     ic_cdk_timers::set_timer(Duration::from_nanos(0), || {
         ic_cdk::spawn(store::keys::load())
     });
+    ic_cdk_timers::set_timer_interval(LUCKYDRAW_JOURNAL_INTERVAL, || {
+        ic_cdk::spawn(api_update::settle_luckydraw_journal())
+    });
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), || {
+        ic_cdk::spawn(store::server_seed::init())
+    });
+    ic_cdk_timers::set_timer_interval(SERVER_SEED_ROTATION_INTERVAL, || {
+        ic_cdk::spawn(store::server_seed::rotate())
+    });
+    ic_cdk_timers::set_timer_interval(ARCHIVE_INTERVAL, || {
+        ic_cdk::spawn(api_update::archive_logs())
+    });
+    ic_cdk_timers::set_timer_interval(CHECKPOINT_INTERVAL, || {
+        store::checkpoint(ic_cdk::api::time() / SECOND)
+    });
+    ic_cdk_timers::set_timer_interval(BAN_EXPIRY_INTERVAL, unban_expired);
+    ic_cdk_timers::set_timer_interval(RECONCILE_INTERVAL, || {
+        ic_cdk::spawn(api_update::reconcile_airdrop_balance())
+    });
+    ic_cdk_timers::set_timer_interval(PRIZE_SWEEP_INTERVAL, sweep_expired_prizes);
+    ic_cdk_timers::set_timer_interval(BURN_INTERVAL, || {
+        ic_cdk::spawn(api_update::burn_luckydraw_payments())
+    });
+    ic_cdk_timers::set_timer_interval(PRIZE_REFUND_INTERVAL, || {
+        ic_cdk::spawn(api_update::refund_prize_deposits())
+    });
+    ic_cdk_timers::set_timer_interval(LOG_COMPACTION_INTERVAL, compact_logs);
 }
 
 #[ic_cdk::pre_upgrade]
 pub fn pre_upgrade() {
-    store::keys::save();
-    store::state::save();
+    store::checkpoint(ic_cdk::api::time() / SECOND);
 }
 
 #[ic_cdk::post_upgrade]
 fn post_upgrade() {
     store::state::load();
+    store::state::init_claimable_totals();
+    store::xauth::init_users();
+    store::certified::init();
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), rebuild_certified_tree);
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), backfill_prize_claimers);
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), backfill_legacy_bans);
 
     ic_cdk_timers::set_timer(Duration::from_nanos(0), || {
         ic_cdk::spawn(store::keys::load())
     });
+    ic_cdk_timers::set_timer_interval(LUCKYDRAW_JOURNAL_INTERVAL, || {
+        ic_cdk::spawn(api_update::settle_luckydraw_journal())
+    });
+    ic_cdk_timers::set_timer(Duration::from_nanos(0), || {
+        ic_cdk::spawn(store::server_seed::init())
+    });
+    ic_cdk_timers::set_timer_interval(SERVER_SEED_ROTATION_INTERVAL, || {
+        ic_cdk::spawn(store::server_seed::rotate())
+    });
+    ic_cdk_timers::set_timer_interval(ARCHIVE_INTERVAL, || {
+        ic_cdk::spawn(api_update::archive_logs())
+    });
+    ic_cdk_timers::set_timer_interval(CHECKPOINT_INTERVAL, || {
+        store::checkpoint(ic_cdk::api::time() / SECOND)
+    });
+    ic_cdk_timers::set_timer_interval(BAN_EXPIRY_INTERVAL, unban_expired);
+    ic_cdk_timers::set_timer_interval(RECONCILE_INTERVAL, || {
+        ic_cdk::spawn(api_update::reconcile_airdrop_balance())
+    });
+    ic_cdk_timers::set_timer_interval(PRIZE_SWEEP_INTERVAL, sweep_expired_prizes);
+    ic_cdk_timers::set_timer_interval(BURN_INTERVAL, || {
+        ic_cdk::spawn(api_update::burn_luckydraw_payments())
+    });
+    ic_cdk_timers::set_timer_interval(PRIZE_REFUND_INTERVAL, || {
+        ic_cdk::spawn(api_update::refund_prize_deposits())
+    });
+    ic_cdk_timers::set_timer_interval(LOG_COMPACTION_INTERVAL, compact_logs);
+    ic_cdk_timers::set_timer(LOG_INDEX_BACKFILL_INTERVAL, backfill_log_index);
+}
+
+// rebuild the certified tree in batches.
+fn rebuild_certified_tree() {
+    if !store::certified::rebuild(5000) {
+        ic_cdk_timers::set_timer(CERTIFIED_REBUILD_INTERVAL, rebuild_certified_tree);
+    }
+}
+
+// record the claimers of the prizes claimed before PRIZE_CLAIMERS in batches.
+fn backfill_prize_claimers() {
+    ic_cdk::spawn(async {
+        if !api_update::backfill_prize_claimers().await {
+            ic_cdk_timers::set_timer(PRIZE_CLAIMERS_BACKFILL_INTERVAL, backfill_prize_claimers);
+        }
+    })
+}
+
+// index the existing logs by user in batches.
+fn backfill_log_index() {
+    if !store::log_index::backfill(1000) {
+        ic_cdk_timers::set_timer(LOG_INDEX_BACKFILL_INTERVAL, backfill_log_index);
+    }
+}
+
+// record the users banned before the ban registry with their former lucky codes in batches.
+fn backfill_legacy_bans() {
+    if !store::ban::backfill_legacy(10_000) {
+        ic_cdk_timers::set_timer(LEGACY_BANS_BACKFILL_INTERVAL, backfill_legacy_bans);
+    }
+}
+
+// reclaim the stable memory of the archived logs in batches.
+fn compact_logs() {
+    if let Err(err) = store::airdrop::compact_logs(10_000) {
+        ic_cdk::println!("failed to compact airdrop logs: {}", err);
+    }
+    if let Err(err) = store::luckydraw::compact_logs(10_000) {
+        ic_cdk::println!("failed to compact luckydraw logs: {}", err);
+    }
+}
+
+// close the expired prizes and return their unclaimed budgets,
+// and remove the closed prizes past the retention.
+fn sweep_expired_prizes() {
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let (_, errors) = store::prize::sweep_expired(now_sec, 1000);
+    for err in errors {
+        ic_cdk::println!("failed to close {}", err);
+    }
+    store::prize::prune_closed(now_sec, 1000);
+}
+
+// lift the expired bans and restore the lucky codes.
+fn unban_expired() {
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let users = store::ban::expired(now_sec);
+    for (user, err) in store::airdrop::unban_users(users, now_sec) {
+        ic_cdk::println!("failed to unban {}: {}", user, err);
+    }
 }
@@ -1,5 +1,13 @@
-use crate::{nat_to_u64, store, types, utils, ANONYMOUS, TOKEN_1};
+use crate::{
+    api_update::{luckydraw_amount, provably_fair_random},
+    nat_to_u64, store, types, ANONYMOUS, ICP_1, SECOND, TOKEN_1,
+};
 use candid::{Nat, Principal};
+use serde_bytes::ByteBuf;
+
+const ICRC3_MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+const ICRC3_BLOCK_TYPES_URL: &str =
+    "https://github.com/ldclabs/ic-panda/blob/main/src/ic_panda_luckypool/README.md";
 
 #[ic_cdk::query]
 fn api_version() -> u16 {
@@ -12,8 +20,8 @@ fn whoami() -> Result<Principal, ()> {
 }
 
 #[ic_cdk::query]
-fn state() -> Result<store::State, ()> {
-    Ok(store::state::with(|r| r.clone()))
+fn state() -> Result<types::StateOutput, ()> {
+    Ok(store::state::with(|r| r.into()))
 }
 
 #[ic_cdk::query]
@@ -29,11 +37,7 @@ async fn airdrop_state_of(owner: Option<Principal>) -> Result<types::AirdropStat
     }
 
     match store::airdrop::state_of(&owner) {
-        Some(store::AirdropState(code, claimed, claimable)) => Ok(types::AirdropStateOutput {
-            lucky_code: Some(utils::luckycode_to_string(code)),
-            claimed: Nat::from(claimed),
-            claimable: Nat::from(claimable),
-        }),
+        Some(state) => Ok(types::AirdropStateOutput::from(&state)),
         None => Ok(types::AirdropStateOutput {
             lucky_code: None,
             claimed: Nat::from(0u64),
@@ -49,38 +53,305 @@ async fn airdrop_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<types::Airdro
     store::airdrop::logs(prev, take)
 }
 
+// the airdrop logs in reverse order, and the range to fetch from the archive canister
+// when the local logs are not enough.
+#[ic_cdk::query]
+async fn get_airdrop_logs(prev: Option<Nat>, take: Option<Nat>) -> types::AirdropLogsOutput {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    let logs = store::airdrop::logs(prev, take);
+    let archived = archived_range(store::airdrop::first_log_id(), prev, take - logs.len()).map(
+        |(archive, prev, take)| types::ArchivedAirdropLogs {
+            prev: Nat::from(prev),
+            take: Nat::from(take),
+            callback: types::AirdropLogsFn::new(archive, "airdrop_logs".to_string()),
+        },
+    );
+    types::AirdropLogsOutput { logs, archived }
+}
+
+// The banned users in the ban registry, ordered by principal and starting after `prev`.
+#[ic_cdk::query]
+fn banned_users(prev: Option<Principal>, take: Option<Nat>) -> Vec<types::BannedUser> {
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::ban::list(prev, take)
+}
+
+// The audit logs of the privileged calls in reverse order, open for review by the DAO members.
+#[ic_cdk::query]
+fn audit_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<types::AuditLog> {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::audit::logs(prev, take)
+}
+
+#[ic_cdk::query]
+async fn my_airdrop_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<types::AirdropLog> {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::airdrop::logs_of(ic_cdk::caller(), prev, take)
+}
+
 #[ic_cdk::query]
 async fn luckydraw_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<types::LuckyDrawLog> {
     let prev = prev.as_ref().map(nat_to_u64);
     let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
-    store::luckydraw::logs(prev, take, None)
+    store::luckydraw::logs(prev, take)
+}
+
+// the luckydraw logs in reverse order, and the range to fetch from the archive canister
+// when the local logs are not enough.
+#[ic_cdk::query]
+async fn get_luckydraw_logs(prev: Option<Nat>, take: Option<Nat>) -> types::LuckyDrawLogsOutput {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    let logs = store::luckydraw::logs(prev, take);
+    let archived = archived_range(store::luckydraw::first_log_id(), prev, take - logs.len()).map(
+        |(archive, prev, take)| types::ArchivedLuckyDrawLogs {
+            prev: Nat::from(prev),
+            take: Nat::from(take),
+            callback: types::LuckyDrawLogsFn::new(archive, "luckydraw_logs".to_string()),
+        },
+    );
+    types::LuckyDrawLogsOutput { logs, archived }
+}
+
+// the archive canister and the (prev, take) arguments to fetch the rest logs from it
+// when the local logs starting from `first_id` are not enough.
+fn archived_range(first_id: u64, prev: Option<u64>, take: usize) -> Option<(Principal, u64, u64)> {
+    let archive = store::state::with(|r| r.archive_canister)?;
+    let prev = prev.unwrap_or(first_id).min(first_id);
+    if prev == 0 || take == 0 {
+        return None;
+    }
+    Some((archive, prev, take as u64))
 }
 
 #[ic_cdk::query]
 async fn my_luckydraw_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<types::LuckyDrawLog> {
     let prev = prev.as_ref().map(nat_to_u64);
     let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
-    store::luckydraw::logs(prev, take, Some(ic_cdk::caller()))
+    store::luckydraw::logs_of(ic_cdk::caller(), prev, take)
+}
+
+#[ic_cdk::query]
+async fn luckydraw_logs_of(
+    owner: Principal,
+    prev: Option<Nat>,
+    take: Option<Nat>,
+) -> Vec<types::LuckyDrawLog> {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::luckydraw::logs_of(owner, prev, take)
+}
+
+// the server seed for provably fair luckydraws, the active one if epoch is None.
+#[ic_cdk::query]
+fn luckydraw_seed(epoch: Option<u32>) -> Result<types::LuckyDrawSeed, String> {
+    store::server_seed::get(epoch)
+        .map(|(seed, _)| seed)
+        .ok_or("server seed not found".to_string())
+}
+
+// the payout table of luckydraw and its version, the active one if version is None.
+#[ic_cdk::query]
+fn luckydraw_table(version: Option<u32>) -> Result<(u32, types::LuckyDrawTable), String> {
+    match version {
+        None => Ok(store::luckydraw_table::active()),
+        Some(version) => store::luckydraw_table::get(version)
+            .map(|table| (version, table))
+            .ok_or("payout table not found".to_string()),
+    }
+}
+
+// recompute a provably fair luckydraw with the revealed server seed.
+#[ic_cdk::query]
+fn verify_luckydraw(id: Nat) -> Result<types::LuckyDrawVerification, String> {
+    let id = nat_to_u64(&id);
+    let log = store::luckydraw::get(id).ok_or("luckydraw log not found".to_string())?;
+    let store::ProvablyFair(epoch, ref client_seed, nonce, table_version) = log
+        .5
+        .clone()
+        .ok_or("luckydraw is not in provably fair mode".to_string())?;
+    let (seed, server_seed) =
+        store::server_seed::get(Some(epoch)).ok_or("server seed not found".to_string())?;
+    let server_seed = server_seed.ok_or("server seed has not been revealed yet".to_string())?;
+
+    let table =
+        store::luckydraw_table::get(table_version).ok_or("payout table not found".to_string())?;
+    let (x, amount) = luckydraw_amount(
+        &table,
+        &provably_fair_random(&server_seed, client_seed, nonce),
+    );
+    let icp01 = log.3 * 10 / ICP_1;
+    let log = types::LuckyDrawLog::from((id, log));
+    Ok(types::LuckyDrawVerification {
+        verified: x == log.random,
+        random: x,
+        amount: Nat::from(icp01 * amount / 10),
+        log,
+        seed,
+    })
+}
+
+// the caller's luckydraws that have not been settled, including outstanding ICP refunds.
+#[ic_cdk::query]
+async fn my_luckydraw_refunds() -> Vec<types::LuckyDrawJournal> {
+    store::luckydraw::journal_of(&ic_cdk::caller())
+}
+
+// the accepted payment tokens for luckydraw and their price ratios.
+#[ic_cdk::query]
+fn payment_tokens() -> Vec<(Principal, types::PaymentToken)> {
+    store::payment_token::list()
 }
 
 #[ic_cdk::query]
 async fn notifications() -> Vec<types::Notification> {
-    store::notification::list()
+    store::notification::list_active(&ic_cdk::caller(), ic_cdk::api::time() / SECOND)
 }
 
-// (Issuer code, Issue time, Expire, Claimable amount, Quantity, Filled quantity)
+// (Issuer code, Issue time, Expire, Claimable amount, Quantity, Filled quantity, Prize id, Luckiest)
 #[ic_cdk::query]
-async fn prizes_of(owner: Option<Principal>) -> Vec<(u32, u32, u16, u32, u16, u16)> {
+async fn prizes_of(owner: Option<Principal>) -> Vec<types::PrizeOutput> {
     let owner = owner.unwrap_or(ic_cdk::caller());
     if owner == ANONYMOUS {
         return vec![];
     }
     match store::airdrop::state_of(&owner) {
         None => vec![],
-        Some(store::AirdropState(code, _, _)) => store::prize::list(code)
-            .0
-            .into_iter()
-            .map(|p| (code, p.0 .0, p.0 .1, p.0 .2, p.0 .3, p.1))
-            .collect(),
+        Some(store::AirdropState(code, _, _)) => store::prize::list(code).to_output(code),
+    }
+}
+
+// the claims of a prize by its id in `prizes_of`,
+// the claimers, amounts and claim times are only for the issuer.
+#[ic_cdk::query]
+fn prize_claims(prize_id: String) -> Result<types::PrizeClaimsOutput, String> {
+    let prize = store::Prize::from_id(&prize_id)?;
+    let caller = ic_cdk::caller();
+    let detail =
+        prize.0 != 0 && store::airdrop::state_of(&caller).is_some_and(|state| state.0 == prize.0);
+    store::prize::claims_of(&prize, detail).ok_or("prize not found".to_string())
+}
+
+// the certified state summary, verify it with the certificate and the witness of `state`.
+#[ic_cdk::query]
+fn certified_state() -> Result<(types::StateSummary, types::Certification), String> {
+    let summary = store::state::with(|r| types::StateSummary::from(r));
+    let certification = certification(&store::certified::Reveal {
+        state: true,
+        ..Default::default()
+    })?;
+    Ok((summary, certification))
+}
+
+// the certified airdrop state of a user, None if the user has not claimed the airdrop.
+// Verify it with the certificate and the witness of `airdrop/<owner>`.
+#[ic_cdk::query]
+fn certified_airdrop_state_of(
+    owner: Option<Principal>,
+) -> Result<(Option<types::AirdropStateOutput>, types::Certification), String> {
+    let owner = owner.unwrap_or(ic_cdk::caller());
+    let state = store::airdrop::state_of(&owner);
+    let certification = certification(&store::certified::Reveal {
+        airdrop: Some(owner),
+        ..Default::default()
+    })?;
+    Ok((
+        state.as_ref().map(types::AirdropStateOutput::from),
+        certification,
+    ))
+}
+
+// the certified prizes of a user, verify them with the certificate and the witnesses of
+// `airdrop/<owner>` (the owner's lucky code) and `prizes/<code>`.
+#[ic_cdk::query]
+fn certified_prizes_of(
+    owner: Option<Principal>,
+) -> Result<(Vec<types::PrizeOutput>, types::Certification), String> {
+    let owner = owner.unwrap_or(ic_cdk::caller());
+    let code = store::airdrop::state_of(&owner).map(|state| state.0);
+    let prizes = code
+        .map(|code| store::prize::list(code).to_output(code))
+        .unwrap_or_default();
+    let certification = certification(&store::certified::Reveal {
+        airdrop: Some(owner),
+        prizes: code,
+        ..Default::default()
+    })?;
+    Ok((prizes, certification))
+}
+
+fn certification(reveal: &store::certified::Reveal) -> Result<types::Certification, String> {
+    if (reveal.airdrop.is_some() || reveal.prizes.is_some()) && !store::certified::is_ready() {
+        return Err("certified data is being rebuilt, try again later".to_string());
+    }
+    let certificate = ic_cdk::api::data_certificate()
+        .ok_or("certificate is only available in query calls".to_string())?;
+    Ok(types::Certification {
+        certificate: ByteBuf::from(certificate),
+        witness: ByteBuf::from(store::certified::witness(reveal)),
+    })
+}
+
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<types::GetBlocksArgs>) -> types::GetBlocksResult {
+    let mut blocks = Vec::new();
+    let mut remaining = ICRC3_MAX_BLOCKS_PER_RESPONSE;
+    for arg in args {
+        let take = nat_to_u64(&arg.length).min(remaining);
+        for (id, block) in store::icrc3::blocks(nat_to_u64(&arg.start), take) {
+            blocks.push(types::BlockWithId {
+                id: Nat::from(id),
+                block,
+            });
+        }
+        remaining = ICRC3_MAX_BLOCKS_PER_RESPONSE.saturating_sub(blocks.len() as u64);
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    types::GetBlocksResult {
+        log_length: Nat::from(store::icrc3::len()),
+        blocks,
+        archived_blocks: vec![],
+    }
+}
+
+#[ic_cdk::query]
+fn icrc3_get_tip_certificate() -> Option<types::ICRC3DataCertificate> {
+    if !store::certified::has_tip() {
+        return None;
     }
+    let certificate = ic_cdk::api::data_certificate()?;
+    let hash_tree = store::certified::witness(&store::certified::Reveal {
+        tip: true,
+        ..Default::default()
+    });
+    Some(types::ICRC3DataCertificate {
+        certificate: ByteBuf::from(certificate),
+        hash_tree: ByteBuf::from(hash_tree),
+    })
+}
+
+#[ic_cdk::query]
+fn icrc3_supported_block_types() -> Vec<types::SupportedBlockType> {
+    [
+        store::icrc3::BTYPE_CLAIM,
+        store::icrc3::BTYPE_REFERRAL,
+        store::icrc3::BTYPE_PRIZE,
+        store::icrc3::BTYPE_HARVEST,
+        store::icrc3::BTYPE_ADJUST,
+        store::icrc3::BTYPE_DRAW,
+        store::icrc3::BTYPE_PRIZE_LOCK,
+        store::icrc3::BTYPE_PRIZE_REFUND,
+    ]
+    .into_iter()
+    .map(|btype| types::SupportedBlockType {
+        block_type: btype.to_string(),
+        url: format!("{}#{}", ICRC3_BLOCK_TYPES_URL, btype),
+    })
+    .collect()
 }
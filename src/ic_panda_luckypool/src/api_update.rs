@@ -1,16 +1,23 @@
 use crate::{
-    icp_transfer_from, icp_transfer_to, is_authenticated, nat_to_u64, store, token_balance_of,
-    token_transfer_to, types,
+    is_authenticated, ledger_transfer_from, ledger_transfer_to, nat_to_u64, operation_memo, store,
+    token_balance_of, token_burn, token_transfer_to, types,
     utils::{self, luckycode_to_string},
-    ICP_1, SECOND, TOKEN_1, TOKEN_CANISTER, TRANS_FEE,
+    ANONYMOUS, DAO_CANISTER, ICP_1, ICP_CANISTER, SECOND, TOKEN_1, TOKEN_CANISTER, TRANS_FEE,
 };
-use candid::Nat;
+use candid::{CandidType, Nat, Principal};
 use ic_captcha::CaptchaBuilder;
-use lib_panda::{mac_256, Cryptogram, Ed25519Message, VerifyingKey};
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use icrc_ledger_types::icrc1::{account::Account, transfer::Memo};
+use lib_panda::{mac_256, to_cbor_bytes, Cryptogram, Ed25519Message, VerifyingKey};
 use once_cell::sync::Lazy;
 
-const LUCKIEST_AIRDROP_AMOUNT: u64 = 100_000;
 const LOWEST_LUCKYDRAW_BALANCE: u64 = 500;
+const ARCHIVE_BATCH_SIZE: u64 = 1000;
+const ARCHIVE_PRUNE_SIZE: u64 = 10_000;
+const ARCHIVE_CREATION_CYCLES: u128 = 1_000_000_000_000;
 
 static CAPTCHA_BUILDER: Lazy<CaptchaBuilder> =
     Lazy::new(|| CaptchaBuilder::new().length(6).width(160).complexity(8));
@@ -107,16 +114,24 @@ async fn airdrop(args: types::AirdropClaimInput) -> Result<types::AirdropStateOu
     } else {
         args.lucky_code
     };
-    let referrer = lucky_code.and_then(|s| store::luckycode::get_by_string(&s));
+    let referrer = lucky_code
+        .and_then(|s| store::luckycode::get_by_string(&s))
+        .filter(|r| !store::ban::is_banned(r));
     let claimable = if referrer.is_some() {
         (airdrop_amount + airdrop_amount / 2) * TOKEN_1
     } else {
         airdrop_amount * TOKEN_1
     };
+    let rebate_bonus = (airdrop_amount / 2) * TOKEN_1;
+    store::state::check_liability(if referrer.is_some() {
+        claimable + rebate_bonus
+    } else {
+        claimable
+    })?;
 
     // issued by users and try to claim airdrop
     if let Some(prize) = prize {
-        store::prize::claim(caller, prize)?;
+        store::prize::claim(caller, prize, now_sec)?;
     }
 
     let caller_code = store::luckycode::new_from(caller);
@@ -125,7 +140,7 @@ async fn airdrop(args: types::AirdropClaimInput) -> Result<types::AirdropStateOu
         referrer,
         now_sec,
         claimable,
-        (airdrop_amount / 2) * TOKEN_1,
+        rebate_bonus,
         caller_code,
     )?;
     store::state::with_mut(|r| {
@@ -150,7 +165,7 @@ async fn prize(cryptogram: String) -> Result<types::AirdropStateOutput, String>
     let cryptogram = cryptogram
         .strip_prefix("PRIZE:")
         .unwrap_or(cryptogram.as_str());
-    let prize = store::Prize::decode(&key, None, cryptogram)?;
+    let prize = store::PrizeCryptogram::decode(&key, None, cryptogram)?.prize();
     let now_sec = ic_cdk::api::time() / SECOND;
     if !prize.is_valid(now_sec) {
         return Err("invalid prize cryptogram or expired".to_string());
@@ -172,17 +187,23 @@ async fn prize(cryptogram: String) -> Result<types::AirdropStateOutput, String>
     if caller_code == 0 {
         return Err("user is banned".to_string());
     }
-    if claimable < TOKEN_1 * 10 {
+    let conditions = store::prize::conditions_of(&prize);
+    conditions.check(&caller, caller_code)?;
+    let min_balance = conditions.0;
+    if claimable < min_balance {
         let balance = token_balance_of(TOKEN_CANISTER, caller)
             .await
             .unwrap_or(Nat::from(0u64));
-        if (claimable + balance) < TOKEN_1 * 10 {
-            return Err("the balance must be more than 10 tokens to claim prize.".to_string());
+        if (claimable + balance) < min_balance {
+            return Err(format!(
+                "the balance must be more than {} tokens to claim prize.",
+                min_balance / TOKEN_1
+            ));
         }
     }
 
     let referrer_code = prize.0;
-    let claimable = store::prize::claim(caller, prize)?;
+    let claimable = store::prize::claim(caller, prize, now_sec)?;
 
     let (state, log) = store::airdrop::prize(caller, now_sec, claimable, referrer_code)?;
     store::state::with_mut(|r| {
@@ -201,9 +222,96 @@ async fn prize(cryptogram: String) -> Result<types::AirdropStateOutput, String>
     })
 }
 
+// create a prize funded by the caller's claimable tokens or a PANDA deposit,
+// the claimers' tokens come from the locked budget instead of the pool.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn add_prize(
+    args: types::AddPrizeInput,
+    funding: types::PrizeFunding,
+) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    args.validate()?;
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let budget = args.claimable as u64 * TOKEN_1;
+    let (code, claimable) = match store::airdrop::state_of(&caller) {
+        Some(store::AirdropState(0, _, _)) => return Err("user is banned".to_string()),
+        Some(store::AirdropState(code, _, claimable)) => (code, claimable),
+        None => return Err("you don't have lucky code".to_string()),
+    };
+    if funding == types::PrizeFunding::Claimable && claimable < budget {
+        return Err("insufficient claimable tokens".to_string());
+    }
+
+    if !store::user::active(caller) {
+        return Err("try again later".to_string());
+    }
+    let _guard = scopeguard::guard((), |_| {
+        store::user::deactive(caller);
+    });
+
+    if funding == types::PrizeFunding::Deposit {
+        ledger_transfer_from(
+            TOKEN_CANISTER,
+            caller,
+            Nat::from(budget),
+            operation_memo("prize", store::state::next_operation_id()),
+            now_sec * SECOND,
+        )
+        .await?;
+        store::state::with_mut(|r| r.airdrop_balance = r.airdrop_balance.saturating_add(budget));
+    }
+
+    let depositor = (funding == types::PrizeFunding::Deposit).then_some(caller);
+    match store::prize::try_add(code, now_sec, &args, true, depositor) {
+        Some(cryptogram) => {
+            match funding {
+                types::PrizeFunding::Claimable => {
+                    store::airdrop::lock_prize(caller, now_sec, budget)?
+                }
+                types::PrizeFunding::Deposit => store::state::reserve_prize(budget),
+            }
+            Ok(cryptogram)
+        }
+        None => {
+            // the deposit is transferred back by the refund timer
+            if funding == types::PrizeFunding::Deposit {
+                store::prize::owe_refund(caller, budget, now_sec);
+                return Err("failed to add prize, the deposit will be refunded".to_string());
+            }
+            Err("failed to add prize".to_string())
+        }
+    }
+}
+
+// cancel a prize issued by the caller, return the unclaimed budget in E8.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn cancel_prize(cryptogram: String) -> Result<Nat, String> {
+    let caller = ic_cdk::caller();
+    let cryptogram = cryptogram
+        .strip_prefix("PRIZE:")
+        .unwrap_or(cryptogram.as_str());
+    let prize = store::PrizeCryptogram::decode(&*store::keys::PRIZE_KEY, None, cryptogram)
+        .map(store::PrizeCryptogram::prize)
+        .or_else(|_| store::Prize::decode(&*store::keys::AIRDROP_KEY, None, cryptogram))
+        .map_err(|_| "invalid prize cryptogram".to_string())?;
+    let code = match store::airdrop::state_of(&caller) {
+        Some(store::AirdropState(0, _, _)) => return Err("user is banned".to_string()),
+        Some(store::AirdropState(code, _, _)) => code,
+        None => return Err("you don't have lucky code".to_string()),
+    };
+    let refund = store::prize::cancel(code, prize, ic_cdk::api::time() / SECOND)?;
+    Ok(Nat::from(refund))
+}
+
+// The ledger deduplicates transfers created within 24 hours, the idempotency key of a harvest
+// should be within the window and is kept a bit longer.
+const HARVEST_KEY_WINDOW: u64 = 3600 * 23 * SECOND;
+const HARVEST_KEY_TTL: u64 = 3600 * 25 * SECOND;
+
 #[ic_cdk::update(guard = "is_authenticated")]
 async fn harvest(args: types::AirdropHarvestInput) -> Result<types::AirdropStateOutput, String> {
     let caller = ic_cdk::caller();
+    let to = payout_account(caller, args.to)?;
     if !store::user::active(caller) {
         return Err("try again later".to_string());
     }
@@ -224,12 +332,55 @@ async fn harvest(args: types::AirdropHarvestInput) -> Result<types::AirdropState
             if amount < TOKEN_1 {
                 return Err("amount must be at least 1 token".to_string());
             }
+            let now = now_sec * SECOND;
+            store::airdrop::prune_harvest_keys(now.saturating_sub(HARVEST_KEY_TTL), 10);
+            if let Some(created_at) = args.created_at {
+                if created_at + HARVEST_KEY_WINDOW < now || created_at > now + 60 * SECOND {
+                    return Err("created_at is out of the last 23 hours".to_string());
+                }
+                // the harvest has been done, return the current state
+                if let Some(harvested) = store::airdrop::harvested(caller, created_at) {
+                    if harvested != amount {
+                        return Err("a different harvest with the same created_at has been done"
+                            .to_string());
+                    }
+                    let state = store::airdrop::state_of(&caller).unwrap_or_default();
+                    return Ok(types::AirdropStateOutput {
+                        lucky_code: Some(utils::luckycode_to_string(state.0)),
+                        claimed: Nat::from(state.1),
+                        claimable: Nat::from(state.2),
+                    });
+                }
+            }
             if amount > claimable {
                 return Err("insufficient claimable tokens to harvest".to_string());
             }
 
-            let _block_idx = token_transfer_to(caller, args.amount).await?;
-            let (state, log) = store::airdrop::harvest(caller, now_sec, amount)?;
+            // the airdrop balance is reconciled only when no harvest is in flight
+            store::user::harvest_started();
+            let _harvest = scopeguard::guard((), |_| {
+                store::user::harvest_finished();
+            });
+            // a retry with the same idempotency key is deduplicated by the ledger
+            let (memo, created_at) = match args.created_at {
+                Some(created_at) => (
+                    Memo::from(to_cbor_bytes(&("harvest", caller, created_at))),
+                    created_at,
+                ),
+                None => (
+                    operation_memo("harvest", store::state::next_operation_id()),
+                    now,
+                ),
+            };
+            let _block_idx = token_transfer_to(
+                to.unwrap_or(Account::from(caller)),
+                args.amount,
+                memo,
+                created_at,
+            )
+            .await?;
+            let (state, log) =
+                store::airdrop::harvest(caller, now_sec, amount, to, args.created_at)?;
             store::state::with_mut(|r| {
                 r.airdrop_balance = r.airdrop_balance.saturating_sub(amount + TRANS_FEE);
                 r.total_airdrop = r.total_airdrop.saturating_add(amount + TRANS_FEE);
@@ -251,8 +402,12 @@ async fn harvest(args: types::AirdropHarvestInput) -> Result<types::AirdropState
 
 #[ic_cdk::update(guard = "is_authenticated")]
 async fn luckydraw(args: types::LuckyDrawInput) -> Result<types::LuckyDrawOutput, String> {
+    let ledger = args.ledger.unwrap_or(ICP_CANISTER);
+    let token =
+        store::payment_token::get(&ledger).ok_or("unsupported payment token".to_string())?;
     let icp01 = if args.icp == 0 {
-        args.amount.map_or(0, |v| nat_to_u64(&v) * 10 / TOKEN_1)
+        args.amount
+            .map_or(0, |v| nat_to_u64(&v).saturating_mul(10) / token.per_icp)
     } else {
         args.icp as u64 * 10
     };
@@ -260,11 +415,16 @@ async fn luckydraw(args: types::LuckyDrawInput) -> Result<types::LuckyDrawOutput
     if !(1..=1000).contains(&icp01) {
         return Err("invalid icp amount, should be in [0.1, 100]".to_string());
     }
-    if store::state::with(|r| r.total_luckydraw) >= 420000000 * TOKEN_1 {
+    if args.client_seed.as_ref().is_some_and(|s| s.len() > 64) {
+        return Err("client seed should be at most 64 bytes".to_string());
+    }
+    let (table_version, table) = store::luckydraw_table::active();
+    if store::state::with(|r| r.total_luckydraw) >= table.total_cap * TOKEN_1 {
         return Err("The lucky draw pool has been drawn empty.".to_string());
     }
 
     let caller = ic_cdk::caller();
+    let to = payout_account(caller, args.to)?;
     if !store::user::active(caller) {
         return Err("try again later".to_string());
     }
@@ -273,11 +433,23 @@ async fn luckydraw(args: types::LuckyDrawInput) -> Result<types::LuckyDrawOutput
     });
 
     let now_sec = ic_cdk::api::time() / SECOND;
-    let rr = ic_cdk::api::management_canister::main::raw_rand()
-        .await
-        .map_err(|_err| "failed to get random bytes".to_string())?;
-    let (x, amount) = luckydraw_amount(&mac_256(&rr.0, b"ICPanda"));
-    let is_luckiest = amount == LUCKIEST_AIRDROP_AMOUNT * TOKEN_1;
+    let (x, amount, provably_fair) = match args.client_seed {
+        None => {
+            let rr = ic_cdk::api::management_canister::main::raw_rand()
+                .await
+                .map_err(|_err| "failed to get random bytes".to_string())?;
+            let (x, amount) = luckydraw_amount(&table, &mac_256(&rr.0, b"ICPanda"));
+            (x, amount, None)
+        }
+        Some(client_seed) => {
+            let (epoch, seed, nonce) = store::server_seed::next_nonce()
+                .ok_or("server seed is not ready, try again later".to_string())?;
+            let (x, amount) =
+                luckydraw_amount(&table, &provably_fair_random(&seed, &client_seed, nonce));
+            let provably_fair = store::ProvablyFair(epoch, client_seed, nonce, table_version);
+            (x, amount, Some(provably_fair))
+        }
+    };
     let icp = icp01 * ICP_1 / 10;
     let amount = icp01 * amount / 10;
 
@@ -291,60 +463,82 @@ async fn luckydraw(args: types::LuckyDrawInput) -> Result<types::LuckyDrawOutput
         ));
     }
 
-    let _ = icp_transfer_from(caller, Nat::from(icp - TRANS_FEE)).await?;
+    let icp_received = icp - TRANS_FEE;
+    let created_at = now_sec * SECOND;
+    let payment = luckydraw_intake(caller, ledger, &token, icp01, created_at).await?;
+    // the jackpot winner takes the entire progressive jackpot pool
+    let jackpot = if table.is_jackpot(x) {
+        store::state::take_jackpot()
+    } else {
+        0
+    };
+    let amount = amount + jackpot;
+    let journal_id = store::luckydraw::journal_add(store::LuckyDrawJournal(
+        caller,
+        now_sec,
+        icp_received,
+        amount,
+        x,
+        types::LuckyDrawStage::IcpReceived,
+        provably_fair.clone(),
+        jackpot,
+        vec![],
+        payment.clone(),
+        to,
+    ));
     let balance = token_balance_of(TOKEN_CANISTER, ic_cdk::id())
         .await
         .unwrap_or(Nat::from(0u64));
     let draw_amount = if balance >= lowest_balance {
         let balance = nat_to_u64(&balance).saturating_sub(TRANS_FEE);
         let draw_amount = if balance < amount { balance } else { amount };
-        match token_transfer_to(caller, Nat::from(draw_amount)).await {
+        // return the jackpot tokens that can not be paid to the pool
+        let unpaid_jackpot = jackpot.min(amount - draw_amount);
+        store::state::add_jackpot(unpaid_jackpot);
+        store::luckydraw::journal_update(
+            journal_id,
+            types::LuckyDrawStage::PayoutSent,
+            draw_amount,
+            jackpot - unpaid_jackpot,
+        );
+        match token_transfer_to(
+            to.unwrap_or(Account::from(caller)),
+            Nat::from(draw_amount),
+            operation_memo("payout", journal_id),
+            created_at,
+        )
+        .await
+        {
             Ok(_) => draw_amount,
-            Err(_) => 0,
+            Err(err) if err.rejected => 0,
+            // the payout may have been delivered, it is resent by the journal timer
+            Err(err) => {
+                return Err(format!(
+                    "the payout is pending and will be settled later, {}",
+                    err.error
+                ))
+            }
         }
     } else {
         0
     };
 
     if draw_amount > 0 {
-        let log = store::luckydraw::insert(caller, now_sec, draw_amount, icp, x)?;
-        store::state::with_mut(|r| {
-            r.total_luckydraw = r.total_luckydraw.saturating_add(draw_amount + TRANS_FEE);
-            r.total_luckydraw_icp = r.total_luckydraw_icp.saturating_add(icp - TRANS_FEE);
-            r.total_luckydraw_count += 1;
-            r.latest_luckydraw_logs.insert(0, log.clone());
-            if r.latest_luckydraw_logs.len() > 10 {
-                r.latest_luckydraw_logs.truncate(10);
-            }
-            if is_luckiest {
-                r.luckiest_luckydraw_logs.insert(0, log);
-                if r.luckiest_luckydraw_logs.len() > 3 {
-                    r.luckiest_luckydraw_logs.truncate(3);
-                }
-            }
-        });
-
-        let (airdrop_amount, _) = store::state::airdrop_amount_balance();
-
-        let airdrop_cryptogram = match store::airdrop::state_of(&caller) {
-            Some(store::AirdropState(code, _, _)) => {
-                if code == 0 {
-                    None
-                } else {
-                    store::prize::try_add(code, now_sec, 4320, 0, (icp01 as u16) * 5)
-                }
-            }
-            None => {
-                let code = store::luckycode::new_from(caller);
-                if store::airdrop::insert(caller, None, now_sec, airdrop_amount * TOKEN_1, 0, code)
-                    .is_ok()
-                {
-                    store::prize::try_add(code, now_sec, 4320, 0, (icp01 as u16) * 5)
-                } else {
-                    None
-                }
-            }
-        };
+        let entry = store::LuckyDrawJournal(
+            caller,
+            now_sec,
+            icp_received,
+            draw_amount,
+            x,
+            types::LuckyDrawStage::PayoutSent,
+            provably_fair,
+            0,
+            vec![],
+            payment,
+            to,
+        );
+        luckydraw_settle(journal_id, entry, &table)?;
+        let airdrop_cryptogram = luckydraw_airdrop_cryptogram(caller, now_sec, icp01 as u16 * 5);
 
         Ok(types::LuckyDrawOutput {
             amount: Nat::from(draw_amount),
@@ -354,65 +548,911 @@ async fn luckydraw(args: types::LuckyDrawInput) -> Result<types::LuckyDrawOutput
             airdrop_cryptogram,
         })
     } else {
-        // refund ICP when failed to transfer tokens
-        let _ = icp_transfer_to(caller, Nat::from(icp - TRANS_FEE - TRANS_FEE))
+        luckydraw_refund(journal_id, created_at).await?;
+        Err("insufficient token balance for luckydraw, payment refunded".to_string())
+    }
+}
+
+// The max number of draws in a batch luckydraw.
+const LUCKYDRAW_BATCH_MAX: u8 = 10;
+
+// draw multiple times with one ICP debit and one payout,
+// the batch is paid in full or refunded.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn luckydraw_batch(
+    args: types::LuckyDrawBatchInput,
+) -> Result<types::LuckyDrawBatchOutput, String> {
+    let ledger = args.ledger.unwrap_or(ICP_CANISTER);
+    let token =
+        store::payment_token::get(&ledger).ok_or("unsupported payment token".to_string())?;
+    let icp01 = nat_to_u64(&args.amount).saturating_mul(10) / token.per_icp;
+    if !(1..=1000).contains(&icp01) {
+        return Err("invalid icp amount, should be in [0.1, 100]".to_string());
+    }
+    if !(1..=LUCKYDRAW_BATCH_MAX).contains(&args.count) {
+        return Err(format!(
+            "invalid count, should be in [1, {}]",
+            LUCKYDRAW_BATCH_MAX
+        ));
+    }
+    if args.client_seed.as_ref().is_some_and(|s| s.len() > 64) {
+        return Err("client seed should be at most 64 bytes".to_string());
+    }
+    let (table_version, table) = store::luckydraw_table::active();
+    if store::state::with(|r| r.total_luckydraw) >= table.total_cap * TOKEN_1 {
+        return Err("The lucky draw pool has been drawn empty.".to_string());
+    }
+
+    let caller = ic_cdk::caller();
+    let to = payout_account(caller, args.to)?;
+    if !store::user::active(caller) {
+        return Err("try again later".to_string());
+    }
+    let _guard = scopeguard::guard((), |_| {
+        store::user::deactive(caller);
+    });
+
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let count = args.count as u64;
+    let mut draws: Vec<store::LuckyDrawBatchItem> = match args.client_seed {
+        None => {
+            let rr = ic_cdk::api::management_canister::main::raw_rand()
+                .await
+                .map_err(|_err| "failed to get random bytes".to_string())?;
+            // derive independent outcomes from one random bytes
+            (0..count)
+                .map(|i| {
+                    let (x, amount) =
+                        luckydraw_amount(&table, &mac_256(&rr.0, &to_cbor_bytes(&("ICPanda", i))));
+                    store::LuckyDrawBatchItem(x, icp01 * amount / 10, None)
+                })
+                .collect()
+        }
+        Some(client_seed) => {
+            let mut draws = Vec::with_capacity(count as usize);
+            for _ in 0..count {
+                let (epoch, seed, nonce) = store::server_seed::next_nonce()
+                    .ok_or("server seed is not ready, try again later".to_string())?;
+                let (x, amount) =
+                    luckydraw_amount(&table, &provably_fair_random(&seed, &client_seed, nonce));
+                let provably_fair =
+                    store::ProvablyFair(epoch, client_seed.clone(), nonce, table_version);
+                draws.push(store::LuckyDrawBatchItem(
+                    x,
+                    icp01 * amount / 10,
+                    Some(provably_fair),
+                ));
+            }
+            draws
+        }
+    };
+    let icp = icp01 * ICP_1 / 10;
+
+    let balance = token_balance_of(TOKEN_CANISTER, ic_cdk::id()).await?;
+    let lowest_balance = (LOWEST_LUCKYDRAW_BALANCE * TOKEN_1 * icp01 * count / 10) + TRANS_FEE;
+    if balance < lowest_balance {
+        return Err(format!(
+            "insufficient token balance ({}) for drawing {} times with {} ICP",
+            balance / TOKEN_1,
+            count,
+            icp01 as f32 / 10f32
+        ));
+    }
+
+    let icp_received = icp * count - TRANS_FEE;
+    let created_at = now_sec * SECOND;
+    let payment = luckydraw_intake(caller, ledger, &token, icp01 * count, created_at).await?;
+    // the first jackpot winner in the batch takes the entire progressive jackpot pool
+    let jackpot = match draws.iter_mut().find(|draw| table.is_jackpot(draw.0)) {
+        Some(draw) => {
+            let jackpot = store::state::take_jackpot();
+            draw.1 += jackpot;
+            jackpot
+        }
+        None => 0,
+    };
+    let amount: u64 = draws.iter().map(|draw| draw.1).sum();
+    let journal_id = store::luckydraw::journal_add(store::LuckyDrawJournal(
+        caller,
+        now_sec,
+        icp_received,
+        amount,
+        draws[0].0,
+        types::LuckyDrawStage::IcpReceived,
+        None,
+        jackpot,
+        draws.clone(),
+        payment.clone(),
+        to,
+    ));
+    let balance = token_balance_of(TOKEN_CANISTER, ic_cdk::id())
+        .await
+        .unwrap_or(Nat::from(0u64));
+    let paid = if nat_to_u64(&balance) >= amount + TRANS_FEE {
+        store::luckydraw::journal_update(
+            journal_id,
+            types::LuckyDrawStage::PayoutSent,
+            amount,
+            jackpot,
+        );
+        match token_transfer_to(
+            to.unwrap_or(Account::from(caller)),
+            Nat::from(amount),
+            operation_memo("payout", journal_id),
+            created_at,
+        )
+        .await
+        {
+            Ok(_) => true,
+            Err(err) if err.rejected => false,
+            // the payout may have been delivered, it is resent by the journal timer
+            Err(err) => {
+                return Err(format!(
+                    "the payout is pending and will be settled later, {}",
+                    err.error
+                ))
+            }
+        }
+    } else {
+        false
+    };
+
+    if !paid {
+        luckydraw_refund(journal_id, created_at).await?;
+        return Err("insufficient token balance for luckydraw, payment refunded".to_string());
+    }
+
+    let entry = store::LuckyDrawJournal(
+        caller,
+        now_sec,
+        icp_received,
+        amount,
+        draws[0].0,
+        types::LuckyDrawStage::PayoutSent,
+        None,
+        0,
+        draws,
+        payment,
+        to,
+    );
+    let logs = luckydraw_settle(journal_id, entry, &table)?;
+    let airdrop_cryptogram =
+        luckydraw_airdrop_cryptogram(caller, now_sec, (icp01 * count) as u16 * 5);
+    Ok(types::LuckyDrawBatchOutput {
+        batch_id: Nat::from(journal_id),
+        amount: Nat::from(amount),
+        logs,
+        airdrop_cryptogram,
+    })
+}
+
+// validate the account to receive tokens, return None if it is the caller's default account.
+fn payout_account(caller: Principal, to: Option<Account>) -> Result<Option<Account>, String> {
+    match to {
+        None => Ok(None),
+        Some(to) => {
+            if to.owner == ANONYMOUS {
+                return Err("anonymous account is not allowed".to_string());
+            }
+            if to.owner == ic_cdk::id() {
+                return Err("can not transfer to the luckypool canister".to_string());
+            }
+            if to == Account::from(caller) {
+                return Ok(None);
+            }
+            Ok(Some(to))
+        }
+    }
+}
+
+// give the drawer an airdrop cryptogram, the drawer will be registered if not yet.
+fn luckydraw_airdrop_cryptogram(caller: Principal, now_sec: u64, quantity: u16) -> Option<String> {
+    let args = types::AddPrizeInput {
+        expire: 4320,
+        claimable: 0,
+        quantity,
+        distribution: None,
+        conditions: None,
+    };
+    match store::airdrop::state_of(&caller) {
+        Some(store::AirdropState(code, _, _)) => {
+            if code == 0 {
+                None
+            } else {
+                store::prize::try_add(code, now_sec, &args, false, None)
+            }
+        }
+        None => {
+            let (airdrop_amount, _) = store::state::airdrop_amount_balance();
+            store::state::check_liability(airdrop_amount * TOKEN_1).ok()?;
+            let code = store::luckycode::new_from(caller);
+            if store::airdrop::insert(caller, None, now_sec, airdrop_amount * TOKEN_1, 0, code)
+                .is_ok()
+            {
+                store::prize::try_add(code, now_sec, &args, false, None)
+            } else {
+                None
+            }
+        }
+    }
+}
+
+// take the payment of luckydraws worth icp01 tenths of ICP from the caller,
+// return the payment record if it is not paid in ICP.
+async fn luckydraw_intake(
+    caller: Principal,
+    ledger: Principal,
+    token: &types::PaymentToken,
+    icp01: u64,
+    created_at: u64,
+) -> Result<Option<store::LuckyDrawPayment>, String> {
+    let paid = (icp01 as u128 * token.per_icp as u128 / 10) as u64;
+    let _ = ledger_transfer_from(
+        ledger,
+        caller,
+        Nat::from(paid - token.fee),
+        operation_memo("luckydraw", store::state::next_operation_id()),
+        created_at,
+    )
+    .await?;
+    if ledger == ICP_CANISTER {
+        Ok(None)
+    } else {
+        Ok(Some(store::LuckyDrawPayment(ledger, paid, token.fee)))
+    }
+}
+
+// refund the payment when failed to transfer tokens, the jackpot tokens are returned to the pool.
+async fn luckydraw_refund(journal_id: u64, created_at: u64) -> Result<(), String> {
+    let entry = match store::luckydraw::journal_get(journal_id) {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    store::state::add_jackpot(entry.7);
+    store::luckydraw::journal_update(journal_id, types::LuckyDrawStage::RefundOwed, 0, 0);
+    luckydraw_refund_transfer(journal_id, &entry, created_at)
+        .await
+        .map_err(|err| format!("failed to refund, will retry later, {}", err))?;
+    store::luckydraw::journal_remove(journal_id);
+    Ok(())
+}
+
+async fn luckydraw_refund_transfer(
+    journal_id: u64,
+    entry: &store::LuckyDrawJournal,
+    created_at: u64,
+) -> Result<Nat, String> {
+    let (ledger, amount) = match entry.9 {
+        None => (ICP_CANISTER, entry.2.saturating_sub(TRANS_FEE)),
+        Some(store::LuckyDrawPayment(ledger, paid, fee)) => (ledger, paid.saturating_sub(fee * 2)),
+    };
+    ledger_transfer_to(
+        ledger,
+        entry.0,
+        Nat::from(amount),
+        operation_memo("refund", journal_id),
+        created_at,
+    )
+    .await
+}
+
+// record a luckydraw whose payout has been delivered,
+// the draws of a batch are recorded as separate logs with the journal id as the batch id.
+// The journal entry is removed after the logs are appended. A failed append traps to roll back
+// the appended logs, and the entry is settled again by the journal timer.
+fn luckydraw_settle(
+    journal_id: u64,
+    entry: store::LuckyDrawJournal,
+    table: &types::LuckyDrawTable,
+) -> Result<Vec<types::LuckyDrawLog>, String> {
+    let store::LuckyDrawJournal(
+        caller,
+        now_sec,
+        icp_received,
+        draw_amount,
+        x,
+        _,
+        provably_fair,
+        _,
+        batch,
+        payment,
+        to,
+    ) = entry;
+    let contribution = jackpot_contribution(icp_received);
+    // the ICP amount of a payment in other tokens is its ICP equivalent
+    let paid_icp = if payment.is_none() { icp_received } else { 0 };
+    // PANDA paid for luckydraw is burned by the burn timer
+    let burn = match &payment {
+        Some(store::LuckyDrawPayment(ledger, paid, fee)) if *ledger == TOKEN_CANISTER => {
+            paid.saturating_sub(*fee)
+        }
+        _ => 0,
+    };
+    let insert = |log: store::LuckyDrawLog| {
+        store::luckydraw::insert(log).unwrap_or_else(|err| ic_cdk::trap(&err))
+    };
+    let mut logs = Vec::with_capacity(batch.len().max(1));
+    if batch.is_empty() {
+        logs.push(insert(store::LuckyDrawLog(
+            caller,
+            now_sec,
+            draw_amount,
+            icp_received + TRANS_FEE,
+            x,
+            provably_fair,
+            None,
+            payment,
+            to,
+        )));
+    } else {
+        let n = batch.len() as u64;
+        let icp = (icp_received + TRANS_FEE) / n;
+        // the last draw takes the remainders of the split
+        let icp_last = icp_received + TRANS_FEE - icp * (n - 1);
+        for (i, store::LuckyDrawBatchItem(x, amount, provably_fair)) in
+            batch.into_iter().enumerate()
+        {
+            let last = i as u64 == n - 1;
+            let payment = payment.as_ref().map(|p| {
+                let paid = p.1 / n;
+                store::LuckyDrawPayment(p.0, if last { p.1 - paid * (n - 1) } else { paid }, p.2)
+            });
+            logs.push(insert(store::LuckyDrawLog(
+                caller,
+                now_sec,
+                amount,
+                if last { icp_last } else { icp },
+                x,
+                provably_fair,
+                Some(journal_id),
+                payment,
+                to,
+            )));
+        }
+    }
+    store::luckydraw::journal_remove(journal_id);
+    store::state::with_mut(|r| {
+        r.total_luckydraw = r.total_luckydraw.saturating_add(draw_amount + TRANS_FEE);
+        r.total_luckydraw_icp = r.total_luckydraw_icp.saturating_add(paid_icp);
+        r.total_luckydraw_count += logs.len() as u64;
+        if burn > 0 {
+            r.luckydraw_burn_pending = Some(
+                r.luckydraw_burn_pending
+                    .unwrap_or_default()
+                    .saturating_add(burn),
+            );
+        }
+        r.jackpot_pool = Some(
+            r.jackpot_pool
+                .unwrap_or_default()
+                .saturating_add(contribution),
+        );
+        for log in &logs {
+            r.latest_luckydraw_logs.insert(0, log.clone());
+            if table.is_jackpot(log.random) {
+                r.luckiest_luckydraw_logs.insert(0, log.clone());
+            }
+        }
+        r.latest_luckydraw_logs.truncate(10);
+        r.luckiest_luckydraw_logs.truncate(3);
+    });
+    Ok(logs)
+}
+
+// the PANDA equivalent of ICP received that is added to the progressive jackpot pool,
+// it is valued at the expected payout of the active table.
+fn jackpot_contribution(icp_received: u64) -> u64 {
+    let share = store::state::with(|r| r.jackpot_share.unwrap_or_default());
+    let (_, table) = store::luckydraw_table::active();
+    (icp_received as u128 * table.expected() as u128 * TOKEN_1 as u128 * share as u128
+        / ICP_1 as u128
+        / 10_000) as u64
+}
+
+// Unsettled luckydraws older than this are considered failed or trapped.
+const LUCKYDRAW_JOURNAL_TIMEOUT: u64 = 60 * 10;
+// The ledger deduplicates transfers created within 24 hours, an older payout can not be resent.
+const LUCKYDRAW_JOURNAL_RESEND_WINDOW: u64 = 3600 * 23;
+
+// settle the luckydraws that were interrupted between the ICP intake and the PANDA payout,
+// it is called by a timer.
+// A payout is resent with the same memo and created_at_time, so the ledger returns the
+// original block if it has been delivered. The payment is refunded only when the ledger
+// rejects the payout, an unknown outcome is resent on the next round.
+pub async fn settle_luckydraw_journal() {
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let resend_after = now_sec.saturating_sub(LUCKYDRAW_JOURNAL_RESEND_WINDOW);
+    let entries = store::luckydraw::journal_stale(
+        resend_after,
+        now_sec.saturating_sub(LUCKYDRAW_JOURNAL_TIMEOUT),
+        10,
+    );
+    for (id, entry) in entries {
+        let user = entry.0;
+        let ts = entry.1;
+        // the user is drawing now, try again later
+        if !store::user::active(user) {
+            continue;
+        }
+        let _guard = scopeguard::guard((), |_| {
+            store::user::deactive(user);
+        });
+
+        if entry.5 == types::LuckyDrawStage::PayoutSent {
+            match token_transfer_to(
+                entry.10.unwrap_or(Account::from(user)),
+                Nat::from(entry.3),
+                operation_memo("payout", id),
+                ts * SECOND,
+            )
             .await
-            .map_err(|err| format!("failed to refund ICP, {}", err))?;
-        Err("insufficient token balance for luckydraw, ICP refunded".to_string())
+            {
+                Ok(_) => {
+                    // the table may have been changed since a non provably fair draw
+                    let table = entry
+                        .6
+                        .as_ref()
+                        .or_else(|| entry.8.first().and_then(|draw| draw.2.as_ref()))
+                        .and_then(|pf| store::luckydraw_table::get(pf.3))
+                        .unwrap_or_else(|| store::luckydraw_table::active().1);
+                    let _ = luckydraw_settle(id, entry, &table);
+                    continue;
+                }
+                Err(err) if err.rejected => {}
+                // try again on the next round, or resolve it after the deduplication window
+                Err(_) => continue,
+            }
+        }
+
+        store::state::add_jackpot(entry.7);
+        store::luckydraw::journal_update(id, types::LuckyDrawStage::RefundOwed, 0, 0);
+        // previous refunds out of the deduplication window have been rejected by the ledger
+        let created_at = if ts >= resend_after { ts } else { now_sec };
+        if luckydraw_refund_transfer(id, &entry, created_at * SECOND)
+            .await
+            .is_ok()
+        {
+            store::luckydraw::journal_remove(id);
+        }
     }
 }
 
-// 344693032001 from b"PANDA"
-const LUCKYDRAW_DIVISOR: u64 = u64::from_be_bytes([0, 0, 0, b'P', b'A', b'N', b'D', b'A']);
+// the luckydraws whose payouts can not be resent after the deduplication window.
+pub fn unresolved_luckydraw_journal() -> Vec<types::LuckyDrawJournal> {
+    let now_sec = ic_cdk::api::time() / SECOND;
+    store::luckydraw::journal_unresolved(now_sec.saturating_sub(LUCKYDRAW_JOURNAL_RESEND_WINDOW))
+}
+
+// resolve a luckydraw whose payout can not be resent, after checking the payout on the ledger.
+// A delivered payout is settled, otherwise the payment is refunded by the journal timer.
+// Return the resolved journal entry.
+pub fn resolve_luckydraw_journal(
+    id: u64,
+    resolution: types::LuckyDrawResolution,
+) -> Result<store::LuckyDrawJournal, String> {
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let entry = store::luckydraw::journal_get(id).ok_or("journal entry not found".to_string())?;
+    if entry.5 != types::LuckyDrawStage::PayoutSent
+        || entry.1 >= now_sec.saturating_sub(LUCKYDRAW_JOURNAL_RESEND_WINDOW)
+    {
+        return Err("the payout can still be resent by the journal timer".to_string());
+    }
+    let user = entry.0;
+    if !store::user::active(user) {
+        return Err("try again later".to_string());
+    }
+    let _guard = scopeguard::guard((), |_| {
+        store::user::deactive(user);
+    });
+
+    match resolution {
+        types::LuckyDrawResolution::Delivered => {
+            let table = entry
+                .6
+                .as_ref()
+                .or_else(|| entry.8.first().and_then(|draw| draw.2.as_ref()))
+                .and_then(|pf| store::luckydraw_table::get(pf.3))
+                .unwrap_or_else(|| store::luckydraw_table::active().1);
+            luckydraw_settle(id, entry.clone(), &table)?;
+        }
+        types::LuckyDrawResolution::Refund => {
+            store::state::add_jackpot(entry.7);
+            store::luckydraw::journal_update(id, types::LuckyDrawStage::RefundOwed, 0, 0);
+        }
+    }
+    Ok(entry)
+}
+
+// reconcile the airdrop balance against the PANDA balance of the pool on the ledger,
+// it is called by a timer.
+// The airdrop balance is the ledger balance minus the reserves: the jackpot pool, the unsettled
+// payouts, the PANDA payments waiting to be burned and the prize deposits owed to the depositors. The liability alert is raised when the outstanding claimable
+// tokens and the reserved prize budgets exceed the airdrop balance.
+pub async fn reconcile_airdrop_balance() {
+    // the canister id is used as the lock of the pool operations
+    let canister = ic_cdk::id();
+    if !store::user::active(canister) {
+        return;
+    }
+    let _guard = scopeguard::guard((), |_| {
+        store::user::deactive(canister);
+    });
+
+    // a harvest in flight may have been transferred but not deducted from the airdrop balance
+    let (started, finished) = store::user::harvests();
+    if started != finished {
+        return;
+    }
+    let prev = store::state::with(|r| r.airdrop_balance);
+    let balance = match token_balance_of(TOKEN_CANISTER, canister).await {
+        Ok(balance) => nat_to_u64(&balance),
+        Err(_) => return,
+    };
+    if store::user::harvests().0 != started {
+        return;
+    }
+
+    let now_sec = ic_cdk::api::time() / SECOND;
+    let reserves = store::state::with(|r| {
+        r.jackpot_pool
+            .unwrap_or_default()
+            .saturating_add(r.luckydraw_burn_pending.unwrap_or_default())
+    })
+    .saturating_add(store::luckydraw::journal_payouts())
+    .saturating_add(store::prize::refunds_owed());
+    let liabilities = store::state::liability();
+    store::state::with_mut(|r| {
+        // a harvest during the call changed the balance, try again in the next round
+        if r.airdrop_balance != prev {
+            return;
+        }
+        r.airdrop_balance = balance.saturating_sub(reserves);
+        r.ledger_balance = Some(balance);
+        r.reconciled_at = Some(now_sec);
+        r.liability_alert = Some(liabilities > r.airdrop_balance);
+    });
+}
 
-fn luckydraw_amount(random: &[u8]) -> (u64, u64) {
+// transfer the unclaimed budgets of the deposit funded prizes back to the depositors,
+// it is called by a timer. The ledger fee is paid from the refund.
+// A refund is resent with the same memo and created_at_time until it is transferred, it gets
+// a new created_at_time only when the ledger rejects it.
+pub async fn refund_prize_deposits() {
+    // the canister id is used as the lock of the pool operations
+    let canister = ic_cdk::id();
+    if !store::user::active(canister) {
+        return;
+    }
+    let _guard = scopeguard::guard((), |_| {
+        store::user::deactive(canister);
+    });
+
+    let now_sec = ic_cdk::api::time() / SECOND;
+    for (id, refund) in store::prize::pending_refunds(10) {
+        if refund.1 <= TRANS_FEE {
+            store::prize::update_refund(id, None);
+            continue;
+        }
+        match token_transfer_to(
+            Account::from(refund.0),
+            Nat::from(refund.1 - TRANS_FEE),
+            operation_memo("prize_refund", id),
+            refund.2 * SECOND,
+        )
+        .await
+        {
+            Ok(_) => store::prize::update_refund(id, None),
+            Err(err) if err.rejected => store::prize::update_refund(id, Some(now_sec)),
+            // the outcome is unknown, resend it on the next round
+            Err(_) => {}
+        }
+    }
+}
+
+// burn the PANDA tokens paid for luckydraws, it is called by a timer.
+// The pending tokens are reserved from the airdrop balance until they are burned.
+pub async fn burn_luckydraw_payments() {
+    let pending = store::state::with(|r| r.luckydraw_burn_pending.unwrap_or_default());
+    if pending < TRANS_FEE {
+        return;
+    }
+
+    // the canister id is used as the lock of the pool operations
+    let canister = ic_cdk::id();
+    if !store::user::active(canister) {
+        return;
+    }
+    let _guard = scopeguard::guard((), |_| {
+        store::user::deactive(canister);
+    });
+
+    let now_sec = ic_cdk::api::time() / SECOND;
+    if token_burn(
+        Nat::from(pending),
+        operation_memo("burn", store::state::next_operation_id()),
+        now_sec * SECOND,
+    )
+    .await
+    .is_ok()
+    {
+        store::state::with_mut(|r| {
+            r.luckydraw_burn_pending = Some(
+                r.luckydraw_burn_pending
+                    .unwrap_or_default()
+                    .saturating_sub(pending),
+            );
+            r.total_luckydraw_burned = Some(
+                r.total_luckydraw_burned
+                    .unwrap_or_default()
+                    .saturating_add(pending),
+            );
+        });
+    }
+}
+
+// move the old logs to the archive canister when the local logs of a kind exceed the threshold,
+// the latest tenth of the threshold are kept locally.
+pub async fn archive_logs() {
+    // the index entries of the archived logs are removed in bounded chunks on each round
+    store::airdrop::prune_index(ARCHIVE_PRUNE_SIZE);
+    store::luckydraw::prune_index(ARCHIVE_PRUNE_SIZE);
+
+    let threshold = match store::state::with(|r| r.archive_threshold) {
+        Some(threshold) => threshold,
+        None => return,
+    };
+    let keep = threshold / 10;
+    let (airdrop_start, airdrop_next) = (
+        store::airdrop::first_log_id(),
+        store::airdrop::next_log_id(),
+    );
+    let (luckydraw_start, luckydraw_next) = (
+        store::luckydraw::first_log_id(),
+        store::luckydraw::next_log_id(),
+    );
+    // the logs are not archived while their stable log is being rebuilt
+    let airdrop_due = airdrop_next - airdrop_start > threshold && !store::airdrop::compacting();
+    let luckydraw_due =
+        luckydraw_next - luckydraw_start > threshold && !store::luckydraw::compacting();
+    if !airdrop_due && !luckydraw_due {
+        return;
+    }
+
+    // the canister id is used as the lock of archiving
+    let canister = ic_cdk::id();
+    if !store::user::active(canister) {
+        return;
+    }
+    let _guard = scopeguard::guard((), |_| {
+        store::user::deactive(canister);
+    });
+
+    let archive = match archive_canister().await {
+        Ok(archive) => archive,
+        Err(_) => return,
+    };
+    if airdrop_due {
+        if let Ok(end) = archive_transfer(
+            archive,
+            "append_airdrop_logs",
+            airdrop_start,
+            airdrop_next - keep,
+            store::airdrop::logs_range,
+        )
+        .await
+        {
+            store::airdrop::truncate_logs(end);
+        }
+    }
+    if luckydraw_due {
+        if let Ok(end) = archive_transfer(
+            archive,
+            "append_luckydraw_logs",
+            luckydraw_start,
+            luckydraw_next - keep,
+            store::luckydraw::logs_range,
+        )
+        .await
+        {
+            store::luckydraw::truncate_logs(end);
+        }
+    }
+}
+
+// record the claimers of the prizes claimed before PRIZE_CLAIMERS from the airdrop logs,
+// the archived logs are fetched from the archive canister. It is called by a timer until
+// all logs have been scanned, return true when done.
+pub async fn backfill_prize_claimers() -> bool {
+    let start = match store::prize::claimers_backfill_next() {
+        Some(start) => start,
+        None => return true,
+    };
+    let first_id = store::airdrop::first_log_id();
+    if start >= first_id {
+        let end = start + ARCHIVE_BATCH_SIZE;
+        let logs = store::airdrop::logs_range(start, end);
+        return store::prize::backfill_claimers(start, end, &logs);
+    }
+
+    let archive = match store::state::with(|r| r.archive_canister) {
+        Some(archive) => archive,
+        None => return store::prize::backfill_claimers(start, first_id, &[]),
+    };
+    // the archive returns at most 100 logs before `prev` in reverse order
+    let end = first_id.min(start + 100);
+    let res: Result<(Vec<types::AirdropLog>,), _> = ic_cdk::call(
+        archive,
+        "airdrop_logs",
+        (Some(Nat::from(end)), Some(Nat::from(end - start))),
+    )
+    .await;
+    match res {
+        Ok((logs,)) => store::prize::backfill_claimers(start, end, &logs),
+        Err(_) => false,
+    }
+}
+
+// get the archive canister, spawn it with the uploaded wasm if it does not exist.
+// The canister id is saved once it is created, a failed installation is retried in the next round.
+async fn archive_canister() -> Result<Principal, String> {
+    let (archive, installed) = store::state::with(|r| (r.archive_canister, r.archive_installed));
+    if let (Some(archive), None | Some(true)) = (archive, installed) {
+        return Ok(archive);
+    }
+    let wasm_module = store::archive::wasm();
+    if wasm_module.is_empty() {
+        return Err("archive wasm is not set".to_string());
+    }
+
+    let arg = candid::encode_one(types::ArchiveInitArgs { pool: ic_cdk::id() })
+        .map_err(|err| format!("failed to encode archive init args, error: {:?}", err))?;
+    let archive = match archive {
+        Some(archive) => archive,
+        None => {
+            let (record,) = create_canister(
+                CreateCanisterArgument {
+                    settings: Some(CanisterSettings {
+                        controllers: Some(vec![ic_cdk::id(), DAO_CANISTER]),
+                        ..Default::default()
+                    }),
+                },
+                ARCHIVE_CREATION_CYCLES,
+            )
+            .await
+            .map_err(|err| format!("failed to create archive canister, error: {:?}", err))?;
+            store::state::with_mut(|r| {
+                r.archive_canister = Some(record.canister_id);
+                r.archive_installed = Some(false);
+            });
+            store::state::save();
+            record.canister_id
+        }
+    };
+    install_code(InstallCodeArgument {
+        mode: CanisterInstallMode::Install,
+        canister_id: archive,
+        wasm_module,
+        arg,
+    })
+    .await
+    .map_err(|err| format!("failed to install archive canister, error: {:?}", err))?;
+
+    store::state::with_mut(|r| r.archive_installed = Some(true));
+    store::state::save();
+    Ok(archive)
+}
+
+// send the logs in [start, end) to the archive canister in batches,
+// return the next log id of the archive, the logs before it can be removed locally.
+async fn archive_transfer<T: CandidType>(
+    archive: Principal,
+    method: &str,
+    start: u64,
+    end: u64,
+    logs_range: fn(u64, u64) -> Vec<T>,
+) -> Result<u64, String> {
+    let mut next = start;
+    while next < end {
+        let logs = logs_range(next, end.min(next + ARCHIVE_BATCH_SIZE));
+        if logs.is_empty() {
+            break;
+        }
+        let (res,): (Result<u64, String>,) = ic_cdk::call(archive, method, (logs,))
+            .await
+            .map_err(|err| format!("failed to call {}, error: {:?}", method, err))?;
+        let archived = res?;
+        if archived <= next {
+            return Err(format!("archive made no progress at log {}", next));
+        }
+        next = archived;
+    }
+    Ok(next)
+}
+
+pub fn luckydraw_amount(table: &types::LuckyDrawTable, random: &[u8]) -> (u64, u64) {
     let mut bytes = [0u8; 8];
     bytes.copy_from_slice(&random[0..8]);
     let x = u64::from_be_bytes(bytes);
-    let x = x % LUCKYDRAW_DIVISOR;
-    let amount = match x / TOKEN_1 {
-        v if v <= 5 => LUCKIEST_AIRDROP_AMOUNT,
-        v if v <= 1000 => 1000,
-        v => v,
-    };
+    let x = x % table.modulus;
+    (x, table.prize(x / TOKEN_1) * TOKEN_1)
+}
 
-    (x, amount * TOKEN_1)
+// the random bytes of a provably fair draw:
+// HMAC-SHA3-256(server_seed, CBOR([client_seed, nonce])).
+pub fn provably_fair_random(server_seed: &[u8], client_seed: &str, nonce: u64) -> [u8; 32] {
+    mac_256(server_seed, &to_cbor_bytes(&(client_seed, nonce)))
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::types::LUCKYDRAW_DIVISOR;
 
     #[test]
     fn test_luckydraw_amount() {
         assert_eq!(LUCKYDRAW_DIVISOR, 344693032001);
+        let table = types::LuckyDrawTable::default();
 
-        let rt = luckydraw_amount(vec![0u8; 8].as_slice());
+        let rt = luckydraw_amount(&table, vec![0u8; 8].as_slice());
         assert_eq!(rt.0, 0);
         assert_eq!(rt.1, 100000 * TOKEN_1);
 
-        let rt = luckydraw_amount(vec![0, 0, 0, 0, 29, 205, 101, 0].as_slice());
+        let rt = luckydraw_amount(&table, vec![0, 0, 0, 0, 29, 205, 101, 0].as_slice());
         assert_eq!(rt.0, TOKEN_1 * 5);
         assert_eq!(rt.1, 100000 * TOKEN_1);
 
-        let rt = luckydraw_amount(vec![0, 0, 0, 2, 84, 11, 228, 0].as_slice());
+        let rt = luckydraw_amount(&table, vec![0, 0, 0, 2, 84, 11, 228, 0].as_slice());
         assert_eq!(rt.0, TOKEN_1 * 100);
         assert_eq!(rt.1, 1000 * TOKEN_1);
 
-        let rt = luckydraw_amount(vec![0, 0, 0, 23, 72, 118, 232, 0].as_slice());
+        let rt = luckydraw_amount(&table, vec![0, 0, 0, 23, 72, 118, 232, 0].as_slice());
         assert_eq!(rt.0, TOKEN_1 * 1000);
         assert_eq!(rt.1, 1000 * TOKEN_1);
 
-        let rt = luckydraw_amount(vec![0, 0, 0, 46, 144, 237, 208, 0].as_slice());
+        let rt = luckydraw_amount(&table, vec![0, 0, 0, 46, 144, 237, 208, 0].as_slice());
         assert_eq!(rt.0, TOKEN_1 * 2000);
         assert_eq!(rt.1, 2000 * TOKEN_1);
 
-        let rt = luckydraw_amount(vec![0, 0, 0, 80, 59, 194, 182, 0].as_slice());
+        let rt = luckydraw_amount(&table, vec![0, 0, 0, 80, 59, 194, 182, 0].as_slice());
         assert_eq!(rt.0, TOKEN_1 * 3446);
         assert_eq!(rt.1, 3446 * TOKEN_1);
 
-        let rt = luckydraw_amount(vec![0, 0, 0, 80, 65, 184, 151, 0].as_slice());
+        let rt = luckydraw_amount(&table, vec![0, 0, 0, 80, 65, 184, 151, 0].as_slice());
         assert_eq!(rt.0, TOKEN_1 * 3447u64 - LUCKYDRAW_DIVISOR);
         assert_eq!(rt.1, 100000 * TOKEN_1);
     }
+
+    #[test]
+    fn test_luckydraw_settle_batch() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let table = types::LuckyDrawTable::default();
+        let draws = vec![
+            store::LuckyDrawBatchItem(TOKEN_1 * 100, 1000 * TOKEN_1, None),
+            store::LuckyDrawBatchItem(TOKEN_1 * 2000, 2000 * TOKEN_1, None),
+            store::LuckyDrawBatchItem(0, 100000 * TOKEN_1, None),
+        ];
+        let entry = store::LuckyDrawJournal(
+            alice,
+            100,
+            3 * ICP_1 - TRANS_FEE,
+            103000 * TOKEN_1,
+            TOKEN_1 * 100,
+            types::LuckyDrawStage::PayoutSent,
+            None,
+            0,
+            draws,
+            None,
+            None,
+        );
+        let journal_id = store::luckydraw::journal_add(entry.clone());
+        let logs = luckydraw_settle(journal_id, entry, &table).unwrap();
+        assert_eq!(logs.len(), 3);
+        assert!(logs
+            .iter()
+            .all(|log| log.batch_id == Some(Nat::from(journal_id))));
+        assert_eq!(logs[2].amount, Nat::from(100000 * TOKEN_1));
+        assert!(store::luckydraw::journal_get(journal_id).is_none());
+        assert_eq!(store::state::with(|r| r.total_luckydraw_count), 3);
+        assert_eq!(store::state::with(|r| r.luckiest_luckydraw_logs.len()), 1);
+    }
 }
@@ -2,12 +2,13 @@ use candid::{Nat, Principal};
 use icrc_ledger_types::{
     icrc1::{
         account::Account,
-        transfer::{TransferArg, TransferError},
+        transfer::{Memo, TransferArg, TransferError},
     },
     icrc2::transfer_from::{TransferFromArgs, TransferFromError},
 };
+use lib_panda::to_cbor_bytes;
 use num_traits::cast::ToPrimitive;
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::Into;
 
 mod api_admin;
@@ -66,30 +67,91 @@ async fn token_balance_of(canister: Principal, user: Principal) -> Result<Nat, S
     Ok(balance)
 }
 
-async fn token_transfer_to(user: Principal, amount: Nat) -> Result<Nat, String> {
+// operation_memo returns the memo of a ledger operation: (operation, operation id) in CBOR.
+// The operation id is taken from store::state::next_operation_id and never reused, a retried
+// transfer with the same memo and created_at_time will be deduplicated by the ledger.
+fn operation_memo(operation: &str, id: u64) -> Memo {
+    Memo::from(to_cbor_bytes(&(operation, id)))
+}
+
+// TransferFailure is the error of a PANDA transfer.
+// A rejected transfer has been refused by the ledger for good and will never be executed,
+// otherwise the outcome is unknown (the call failed or the ledger is temporarily unavailable)
+// and the transfer should be resent with the same memo and created_at_time.
+#[derive(Debug)]
+struct TransferFailure {
+    rejected: bool,
+    error: String,
+}
+
+impl From<TransferFailure> for String {
+    fn from(failure: TransferFailure) -> Self {
+        failure.error
+    }
+}
+
+async fn token_transfer_to(
+    to: Account,
+    amount: Nat,
+    memo: Memo,
+    created_at_time: u64,
+) -> Result<Nat, TransferFailure> {
     let (res,): (Result<Nat, TransferError>,) = ic_cdk::call(
         TOKEN_CANISTER,
         "icrc1_transfer",
         (TransferArg {
             from_subaccount: None,
-            to: Account {
-                owner: user,
-                subaccount: None,
-            },
+            to,
             fee: None,
-            created_at_time: None,
-            memo: None,
+            created_at_time: Some(created_at_time),
+            memo: Some(memo),
             amount,
         },),
     )
     .await
-    .map_err(|err| format!("failed to call icrc1_transfer, error: {:?}", err))?;
-    res.map_err(|err| format!("failed to transfer tokens, error: {:?}", err))
+    .map_err(|err| TransferFailure {
+        rejected: false,
+        error: format!("failed to call icrc1_transfer, error: {:?}", err),
+    })?;
+    match res {
+        Ok(block_idx) => Ok(block_idx),
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        // the ledger checks the deduplication before the balance and the fee,
+        // so a resent transfer that has been executed is never rejected with these errors.
+        Err(
+            err @ (TransferError::InsufficientFunds { .. }
+            | TransferError::BadFee { .. }
+            | TransferError::BadBurn { .. }),
+        ) => Err(TransferFailure {
+            rejected: true,
+            error: format!("failed to transfer tokens, error: {:?}", err),
+        }),
+        Err(err) => Err(TransferFailure {
+            rejected: false,
+            error: format!("failed to transfer tokens, error: {:?}", err),
+        }),
+    }
+}
+
+// burn PANDA tokens of the pool by transferring them to the minting account of the ledger.
+async fn token_burn(amount: Nat, memo: Memo, created_at_time: u64) -> Result<Nat, String> {
+    let (minting_account,): (Option<Account>,) =
+        ic_cdk::call(TOKEN_CANISTER, "icrc1_minting_account", ())
+            .await
+            .map_err(|err| format!("failed to call icrc1_minting_account, error: {:?}", err))?;
+    let minting_account = minting_account.ok_or("no minting account".to_string())?;
+    Ok(token_transfer_to(minting_account, amount, memo, created_at_time).await?)
 }
 
-async fn icp_transfer_to(user: Principal, amount: Nat) -> Result<Nat, String> {
+async fn ledger_transfer_to(
+    ledger: Principal,
+    user: Principal,
+    amount: Nat,
+    memo: Memo,
+    created_at_time: u64,
+) -> Result<Nat, String> {
     let (res,): (Result<Nat, TransferError>,) = ic_cdk::call(
-        ICP_CANISTER,
+        ledger,
         "icrc1_transfer",
         (TransferArg {
             from_subaccount: None,
@@ -98,19 +160,32 @@ async fn icp_transfer_to(user: Principal, amount: Nat) -> Result<Nat, String> {
                 subaccount: None,
             },
             fee: None,
-            created_at_time: None,
-            memo: None,
+            created_at_time: Some(created_at_time),
+            memo: Some(memo),
             amount,
         },),
     )
     .await
     .map_err(|err| format!("failed to call icrc1_transfer, error: {:?}", err))?;
-    res.map_err(|err| format!("failed to transfer ICP, error: {:?}", err))
+    match res {
+        Ok(block_idx) => Ok(block_idx),
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        Err(err) => Err(format!(
+            "failed to transfer tokens of {}, error: {:?}",
+            ledger, err
+        )),
+    }
 }
 
-async fn icp_transfer_from(user: Principal, amount: Nat) -> Result<Nat, String> {
+async fn ledger_transfer_from(
+    ledger: Principal,
+    user: Principal,
+    amount: Nat,
+    memo: Memo,
+    created_at_time: u64,
+) -> Result<Nat, String> {
     let (res,): (Result<Nat, TransferFromError>,) = ic_cdk::call(
-        ICP_CANISTER,
+        ledger,
         "icrc2_transfer_from",
         (TransferFromArgs {
             spender_subaccount: None,
@@ -123,14 +198,21 @@ async fn icp_transfer_from(user: Principal, amount: Nat) -> Result<Nat, String>
                 subaccount: None,
             },
             fee: None,
-            created_at_time: None,
-            memo: None,
+            created_at_time: Some(created_at_time),
+            memo: Some(memo),
             amount,
         },),
     )
     .await
     .map_err(|err| format!("failed to call icrc2_transfer_from, error: {:?}", err))?;
-    res.map_err(|err| format!("failed to transfer ICP from user, error: {:?}", err))
+    match res {
+        Ok(block_idx) => Ok(block_idx),
+        Err(TransferFromError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        Err(err) => Err(format!(
+            "failed to transfer tokens of {} from user, error: {:?}",
+            ledger, err
+        )),
+    }
 }
 
 ic_cdk::export_candid!();
@@ -150,4 +232,14 @@ mod test {
         let id2 = Principal::from_slice(&id.to_bytes());
         assert_eq!(s, id2.to_string());
     }
+
+    #[test]
+    fn test_operation_memo() {
+        // ICP ledger accepts memo up to 32 bytes
+        for op in ["luckydraw", "payout", "refund", "harvest", "collect"] {
+            let memo = operation_memo(op, u64::MAX);
+            assert!(memo.0.len() <= 32);
+            assert_ne!(memo, operation_memo(op, u64::MAX - 1));
+        }
+    }
 }
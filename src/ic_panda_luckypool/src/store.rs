@@ -1,3 +1,4 @@
+use base64::{engine::general_purpose, Engine};
 use candid::{CandidType, Nat, Principal};
 use ciborium::{from_reader, into_writer};
 use ic_stable_structures::{
@@ -5,17 +6,20 @@ use ic_stable_structures::{
     storable::Bound,
     DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable,
 };
-use lib_panda::mac_256;
+use icrc_ledger_types::icrc1::account::Account;
+use lib_panda::{mac_256, sha3_256};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use std::{
     borrow::Cow,
     cell::RefCell,
     collections::{BTreeMap, BTreeSet},
+    ops,
 };
 
 use crate::utils::{luckycode_from_string, luckycode_to_string};
-use crate::{types, TOKEN_1};
+use crate::{types, SECOND, TOKEN_1};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -34,6 +38,38 @@ pub struct State {
     pub latest_luckydraw_logs: Vec<types::LuckyDrawLog>, // latest 10 luckydraw logs
     pub managers: Option<BTreeSet<Principal>>,
     pub airdrop_amount: Option<u64>,
+    pub luckydraw_max_expected: Option<u64>, // max expected tokens of the payout table for 1 ICP
+    pub jackpot_pool: Option<u64>, // progressive jackpot tokens in E8, paid out entirely on a jackpot hit
+    pub jackpot_share: Option<u16>, // share of each draw's ICP in PANDA equivalent added to the jackpot pool, in basis points
+    pub airdrop_logs_indexed: Option<u64>, // number of airdrop logs backfilled into the per-user index
+    pub luckydraw_logs_indexed: Option<u64>, // number of luckydraw logs backfilled into the per-user index
+    pub archive_canister: Option<Principal>, // the spawned archive canister of old logs
+    pub archive_threshold: Option<u64>, // local logs of each kind that trigger archiving, None disables it
+    pub airdrop_logs_archived: Option<u64>, // number of airdrop logs moved to the archive, the id of the oldest local log
+    pub luckydraw_logs_archived: Option<u64>, // number of luckydraw logs moved to the archive, the id of the oldest local log
+    pub checkpointed_at: Option<u64>, // the time in seconds of the last checkpoint into stable memory
+    pub roles: Option<BTreeMap<Principal, BTreeSet<types::Role>>>, // scoped roles of managers, replace managers once set
+    pub ledger_balance: Option<u64>, // PANDA balance of the pool on the ledger at the last reconciliation
+    pub reconciled_at: Option<u64>, // the time in seconds of the last reconciliation against the ledger
+    pub liability_alert: Option<bool>, // the outstanding claimable tokens exceed the airdrop balance
+    pub prize_reserved: Option<u64>,   // unclaimed budgets of the open prizes funded by the pool
+    pub total_credited: Option<u64>, // tokens credited to claimable balances by claims, referrals and prizes
+    pub total_harvested: Option<u64>, // tokens harvested from claimable balances
+    pub total_claimable: Option<u64>, // outstanding claimable tokens of all users, the liability of the pool
+    pub next_operation_id: Option<u64>, // the next id of the ledger operations, never reused
+    pub luckydraw_burn_pending: Option<u64>, // PANDA paid for luckydraws that is waiting to be burned
+    pub total_luckydraw_burned: Option<u64>, // PANDA paid for luckydraws that has been burned
+    pub airdrop_logs_base: Option<u64>, // the id of the first airdrop log in the active stable log
+    pub luckydraw_logs_base: Option<u64>, // the id of the first luckydraw log in the active stable log
+    pub airdrop_logs_pruned: Option<u64>, // number of archived airdrop logs removed from the per-user index
+    pub luckydraw_logs_pruned: Option<u64>, // number of archived luckydraw logs removed from the per-user index
+    pub archive_installed: Option<bool>, // the archive canister has been installed, None for the one spawned before
+    pub prize_claimers_backfilled: Option<u64>, // the next airdrop log to scan for prize claimers, u64::MAX when done
+    pub legacy_bans_backfilled: Option<u64>, // the next lucky code to scan for the users banned before the ban registry, u64::MAX when done
+    pub airdrop_logs_slot: Option<u8>, // the memories of the active airdrop stable log, 0 or 1
+    pub luckydraw_logs_slot: Option<u8>, // the memories of the active luckydraw stable log, 0 or 1
+    pub airdrop_logs_compacting: Option<bool>, // the airdrop stable log is being rebuilt without the archived logs
+    pub luckydraw_logs_compacting: Option<bool>, // the luckydraw stable log is being rebuilt without the archived logs
 }
 
 impl Storable for State {
@@ -55,6 +91,70 @@ impl Storable for State {
 #[derive(Clone, Default, Deserialize, Serialize)]
 pub struct AirdropState(pub u32, pub u64, pub u64);
 
+impl From<&AirdropState> for types::AirdropStateOutput {
+    fn from(state: &AirdropState) -> Self {
+        types::AirdropStateOutput {
+            lucky_code: Some(luckycode_to_string(state.0)),
+            claimed: Nat::from(state.1),
+            claimable: Nat::from(state.2),
+        }
+    }
+}
+
+impl From<&State> for types::StateOutput {
+    fn from(state: &State) -> Self {
+        types::StateOutput {
+            airdrop_balance: state.airdrop_balance,
+            total_airdrop: state.total_airdrop,
+            total_airdrop_count: state.total_airdrop_count,
+            total_luckydraw: state.total_luckydraw,
+            total_luckydraw_icp: state.total_luckydraw_icp,
+            total_luckydraw_count: state.total_luckydraw_count,
+            total_prize: state.total_prize,
+            total_prize_count: state.total_prize_count,
+            latest_airdrop_logs: state.latest_airdrop_logs.clone(),
+            luckiest_luckydraw_logs: state.luckiest_luckydraw_logs.clone(),
+            latest_luckydraw_logs: state.latest_luckydraw_logs.clone(),
+            managers: state.managers.clone(),
+            airdrop_amount: state.airdrop_amount,
+            luckydraw_max_expected: state.luckydraw_max_expected,
+            jackpot_pool: state.jackpot_pool,
+            jackpot_share: state.jackpot_share,
+            archive_canister: state.archive_canister,
+            archive_threshold: state.archive_threshold,
+            airdrop_logs_archived: state.airdrop_logs_archived,
+            luckydraw_logs_archived: state.luckydraw_logs_archived,
+            checkpointed_at: state.checkpointed_at,
+            roles: state.roles.clone(),
+            ledger_balance: state.ledger_balance,
+            reconciled_at: state.reconciled_at,
+            liability_alert: state.liability_alert,
+            prize_reserved: state.prize_reserved,
+            total_credited: state.total_credited,
+            total_harvested: state.total_harvested,
+            total_claimable: state.total_claimable,
+            luckydraw_burn_pending: state.luckydraw_burn_pending,
+            total_luckydraw_burned: state.total_luckydraw_burned,
+        }
+    }
+}
+
+impl From<&State> for types::StateSummary {
+    fn from(state: &State) -> Self {
+        types::StateSummary {
+            airdrop_balance: state.airdrop_balance,
+            total_airdrop: state.total_airdrop,
+            total_airdrop_count: state.total_airdrop_count,
+            total_luckydraw: state.total_luckydraw,
+            total_luckydraw_icp: state.total_luckydraw_icp,
+            total_luckydraw_count: state.total_luckydraw_count,
+            total_prize: state.total_prize.unwrap_or_default(),
+            total_prize_count: state.total_prize_count.unwrap_or_default(),
+            jackpot_pool: state.jackpot_pool.unwrap_or_default(),
+        }
+    }
+}
+
 impl Storable for AirdropState {
     const BOUND: Bound = Bound::Unbounded;
 
@@ -69,9 +169,49 @@ impl Storable for AirdropState {
     }
 }
 
-// AirdropLog format: (user, time, token_amount, lucky_code)
+// AirdropLog format: (user, time, debited_amount, counterparty_code, to, kind, credited_amount)
+// to: the account received harvested tokens if it is not the user's default account
+// kind: None for the logs before kinds were introduced, they are either a claim with
+// the referrer code and no amount, or a harvest with the amount and code 0.
 #[derive(Clone, Deserialize, Serialize)]
-pub struct AirdropLog(Principal, u64, u64, u32);
+pub struct AirdropLog(
+    Principal,
+    u64,
+    u64,
+    u32,
+    #[serde(default)] Option<Account>,
+    #[serde(default)] Option<types::AirdropLogKind>,
+    #[serde(default)] u64,
+);
+
+impl AirdropLog {
+    pub fn new(
+        user: Principal,
+        now_sec: u64,
+        kind: types::AirdropLogKind,
+        credited: u64,
+        debited: u64,
+        counterparty_code: u32,
+    ) -> Self {
+        AirdropLog(
+            user,
+            now_sec,
+            debited,
+            counterparty_code,
+            None,
+            Some(kind),
+            credited,
+        )
+    }
+
+    pub fn kind(&self) -> types::AirdropLogKind {
+        match self.5 {
+            Some(kind) => kind,
+            None if self.2 > 0 => types::AirdropLogKind::Harvest,
+            None => types::AirdropLogKind::Claim,
+        }
+    }
+}
 
 impl From<(u64, AirdropLog)> for types::AirdropLog {
     fn from(log: (u64, AirdropLog)) -> Self {
@@ -82,6 +222,10 @@ impl From<(u64, AirdropLog)> for types::AirdropLog {
             caller: log.0,
             amount: Nat::from(log.2),
             lucky_code: luckycode_to_string(log.3),
+            to: log.4,
+            kind: log.kind(),
+            credited: Nat::from(log.6),
+            debited: Nat::from(log.2),
         }
     }
 }
@@ -100,9 +244,24 @@ impl Storable for AirdropLog {
     }
 }
 
-// AirdropLog format: (user, time, token_amount, icp_amount, random_number)
+// LuckyDrawLog format: (user, time, token_amount, icp_amount, random_number, provably_fair, batch_id, payment, to)
+// provably_fair is None for draws with random bytes from the management canister.
+// batch_id: the journal id of the batch, None for single draws. The batches settled before it
+// was the journal id have the log index of the first draw in the batch.
+// payment: the payment in a token other than ICP, icp_amount is its ICP equivalent.
+// to: the account received tokens if it is not the user's default account.
 #[derive(Clone, Deserialize, Serialize)]
-pub struct LuckyDrawLog(Principal, u64, u64, u64, u64);
+pub struct LuckyDrawLog(
+    pub Principal,
+    pub u64,
+    pub u64,
+    pub u64,
+    pub u64,
+    #[serde(default)] pub Option<ProvablyFair>,
+    #[serde(default)] pub Option<u64>,
+    #[serde(default)] pub Option<LuckyDrawPayment>,
+    #[serde(default)] pub Option<Account>,
+);
 
 impl From<(u64, LuckyDrawLog)> for types::LuckyDrawLog {
     fn from(log: (u64, LuckyDrawLog)) -> Self {
@@ -114,10 +273,116 @@ impl From<(u64, LuckyDrawLog)> for types::LuckyDrawLog {
             amount: Nat::from(log.2),
             icp_amount: Nat::from(log.3),
             random: log.4,
+            provably_fair: log.5.map(types::ProvablyFair::from),
+            batch_id: log.6.map(Nat::from),
+            payment: log.7.map(|p| types::LuckyDrawPayment {
+                ledger: p.0,
+                amount: Nat::from(p.1),
+            }),
+            to: log.8,
+        }
+    }
+}
+
+// LuckyDrawPayment format: (ledger, paid amount, ledger fee)
+// paid amount: tokens paid by the user in the smallest units, including the ledger fee.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LuckyDrawPayment(pub Principal, pub u64, pub u64);
+
+// ProvablyFair format: (server seed epoch, client seed, nonce, payout table version)
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ProvablyFair(pub u32, pub String, pub u64, #[serde(default)] pub u32);
+
+impl From<ProvablyFair> for types::ProvablyFair {
+    fn from(pf: ProvablyFair) -> Self {
+        types::ProvablyFair {
+            seed_epoch: pf.0,
+            client_seed: pf.1,
+            nonce: pf.2,
+            table_version: pf.3,
+        }
+    }
+}
+
+impl Storable for types::PaymentToken {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode PaymentToken data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode PaymentToken data")
+    }
+}
+
+impl Storable for types::ICRC3Value {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode ICRC3Value data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode ICRC3Value data")
+    }
+}
+
+impl Storable for types::LuckyDrawTable {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode LuckyDrawTable data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode LuckyDrawTable data")
+    }
+}
+
+// ServerSeed format: (seed, created time, nonce)
+// The last seed is the active one, its commitment (SHA3-256 hash) is published
+// and the seed is revealed after it is rotated.
+// nonce: how many provably fair draws used the seed
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ServerSeed(pub ByteBuf, pub u64, pub u64);
+
+impl ServerSeed {
+    pub fn to_output(&self, epoch: u32, reveal: bool) -> types::LuckyDrawSeed {
+        types::LuckyDrawSeed {
+            epoch,
+            ts: self.1,
+            commitment: hex::encode(sha3_256(&self.0)),
+            seed: if reveal {
+                Some(hex::encode(&self.0))
+            } else {
+                None
+            },
+            nonce: self.2,
         }
     }
 }
 
+impl Storable for ServerSeed {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode ServerSeed data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode ServerSeed data")
+    }
+}
+
 impl Storable for LuckyDrawLog {
     const BOUND: Bound = Bound::Unbounded;
 
@@ -132,17 +397,88 @@ impl Storable for LuckyDrawLog {
     }
 }
 
-// Prize format: (Issuer code, Issue time, Expire, Claimable amount, Quantity)
+// LuckyDrawJournal format: (user, time, icp_amount, token_amount, random_number, stage, provably_fair, jackpot_amount, batch, payment, to)
+// It tracks a luckydraw between the ICP intake and the PANDA payout (or the ICP refund),
+// and is removed once the draw is settled.
+// jackpot_amount: the progressive jackpot tokens taken from the pool and included in token_amount
+// batch: the draws of a batch luckydraw, empty for single draws
+// payment: the payment in a token other than ICP, icp_amount is its ICP equivalent minus TRANS_FEE
+// to: the account to receive tokens, the user's default account if None
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LuckyDrawJournal(
+    pub Principal,
+    pub u64,
+    pub u64,
+    pub u64,
+    pub u64,
+    pub types::LuckyDrawStage,
+    #[serde(default)] pub Option<ProvablyFair>,
+    #[serde(default)] pub u64,
+    #[serde(default)] pub Vec<LuckyDrawBatchItem>,
+    #[serde(default)] pub Option<LuckyDrawPayment>,
+    #[serde(default)] pub Option<Account>,
+);
+
+// LuckyDrawBatchItem format: (random_number, token_amount, provably_fair)
+#[derive(Clone, Deserialize, Serialize)]
+pub struct LuckyDrawBatchItem(pub u64, pub u64, #[serde(default)] pub Option<ProvablyFair>);
+
+impl From<(u64, LuckyDrawJournal)> for types::LuckyDrawJournal {
+    fn from(entry: (u64, LuckyDrawJournal)) -> Self {
+        let (id, entry) = entry;
+        types::LuckyDrawJournal {
+            id: Nat::from(id),
+            ts: entry.1,
+            caller: entry.0,
+            icp_amount: Nat::from(entry.2),
+            amount: Nat::from(entry.3),
+            stage: entry.5,
+            payment: entry.9.map(|p| types::LuckyDrawPayment {
+                ledger: p.0,
+                amount: Nat::from(p.1),
+            }),
+        }
+    }
+}
+
+impl Storable for LuckyDrawJournal {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode LuckyDrawJournal data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode LuckyDrawJournal data")
+    }
+}
+
+// Prize format: (Issuer code, Issue time, Expire, Claimable amount, Quantity, Nonce)
 // Issuer code: The lucky code of the issuer, 0 for system
 // Issue time: The issue time of the prize, in minutes since UNIX epoch
 // Expire: The expire duration in minutes
 // Claimable amount: The amount of tokens that can be claimed by users, in PANDA * 1000
 // Quantity: How many users can claim the prize
+// Nonce: distinguishes the identical prizes issued in the same minute, 0 for the first one.
+// It is omitted from the encoding when 0, so the prizes issued before keep their keys.
 //
-// System can only issue prizes for free airdrop with Prize(0, Issue time, expire, 0, 0).
+// System can only issue prizes for free airdrop with Prize(0, Issue time, expire, 0, 0, 0).
 // This prizes will not be stored.
 #[derive(Clone, Deserialize, Serialize, Ord, PartialOrd, Eq, PartialEq)]
-pub struct Prize(pub u32, pub u32, pub u16, pub u32, pub u16);
+pub struct Prize(
+    pub u32,
+    pub u32,
+    pub u16,
+    pub u32,
+    pub u16,
+    #[serde(default, skip_serializing_if = "is_zero")] pub u32,
+);
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
 impl Storable for Prize {
     const BOUND: Bound = Bound::Unbounded;
 
@@ -158,6 +494,14 @@ impl Storable for Prize {
 }
 
 impl Prize {
+    // the tokens credited to each claimer.
+    pub fn claim_amount(&self) -> u64 {
+        if self.4 == 0 {
+            return 0;
+        }
+        self.3 as u64 * TOKEN_1 / self.4 as u64
+    }
+
     pub fn is_valid(&self, now_sec: u64) -> bool {
         (self.1 + self.2 as u32) >= (now_sec / 60) as u32
     }
@@ -165,148 +509,944 @@ impl Prize {
     pub fn is_valid_system(&self, now_sec: u64) -> bool {
         self.0 == 0 && self.3 == 0 && self.4 == 0 && self.is_valid(now_sec)
     }
+
+    // the stable id of a prize, the URL safe base64 of the big-endian encoded fields.
+    // The nonce is appended only when it is not 0.
+    pub fn id(&self) -> String {
+        let mut buf = Vec::with_capacity(20);
+        buf.extend_from_slice(&self.0.to_be_bytes());
+        buf.extend_from_slice(&self.1.to_be_bytes());
+        buf.extend_from_slice(&self.2.to_be_bytes());
+        buf.extend_from_slice(&self.3.to_be_bytes());
+        buf.extend_from_slice(&self.4.to_be_bytes());
+        if self.5 > 0 {
+            buf.extend_from_slice(&self.5.to_be_bytes());
+        }
+        general_purpose::URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    pub fn from_id(id: &str) -> Result<Self, String> {
+        let buf = general_purpose::URL_SAFE_NO_PAD
+            .decode(id.as_bytes())
+            .map_err(|_err| "invalid prize id".to_string())?;
+        let nonce = match buf.len() {
+            16 => 0,
+            20 => u32::from_be_bytes(buf[16..20].try_into().unwrap()),
+            _ => return Err("invalid prize id".to_string()),
+        };
+        Ok(Prize(
+            u32::from_be_bytes(buf[0..4].try_into().unwrap()),
+            u32::from_be_bytes(buf[4..8].try_into().unwrap()),
+            u16::from_be_bytes(buf[8..10].try_into().unwrap()),
+            u32::from_be_bytes(buf[10..14].try_into().unwrap()),
+            u16::from_be_bytes(buf[14..16].try_into().unwrap()),
+            nonce,
+        ))
+    }
+
+    // the key of the prize in the issuer's prizes.
+    pub fn issuer_key(&self) -> IssuerPrizeKey {
+        IssuerPrizeKey(self.1, self.2, self.3, self.4, self.5)
+    }
 }
 
-// IssuerPrize key: (Issue time, Expire, Claimable tokens, Quantity)
-// IssuerPrize value: filled quantity
+// PrizeCryptogram format:
+// V1: Prize, the prizes with the equal split
+// V2: (Prize, distribution), the prizes with the random or tiers distributions
 #[derive(Clone, Deserialize, Serialize)]
-pub struct IssuerPrizes(pub BTreeMap<(u32, u16, u32, u16), u16>);
-impl Storable for IssuerPrizes {
+#[serde(untagged)]
+pub enum PrizeCryptogram {
+    V1(Prize),
+    V2(Prize, types::PrizeDistribution),
+}
+
+impl PrizeCryptogram {
+    pub fn new(prize: Prize, distribution: types::PrizeDistribution) -> Self {
+        match distribution {
+            types::PrizeDistribution::Equal => PrizeCryptogram::V1(prize),
+            distribution => PrizeCryptogram::V2(prize, distribution),
+        }
+    }
+
+    pub fn prize(self) -> Prize {
+        match self {
+            PrizeCryptogram::V1(prize) => prize,
+            PrizeCryptogram::V2(prize, _) => prize,
+        }
+    }
+}
+
+// PrizeInfo format: (funded by the issuer, locked tokens in E8, claimed tokens in E8,
+// distribution, luckiest claimer with the claimed tokens in E8, depositor)
+// The unclaimed tokens of a prize funded by the issuer are refunded to the issuer's claimable
+// balance when it is closed, or to the depositor on the ledger if it is funded by a PANDA
+// deposit. Otherwise they are returned to the pool.
+// The prizes issued before PrizeInfo don't have it, they are funded by the pool.
+// The luckiest claimer is only tracked for the random and tiers distributions.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct PrizeInfo(
+    pub bool,
+    pub u64,
+    pub u64,
+    #[serde(default)] pub types::PrizeDistribution,
+    #[serde(default)] pub Option<(Principal, u64)>,
+    #[serde(default)] pub Option<Principal>,
+);
+
+impl PrizeInfo {
+    // the tokens credited to the nth (0-based) claimer of the prize.
+    pub fn share(&self, prize: &Prize, user: &Principal, nth: u16) -> u64 {
+        let remaining = self.1.saturating_sub(self.2);
+        match self.3 {
+            types::PrizeDistribution::Equal => prize.claim_amount().min(remaining),
+            types::PrizeDistribution::Random => {
+                let left = prize.4.saturating_sub(nth) as u64;
+                if left <= 1 {
+                    return remaining;
+                }
+                let mut data = vec![];
+                into_writer(&(prize, user, nth), &mut data).expect("failed to encode prize share");
+                let rnd = keys::with_secret(|s| mac_256(s, &data));
+                let rnd = u64::from_be_bytes(rnd[..8].try_into().unwrap());
+                // up to twice the average share, and leave at least 1 E8 to each of the others.
+                let max = (remaining / left * 2)
+                    .min(remaining.saturating_sub(left - 1))
+                    .max(1);
+                (1 + rnd % max).min(remaining)
+            }
+            types::PrizeDistribution::Tiers(ref tiers) => {
+                let total: u64 = tiers.iter().map(|(q, w)| *q as u64 * *w as u64).sum();
+                let mut filled = 0u16;
+                for (q, w) in tiers {
+                    filled = filled.saturating_add(*q);
+                    if nth < filled {
+                        return (self.1 * *w as u64 / total.max(1)).min(remaining);
+                    }
+                }
+                0
+            }
+        }
+    }
+}
+
+impl Storable for PrizeInfo {
     const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         let mut buf = vec![];
-        into_writer(&self, &mut buf).expect("failed to encode IssuerPrizes data");
+        into_writer(self, &mut buf).expect("failed to encode PrizeInfo data");
         Cow::Owned(buf)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        from_reader(&bytes[..]).expect("failed to decode IssuerPrizes data")
+        from_reader(&bytes[..]).expect("failed to decode PrizeInfo data")
     }
 }
 
+// PrizeRefund format: (depositor, refund tokens in E8, created_at in seconds)
+// The unclaimed budget of a deposit funded prize that is waiting to be transferred back,
+// created_at is kept for the retries to be deduplicated by the ledger.
 #[derive(Clone, Deserialize, Serialize)]
-pub struct Principals(BTreeSet<Principal>);
-impl Storable for Principals {
+pub struct PrizeRefund(pub Principal, pub u64, pub u64);
+
+impl Storable for PrizeRefund {
     const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         let mut buf = vec![];
-        into_writer(self, &mut buf).expect("failed to encode Principals data");
+        into_writer(self, &mut buf).expect("failed to encode PrizeRefund data");
         Cow::Owned(buf)
     }
 
     fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
-        from_reader(&bytes[..]).expect("failed to decode Principals data")
+        from_reader(&bytes[..]).expect("failed to decode PrizeRefund data")
     }
 }
 
-const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
-const AIRDROP_MEMORY_ID: MemoryId = MemoryId::new(1);
-const LUCKYCODE_MEMORY_ID: MemoryId = MemoryId::new(2);
-const AIRDROP_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
-const AIRDROP_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(4);
-const LUCKYDRAW_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(5);
-const LUCKYDRAW_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(6);
-const ISSUER_PRIZE_MEMORY_ID: MemoryId = MemoryId::new(7);
-const PRIZE_MEMORY_ID: MemoryId = MemoryId::new(8);
-const KEYS_MEMORY_ID: MemoryId = MemoryId::new(9);
-const X_AUTH_MEMORY_ID: MemoryId = MemoryId::new(10);
-
-thread_local! {
-    static CAPTCHA_SECRET: RefCell<[u8; 32]> = const { RefCell::new([0; 32]) };
-    static CHALLENGE_PUB_KEY: RefCell<[u8; 32]> = const { RefCell::new([0; 32]) };
+// PrizeConditions format: (min balance in E8, allowed principals, allowed lucky codes,
+// verified XAuth identity required, first-time users only)
+// The claimer should be in one of the allowlists when any of them is not empty.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PrizeConditions(
+    pub u64,
+    pub BTreeSet<Principal>,
+    pub BTreeSet<u32>,
+    pub bool,
+    pub bool,
+);
 
-    static STATE_HEAP: RefCell<State> = RefCell::new(State::default());
+// The prizes without conditions require 10 tokens to claim.
+impl Default for PrizeConditions {
+    fn default() -> Self {
+        PrizeConditions(10 * TOKEN_1, BTreeSet::new(), BTreeSet::new(), false, false)
+    }
+}
 
-    static ACTIVE_USERS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+impl From<types::PrizeConditions> for PrizeConditions {
+    fn from(c: types::PrizeConditions) -> Self {
+        PrizeConditions(
+            c.min_balance.map_or(10 * TOKEN_1, |b| b as u64 * TOKEN_1),
+            c.principals.unwrap_or_default(),
+            c.lucky_codes
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|code| luckycode_from_string(code).ok())
+                .collect(),
+            c.xauth,
+            c.new_user,
+        )
+    }
+}
 
-    static MANAGERS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+impl PrizeConditions {
+    // check the conditions except the min balance, which needs the ledger balance.
+    pub fn check(&self, user: &Principal, code: u32) -> Result<(), String> {
+        if (!self.1.is_empty() || !self.2.is_empty())
+            && !self.1.contains(user)
+            && !self.2.contains(&code)
+        {
+            return Err("you are not in the allowlist of the prize".to_string());
+        }
+        if self.3 && !xauth::is_verified(user) {
+            return Err("the prize requires a verified XAuth identity".to_string());
+        }
+        if self.4 {
+            if prize::claimers_backfill_next().is_some() {
+                return Err("the prize is not open yet, try again later".to_string());
+            }
+            if prize::has_claimed(user) {
+                return Err("the prize is only for first-time users".to_string());
+            }
+        }
+        Ok(())
+    }
+}
 
-    static NOTIFICATIONS: RefCell<BTreeMap<u8, types::Notification>> = const { RefCell::new(BTreeMap::new()) };
+impl Storable for PrizeConditions {
+    const BOUND: Bound = Bound::Unbounded;
 
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode PrizeConditions data");
+        Cow::Owned(buf)
+    }
 
-    static STATE: RefCell<StableCell<State, Memory>> = RefCell::new(
-        StableCell::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
-            State::default()
-        ).expect("failed to init STATE store")
-    );
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode PrizeConditions data")
+    }
+}
 
-    static AIRDROP: RefCell<StableBTreeMap<Principal, AirdropState, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(AIRDROP_MEMORY_ID)),
-        )
-    );
+// IssuerPrizeKey format: (Issue time, Expire, Claimable tokens, Quantity, Nonce)
+// The nonce is omitted from the encoding when 0 like in Prize.
+#[derive(Clone, Debug, Deserialize, Serialize, Ord, PartialOrd, Eq, PartialEq)]
+pub struct IssuerPrizeKey(
+    pub u32,
+    pub u16,
+    pub u32,
+    pub u16,
+    #[serde(default, skip_serializing_if = "is_zero")] pub u32,
+);
 
-    static LUCKYCODE: RefCell<StableBTreeMap<u32, Principal, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYCODE_MEMORY_ID)),
-        )
-    );
+// IssuerPrize key: IssuerPrizeKey
+// IssuerPrize value: filled quantity
+// The closed prizes are removed from PRIZE, they are kept in the second map with
+// (cancelled, refunded tokens in E8). The expired prizes without refund are not recorded.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(from = "IssuerPrizesFormat")]
+pub struct IssuerPrizes(
+    pub BTreeMap<IssuerPrizeKey, u16>,
+    pub BTreeMap<IssuerPrizeKey, (bool, u64)>,
+);
 
-    static AIRDROP_LOGS: RefCell<StableLog<AirdropLog, Memory, Memory>> = RefCell::new(
-        StableLog::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(AIRDROP_LOG_INDEX_MEMORY_ID)),
-            MEMORY_MANAGER.with_borrow(|m| m.get(AIRDROP_LOG_DATA_MEMORY_ID)),
-        ).expect("failed to init AIRDROP_LOGS store")
-    );
+// IssuerPrizes was a map of the filled quantity before the closed prizes were introduced.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum IssuerPrizesFormat {
+    V1(BTreeMap<IssuerPrizeKey, u16>),
+    V2(
+        BTreeMap<IssuerPrizeKey, u16>,
+        BTreeMap<IssuerPrizeKey, (bool, u64)>,
+    ),
+}
 
-    static LUCKYDRAW_LOGS: RefCell<StableLog<LuckyDrawLog, Memory, Memory>> = RefCell::new(
-        StableLog::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYDRAW_LOG_INDEX_MEMORY_ID)),
-            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYDRAW_LOG_DATA_MEMORY_ID)),
-        ).expect("failed to init LUCKY_DRAW_LOGS store")
-    );
+impl From<IssuerPrizesFormat> for IssuerPrizes {
+    fn from(f: IssuerPrizesFormat) -> Self {
+        match f {
+            IssuerPrizesFormat::V1(filled) => IssuerPrizes(filled, BTreeMap::new()),
+            IssuerPrizesFormat::V2(filled, closed) => IssuerPrizes(filled, closed),
+        }
+    }
+}
 
-    static ISSUER_PRIZE: RefCell<StableBTreeMap<u32, IssuerPrizes, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(ISSUER_PRIZE_MEMORY_ID)),
-        )
-    );
+impl IssuerPrizes {
+    // the prizes of the issuer in the output format of prizes_of.
+    pub fn to_output(&self, code: u32) -> Vec<types::PrizeOutput> {
+        self.0
+            .iter()
+            .map(|(p, filled)| {
+                let prize = Prize(code, p.0, p.1, p.2, p.3, p.4);
+                let luckiest = if p.2 > 0 {
+                    PRIZE_INFO
+                        .with(|r| r.borrow().get(&prize))
+                        .and_then(|info| info.4)
+                } else {
+                    None
+                };
+                (code, p.0, p.1, p.2, p.3, *filled, prize.id(), luckiest)
+            })
+            .collect()
+    }
+}
 
-    static PRIZE: RefCell<StableBTreeMap<Prize, Principals, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(PRIZE_MEMORY_ID)),
-        )
-    );
+impl Storable for IssuerPrizes {
+    const BOUND: Bound = Bound::Unbounded;
 
-    static KEYS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(KEYS_MEMORY_ID)),
-        )
-    );
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode IssuerPrizes data");
+        Cow::Owned(buf)
+    }
 
-    static X_AUTH: RefCell<StableBTreeMap<String, (Principal, u64), Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(X_AUTH_MEMORY_ID)),
-        )
-    );
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode IssuerPrizes data")
+    }
 }
 
-pub mod keys {
-    use super::*;
+// PrizeClaims format: claimer -> (claimed tokens in E8, claimed at in seconds)
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(from = "PrizeClaimsFormat")]
+pub struct PrizeClaims(pub BTreeMap<Principal, (u64, u64)>);
 
-    pub async fn load() {
-        let keys = KEYS.with(|r| r.borrow().iter().collect::<BTreeMap<String, Vec<u8>>>());
-        {
-            let mut secret: Vec<u8> = match keys.get("CAPTCHA_SECRET") {
-                Some(secret) => secret.clone(),
-                None => vec![],
-            };
-            if secret.len() != 32 {
-                let rr = ic_cdk::api::management_canister::main::raw_rand()
-                    .await
-                    .expect("failed to get random bytes");
-                secret = mac_256(&rr.0, b"CAPTCHA_SECRET").to_vec();
+// PrizeClaims was a set of the claimers before the claimed tokens were recorded,
+// and a map of the claimed tokens before the claim time was recorded. They are 0 if unknown.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PrizeClaimsFormat {
+    V1(BTreeSet<Principal>),
+    V2(BTreeMap<Principal, u64>),
+    V3(BTreeMap<Principal, (u64, u64)>),
+}
+
+impl From<PrizeClaimsFormat> for PrizeClaims {
+    fn from(f: PrizeClaimsFormat) -> Self {
+        match f {
+            PrizeClaimsFormat::V1(users) => {
+                PrizeClaims(users.into_iter().map(|u| (u, (0, 0))).collect())
             }
-            CAPTCHA_SECRET.with(|r| r.borrow_mut().copy_from_slice(&secret));
+            PrizeClaimsFormat::V2(claims) => {
+                PrizeClaims(claims.into_iter().map(|(u, a)| (u, (a, 0))).collect())
+            }
+            PrizeClaimsFormat::V3(claims) => PrizeClaims(claims),
         }
-        {
-            let key: Vec<u8> = match keys.get("CHALLENGE_PUB_KEY") {
-                Some(secret) => secret.clone(),
+    }
+}
+
+impl PrizeClaims {
+    // the claims of a prize, the claimers and amounts are only for the issuer.
+    pub fn to_output(
+        &self,
+        prize: &Prize,
+        info: Option<&PrizeInfo>,
+        closed: bool,
+        detail: bool,
+    ) -> types::PrizeClaimsOutput {
+        let claimed = match info {
+            Some(info) => info.2,
+            None => prize.claim_amount() * self.0.len() as u64,
+        };
+        let claims = if detail {
+            let mut claims: Vec<types::PrizeClaim> = self
+                .0
+                .iter()
+                .map(|(user, (amount, ts))| types::PrizeClaim {
+                    user: *user,
+                    // the claims before the amounts were recorded are split equally
+                    amount: Nat::from(if *amount > 0 {
+                        *amount
+                    } else {
+                        prize.claim_amount()
+                    }),
+                    claimed_at: if *ts > 0 { Some(*ts) } else { None },
+                })
+                .collect();
+            claims.sort_by_key(|c| c.claimed_at);
+            Some(claims)
+        } else {
+            None
+        };
+        types::PrizeClaimsOutput {
+            id: prize.id(),
+            issuer: luckycode_to_string(prize.0),
+            issued_at: prize.1 as u64 * 60,
+            expire: prize.2,
+            claimable: prize.3,
+            quantity: prize.4,
+            filled: self.0.len() as u16,
+            claimed: Nat::from(claimed),
+            closed,
+            claims,
+        }
+    }
+}
+
+impl Storable for PrizeClaims {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode PrizeClaims data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode PrizeClaims data")
+    }
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Notifications(Vec<types::Notification>);
+
+impl Storable for Notifications {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Notifications data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Notifications data")
+    }
+}
+
+// BanState format: (former lucky code, reason, moderator, banned at in seconds, expire at in seconds or 0 for never)
+#[derive(Clone, Deserialize, Serialize)]
+pub struct BanState(pub u32, pub String, pub Principal, pub u64, pub u64);
+
+impl BanState {
+    pub fn into_output(self, user: Principal) -> types::BannedUser {
+        types::BannedUser {
+            user,
+            lucky_code: luckycode_to_string(self.0),
+            reason: self.1,
+            moderator: self.2,
+            banned_at: self.3,
+            expire_at: if self.4 > 0 { Some(self.4) } else { None },
+        }
+    }
+}
+
+impl Storable for BanState {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode BanState data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode BanState data")
+    }
+}
+
+// AuditLog format: (time in seconds, caller, method, arguments digest, previous value, previous hash)
+// arguments digest: the SHA3-256 digest of the CBOR encoded arguments
+// previous value: the CBOR encoded value replaced by the call, if any
+// previous hash: the SHA3-256 hash of the CBOR encoded previous audit log, zeros for the first log
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AuditLog(
+    u64,
+    Principal,
+    String,
+    #[serde(with = "serde_bytes")] [u8; 32],
+    Option<ByteBuf>,
+    #[serde(with = "serde_bytes")] [u8; 32],
+);
+
+impl AuditLog {
+    pub fn hash(&self) -> [u8; 32] {
+        sha3_256(&lib_panda::to_cbor_bytes(self))
+    }
+
+    pub fn into_output(self, id: u64) -> types::AuditLog {
+        types::AuditLog {
+            id,
+            hash: ByteBuf::from(self.hash()),
+            ts: self.0,
+            caller: self.1,
+            method: self.2,
+            args_digest: ByteBuf::from(self.3),
+            prev_value: self.4,
+            phash: ByteBuf::from(self.5),
+        }
+    }
+}
+
+impl Storable for AuditLog {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode AuditLog data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode AuditLog data")
+    }
+}
+
+const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const AIRDROP_MEMORY_ID: MemoryId = MemoryId::new(1);
+const LUCKYCODE_MEMORY_ID: MemoryId = MemoryId::new(2);
+const AIRDROP_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const AIRDROP_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(4);
+const LUCKYDRAW_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(5);
+const LUCKYDRAW_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(6);
+const ISSUER_PRIZE_MEMORY_ID: MemoryId = MemoryId::new(7);
+const PRIZE_MEMORY_ID: MemoryId = MemoryId::new(8);
+const KEYS_MEMORY_ID: MemoryId = MemoryId::new(9);
+const X_AUTH_MEMORY_ID: MemoryId = MemoryId::new(10);
+const LUCKYDRAW_JOURNAL_MEMORY_ID: MemoryId = MemoryId::new(11);
+const SERVER_SEED_MEMORY_ID: MemoryId = MemoryId::new(12);
+const LUCKYDRAW_TABLE_MEMORY_ID: MemoryId = MemoryId::new(13);
+const PAYMENT_TOKEN_MEMORY_ID: MemoryId = MemoryId::new(14);
+const AIRDROP_LOG_USER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(15);
+const LUCKYDRAW_LOG_USER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(16);
+const BLOCK_INDEX_MEMORY_ID: MemoryId = MemoryId::new(17);
+const BLOCK_DATA_MEMORY_ID: MemoryId = MemoryId::new(18);
+const ARCHIVE_WASM_MEMORY_ID: MemoryId = MemoryId::new(19);
+const NOTIFICATIONS_MEMORY_ID: MemoryId = MemoryId::new(20);
+const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(21);
+const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(22);
+const BAN_MEMORY_ID: MemoryId = MemoryId::new(23);
+const PRIZE_INFO_MEMORY_ID: MemoryId = MemoryId::new(24);
+const PRIZE_CONDITIONS_MEMORY_ID: MemoryId = MemoryId::new(25);
+const X_AUTH_USERS_MEMORY_ID: MemoryId = MemoryId::new(26);
+const PRIZE_CLAIMERS_MEMORY_ID: MemoryId = MemoryId::new(27);
+const CLOSED_PRIZE_MEMORY_ID: MemoryId = MemoryId::new(28);
+const HARVEST_KEYS_MEMORY_ID: MemoryId = MemoryId::new(29);
+const PRIZE_REFUNDS_MEMORY_ID: MemoryId = MemoryId::new(30);
+const AIRDROP_LOG_SPARE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(31);
+const AIRDROP_LOG_SPARE_DATA_MEMORY_ID: MemoryId = MemoryId::new(32);
+const LUCKYDRAW_LOG_SPARE_INDEX_MEMORY_ID: MemoryId = MemoryId::new(33);
+const LUCKYDRAW_LOG_SPARE_DATA_MEMORY_ID: MemoryId = MemoryId::new(34);
+
+// the (index, data) memories of the two slots of a stable log, the active log is in one slot
+// and it is rebuilt without the archived logs in the other one.
+type LogSlots = [(MemoryId, MemoryId); 2];
+
+const AIRDROP_LOG_SLOTS: LogSlots = [
+    (AIRDROP_LOG_INDEX_MEMORY_ID, AIRDROP_LOG_DATA_MEMORY_ID),
+    (
+        AIRDROP_LOG_SPARE_INDEX_MEMORY_ID,
+        AIRDROP_LOG_SPARE_DATA_MEMORY_ID,
+    ),
+];
+const LUCKYDRAW_LOG_SLOTS: LogSlots = [
+    (LUCKYDRAW_LOG_INDEX_MEMORY_ID, LUCKYDRAW_LOG_DATA_MEMORY_ID),
+    (
+        LUCKYDRAW_LOG_SPARE_INDEX_MEMORY_ID,
+        LUCKYDRAW_LOG_SPARE_DATA_MEMORY_ID,
+    ),
+];
+
+thread_local! {
+    static CAPTCHA_SECRET: RefCell<[u8; 32]> = const { RefCell::new([0; 32]) };
+    static CHALLENGE_PUB_KEY: RefCell<[u8; 32]> = const { RefCell::new([0; 32]) };
+
+    static STATE_HEAP: RefCell<State> = RefCell::new(State::default());
+
+    static ACTIVE_USERS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    // the number of harvests started and finished, the harvests in flight are the difference
+    static HARVESTS: RefCell<(u64, u64)> = const { RefCell::new((0, 0)) };
+    // the last closed prize scanned by prize::prune_closed
+    static CLOSED_PRIZE_CURSOR: RefCell<Option<Prize>> = const { RefCell::new(None) };
+
+    static MANAGERS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE: RefCell<StableCell<State, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
+            State::default()
+        ).expect("failed to init STATE store")
+    );
+
+    static AIRDROP: RefCell<StableBTreeMap<Principal, AirdropState, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AIRDROP_MEMORY_ID)),
+        )
+    );
+
+    static LUCKYCODE: RefCell<StableBTreeMap<u32, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYCODE_MEMORY_ID)),
+        )
+    );
+
+    // the slots are read from the saved state, it is saved whenever a slot is switched
+    static AIRDROP_LOGS: RefCell<StableLog<AirdropLog, Memory, Memory>> = RefCell::new(
+        open_log(&AIRDROP_LOG_SLOTS, STATE.with(|r| r.borrow().get().airdrop_logs_slot.unwrap_or(0)))
+    );
+
+    // the airdrop log being rebuilt from the first local log
+    static AIRDROP_LOGS_SPARE: RefCell<StableLog<AirdropLog, Memory, Memory>> = RefCell::new(
+        open_log(&AIRDROP_LOG_SLOTS, 1 - STATE.with(|r| r.borrow().get().airdrop_logs_slot.unwrap_or(0)))
+    );
+
+    static LUCKYDRAW_LOGS: RefCell<StableLog<LuckyDrawLog, Memory, Memory>> = RefCell::new(
+        open_log(&LUCKYDRAW_LOG_SLOTS, STATE.with(|r| r.borrow().get().luckydraw_logs_slot.unwrap_or(0)))
+    );
+
+    // the luckydraw log being rebuilt from the first local log
+    static LUCKYDRAW_LOGS_SPARE: RefCell<StableLog<LuckyDrawLog, Memory, Memory>> = RefCell::new(
+        open_log(&LUCKYDRAW_LOG_SLOTS, 1 - STATE.with(|r| r.borrow().get().luckydraw_logs_slot.unwrap_or(0)))
+    );
+
+    static ISSUER_PRIZE: RefCell<StableBTreeMap<u32, IssuerPrizes, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ISSUER_PRIZE_MEMORY_ID)),
+        )
+    );
+
+    static PRIZE: RefCell<StableBTreeMap<Prize, PrizeClaims, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PRIZE_MEMORY_ID)),
+        )
+    );
+
+    static KEYS: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(KEYS_MEMORY_ID)),
+        )
+    );
+
+    static X_AUTH: RefCell<StableBTreeMap<String, (Principal, u64), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(X_AUTH_MEMORY_ID)),
+        )
+    );
+
+    static LUCKYDRAW_JOURNAL: RefCell<StableBTreeMap<u64, LuckyDrawJournal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYDRAW_JOURNAL_MEMORY_ID)),
+        )
+    );
+
+    static SERVER_SEED: RefCell<StableBTreeMap<u32, ServerSeed, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(SERVER_SEED_MEMORY_ID)),
+        )
+    );
+
+    static LUCKYDRAW_TABLE: RefCell<StableBTreeMap<u32, types::LuckyDrawTable, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYDRAW_TABLE_MEMORY_ID)),
+        )
+    );
+
+    static PAYMENT_TOKEN: RefCell<StableBTreeMap<Principal, types::PaymentToken, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PAYMENT_TOKEN_MEMORY_ID)),
+        )
+    );
+
+    // (user, !log_id) -> (), the log ids are inverted so that newer logs come first.
+    static AIRDROP_LOG_USER_INDEX: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AIRDROP_LOG_USER_INDEX_MEMORY_ID)),
+        )
+    );
+
+    static LUCKYDRAW_LOG_USER_INDEX: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYDRAW_LOG_USER_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // ICRC-3 blocks of the balance changes, chained by the hash of the previous block.
+    static BLOCKS: RefCell<StableLog<types::ICRC3Value, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCK_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCK_DATA_MEMORY_ID)),
+        ).expect("failed to init BLOCKS store")
+    );
+
+    static NOTIFICATIONS_STORE: RefCell<StableCell<Notifications, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(NOTIFICATIONS_MEMORY_ID)),
+            Notifications::default()
+        ).expect("failed to init NOTIFICATIONS_STORE store")
+    );
+
+    static CERTIFIED_TREE: RefCell<certified::CertifiedTree> = RefCell::new(certified::CertifiedTree::default());
+
+    static ARCHIVE_WASM: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ARCHIVE_WASM_MEMORY_ID)),
+            Vec::new()
+        ).expect("failed to init ARCHIVE_WASM store")
+    );
+
+    // audit logs of the privileged calls, chained by the hash of the previous log.
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_DATA_MEMORY_ID)),
+        ).expect("failed to init AUDIT_LOGS store")
+    );
+
+    static BANS: RefCell<StableBTreeMap<Principal, BanState, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(BAN_MEMORY_ID)),
+        )
+    );
+
+    static PRIZE_INFO: RefCell<StableBTreeMap<Prize, PrizeInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PRIZE_INFO_MEMORY_ID)),
+        )
+    );
+
+    static PRIZE_CONDITIONS: RefCell<StableBTreeMap<Prize, PrizeConditions, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PRIZE_CONDITIONS_MEMORY_ID)),
+        )
+    );
+
+    // user -> XAuth user id, the reverse index of X_AUTH
+    static X_AUTH_USERS: RefCell<StableBTreeMap<Principal, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(X_AUTH_USERS_MEMORY_ID)),
+        )
+    );
+
+    // user -> the time of the first claimed prize, in seconds
+    static PRIZE_CLAIMERS: RefCell<StableBTreeMap<Principal, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PRIZE_CLAIMERS_MEMORY_ID)),
+        )
+    );
+
+    // the claims of the cancelled or expired prizes, they are removed from PRIZE
+    static CLOSED_PRIZE: RefCell<StableBTreeMap<Prize, PrizeClaims, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(CLOSED_PRIZE_MEMORY_ID)),
+        )
+    );
+
+    // (created_at in nanoseconds, user) -> the harvested amount, the idempotency keys of harvests
+    static HARVEST_KEYS: RefCell<StableBTreeMap<(u64, Principal), u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(HARVEST_KEYS_MEMORY_ID)),
+        )
+    );
+
+    // operation id -> the pending refund of a deposit funded prize
+    static PRIZE_REFUNDS: RefCell<StableBTreeMap<u64, PrizeRefund, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PRIZE_REFUNDS_MEMORY_ID)),
+        )
+    );
+}
+
+// get the log ids of a user in reverse order from a per-user index, starting before `prev`.
+fn user_log_ids(
+    index: &StableBTreeMap<(Principal, u64), (), Memory>,
+    user: Principal,
+    prev: Option<u64>,
+    take: usize,
+) -> Vec<u64> {
+    let start = match prev {
+        None => 0,
+        Some(0) => return vec![],
+        Some(prev) => !(prev - 1),
+    };
+    index
+        .range((user, start)..=(user, u64::MAX))
+        .take(take)
+        .map(|((_, id), _)| !id)
+        .collect()
+}
+
+// get the logs in reverse order with their ids, starting before `prev`.
+// The stable log starts from `base`, the logs before `first_id` have been archived.
+fn logs_before<T: Storable>(
+    logs: &StableLog<T, Memory, Memory>,
+    base: u64,
+    first_id: u64,
+    prev: Option<u64>,
+    take: usize,
+) -> Vec<(u64, T)> {
+    let latest = base + logs.len();
+    let prev = prev.unwrap_or(latest);
+    if prev > latest {
+        return vec![];
+    }
+    (first_id..prev)
+        .rev()
+        .take(take)
+        .filter_map(|id| logs.get(id - base).map(|log| (id, log)))
+        .collect()
+}
+
+// open the stable log in a slot.
+fn open_log<T: Storable>(slots: &LogSlots, slot: u8) -> StableLog<T, Memory, Memory> {
+    let (index, data) = slots[slot as usize % 2];
+    StableLog::init(
+        MEMORY_MANAGER.with_borrow(|m| m.get(index)),
+        MEMORY_MANAGER.with_borrow(|m| m.get(data)),
+    )
+    .expect("failed to init log store")
+}
+
+// create an empty stable log in a slot, the memories of the former log are written over.
+fn reset_log<T: Storable>(slots: &LogSlots, slot: u8) -> StableLog<T, Memory, Memory> {
+    let (index, data) = slots[slot as usize % 2];
+    StableLog::new(
+        MEMORY_MANAGER.with_borrow(|m| m.get(index)),
+        MEMORY_MANAGER.with_borrow(|m| m.get(data)),
+    )
+}
+
+// copy at most `take` logs that follow the copied ones from the active log starting at `base`
+// to the spare log starting at `first_id`.
+// Return true when the spare log has caught up with the active log.
+fn copy_logs<T: Storable>(
+    active: &StableLog<T, Memory, Memory>,
+    spare: &StableLog<T, Memory, Memory>,
+    base: u64,
+    first_id: u64,
+    take: u64,
+) -> Result<bool, String> {
+    let next = base + active.len();
+    let start = first_id + spare.len();
+    let end = next.min(start + take);
+    for id in start..end {
+        let log = active
+            .get(id - base)
+            .ok_or_else(|| format!("log {} not found", id))?;
+        spare
+            .append(&log)
+            .map_err(|err| format!("failed to copy log {}, error {:?}", id, err))?;
+    }
+    Ok(end == next)
+}
+
+// remove the per-user index entries of the logs in [start, end) from a log starting at `base`.
+// The entries are removed in bounded chunks before the stable log is rebuilt without the
+// archived logs, the archive canister keeps its own per-user index.
+fn prune_log_index<T: Storable>(
+    logs: &StableLog<T, Memory, Memory>,
+    index: &mut StableBTreeMap<(Principal, u64), (), Memory>,
+    base: u64,
+    start: u64,
+    end: u64,
+    user_of: impl Fn(&T) -> Principal,
+) {
+    for id in start..end {
+        if let Some(log) = id.checked_sub(base).and_then(|i| logs.get(i)) {
+            index.remove(&(user_of(&log), !id));
+        }
+    }
+}
+
+pub mod log_index {
+    use super::*;
+
+    // index the logs appended before the per-user indexes were introduced,
+    // return true if all logs have been indexed.
+    pub fn backfill(take: u64) -> bool {
+        let (airdrop_indexed, luckydraw_indexed) = state::with(|r| {
+            (
+                r.airdrop_logs_indexed.unwrap_or_default(),
+                r.luckydraw_logs_indexed.unwrap_or_default(),
+            )
+        });
+        let (airdrop_indexed, airdrop_done) = AIRDROP_LOGS.with(|r| {
+            AIRDROP_LOG_USER_INDEX.with(|m| {
+                backfill_index(
+                    &r.borrow(),
+                    &mut m.borrow_mut(),
+                    airdrop::log_base(),
+                    airdrop::first_log_id(),
+                    airdrop_indexed,
+                    take,
+                    |log| log.0,
+                )
+            })
+        });
+        let (luckydraw_indexed, luckydraw_done) = LUCKYDRAW_LOGS.with(|r| {
+            LUCKYDRAW_LOG_USER_INDEX.with(|m| {
+                backfill_index(
+                    &r.borrow(),
+                    &mut m.borrow_mut(),
+                    luckydraw::log_base(),
+                    luckydraw::first_log_id(),
+                    luckydraw_indexed,
+                    take,
+                    |log| log.0,
+                )
+            })
+        });
+        state::with_mut(|r| {
+            r.airdrop_logs_indexed = Some(airdrop_indexed);
+            r.luckydraw_logs_indexed = Some(luckydraw_indexed);
+        });
+        airdrop_done && luckydraw_done
+    }
+
+    // index the logs from `indexed` of a log starting at `base`,
+    // the archived logs before `first_id` are skipped.
+    // return the next log id to index and whether all logs have been indexed.
+    fn backfill_index<T: Storable>(
+        logs: &StableLog<T, Memory, Memory>,
+        index: &mut StableBTreeMap<(Principal, u64), (), Memory>,
+        base: u64,
+        first_id: u64,
+        indexed: u64,
+        take: u64,
+        user_of: impl Fn(&T) -> Principal,
+    ) -> (u64, bool) {
+        let total = base + logs.len();
+        let start = indexed.max(first_id);
+        let end = total.min(start + take);
+        for id in start..end {
+            if let Some(log) = logs.get(id - base) {
+                index.insert((user_of(&log), !id), ());
+            }
+        }
+        (end, end == total)
+    }
+}
+
+pub mod keys {
+    use super::*;
+
+    pub async fn load() {
+        let keys = KEYS.with(|r| r.borrow().iter().collect::<BTreeMap<String, Vec<u8>>>());
+        {
+            let mut secret: Vec<u8> = match keys.get("CAPTCHA_SECRET") {
+                Some(secret) => secret.clone(),
+                None => vec![],
+            };
+            if secret.len() != 32 {
+                let rr = ic_cdk::api::management_canister::main::raw_rand()
+                    .await
+                    .expect("failed to get random bytes");
+                secret = mac_256(&rr.0, b"CAPTCHA_SECRET").to_vec();
+            }
+            CAPTCHA_SECRET.with(|r| r.borrow_mut().copy_from_slice(&secret));
+        }
+        {
+            let key: Vec<u8> = match keys.get("CHALLENGE_PUB_KEY") {
+                Some(secret) => secret.clone(),
                 None => vec![],
             };
             if key.len() == 32 {
@@ -315,298 +1455,1371 @@ pub mod keys {
         }
     }
 
-    pub fn save() {
-        KEYS.with(|r| {
-            r.borrow_mut().insert(
-                "CAPTCHA_SECRET".to_string(),
-                CAPTCHA_SECRET.with(|r| r.borrow().to_vec()),
-            );
-            r.borrow_mut().insert(
-                "CHALLENGE_PUB_KEY".to_string(),
-                CHALLENGE_PUB_KEY.with(|r| r.borrow().to_vec()),
-            );
+    pub fn save() {
+        let secret = CAPTCHA_SECRET.with(|r| *r.borrow());
+        KEYS.with(|r| {
+            // the secret has not been loaded yet
+            if secret != [0u8; 32] {
+                r.borrow_mut()
+                    .insert("CAPTCHA_SECRET".to_string(), secret.to_vec());
+            }
+            r.borrow_mut().insert(
+                "CHALLENGE_PUB_KEY".to_string(),
+                CHALLENGE_PUB_KEY.with(|r| r.borrow().to_vec()),
+            );
+        });
+    }
+
+    pub fn with_secret<R>(f: impl FnOnce(&[u8; 32]) -> R) -> R {
+        CAPTCHA_SECRET.with(|r| f(&r.borrow()))
+    }
+
+    pub fn with_challenge_pub_key<R>(f: impl FnOnce(&[u8; 32]) -> R) -> R {
+        CHALLENGE_PUB_KEY.with(|r| f(&r.borrow()))
+    }
+
+    pub fn set_challenge_pub_key(key: [u8; 32]) {
+        CHALLENGE_PUB_KEY.with(|r| *r.borrow_mut() = key);
+    }
+
+    pub static AIRDROP_KEY: Lazy<[u8; 32]> =
+        Lazy::new(|| with_secret(|b| mac_256(b, b"AIRDROP_KEY")));
+    pub static PRIZE_KEY: Lazy<[u8; 32]> = Lazy::new(|| with_secret(|b| mac_256(b, b"PRIZE_KEY")));
+}
+
+pub mod luckycode {
+    use super::*;
+
+    // pub fn get(code: u32) -> Option<Principal> {
+    //     LUCKYCODE.with(|r| r.borrow().get(&code))
+    // }
+
+    pub fn get_by_string(code: &str) -> Option<Principal> {
+        match luckycode_from_string(code) {
+            Ok(code) => LUCKYCODE.with(|r| r.borrow().get(&code)),
+            Err(_) => None,
+        }
+    }
+
+    pub fn new_from(user: Principal) -> u32 {
+        LUCKYCODE.with(|r| {
+            let mut m = r.borrow_mut();
+            let mut code = (m.len() as u32).saturating_add(1000000);
+            let mut i = 0u32;
+            while m.contains_key(&code) {
+                code = code.saturating_add(1);
+                i += 1;
+                if i > 100000 {
+                    ic_cdk::trap("failed to generate a lucky code");
+                }
+            }
+            m.insert(code, user);
+            code
+        })
+    }
+}
+
+pub mod xauth {
+    use super::*;
+
+    // pub fn get(id: &String) -> Option<(Principal, u64)> {
+    //     X_AUTH.with(|r| r.borrow().get(id))
+    // }
+
+    // pub fn exists(id: &String) -> bool {
+    //     X_AUTH.with(|r| r.borrow().contains_key(id))
+    // }
+
+    pub fn try_set(id: String, user: Principal, now_sec: u64) -> bool {
+        X_AUTH.with(|r| {
+            let mut m = r.borrow_mut();
+            if m.contains_key(&id) {
+                return false;
+            }
+
+            m.insert(id.clone(), (user, now_sec));
+            X_AUTH_USERS.with(|r| r.borrow_mut().insert(user, id));
+            true
+        })
+    }
+
+    pub fn is_verified(user: &Principal) -> bool {
+        X_AUTH_USERS.with(|r| r.borrow().contains_key(user))
+    }
+
+    // build the reverse index for the XAuth identities verified before it,
+    // it should be called after upgrading.
+    pub fn init_users() {
+        let indexed = X_AUTH_USERS.with(|r| r.borrow().len());
+        if indexed >= X_AUTH.with(|r| r.borrow().len()) {
+            return;
+        }
+        X_AUTH.with(|r| {
+            X_AUTH_USERS.with(|m| {
+                let mut m = m.borrow_mut();
+                for (id, (user, _)) in r.borrow().iter() {
+                    m.insert(user, id);
+                }
+            })
+        });
+    }
+}
+
+pub mod airdrop {
+    use crate::TOKEN_1;
+
+    use super::*;
+
+    // check if a user has claimed airdrop.
+    pub fn state_of(user: &Principal) -> Option<AirdropState> {
+        AIRDROP.with(|r| r.borrow().get(user))
+    }
+
+    // update luckydraw state and append a log.
+    // return the log or an error message when append failed.
+    pub fn insert(
+        user: Principal,
+        referrer: Option<Principal>,
+        now_sec: u64,
+        amount: u64,
+        rebate_bonus: u64,
+        caller_code: u32,
+    ) -> Result<types::AirdropLog, String> {
+        let referrer_code = AIRDROP.with(|r| {
+            let mut m = r.borrow_mut();
+            let state = AirdropState(caller_code, 0, amount);
+            m.insert(user, state.clone());
+            certified::set_airdrop(&user, &state);
+
+            match referrer {
+                None => 0,
+                Some(referrer) => match m.get(&referrer) {
+                    // banned referrers don't get the referral bonus
+                    None | Some(AirdropState(0, _, _)) => 0,
+                    Some(state) => {
+                        let state = AirdropState(state.0, state.1, state.2 + rebate_bonus);
+                        m.insert(referrer, state.clone());
+                        certified::set_airdrop(&referrer, &state);
+                        state.0
+                    }
+                },
+            }
+        });
+        state::credit_claimable(if referrer_code > 0 {
+            amount + rebate_bonus
+        } else {
+            amount
+        });
+
+        let log = AirdropLog::new(
+            user,
+            now_sec,
+            types::AirdropLogKind::Claim,
+            amount,
+            0,
+            referrer_code,
+        );
+        let idx = append_log(&log)?;
+        if let (Some(referrer), true) = (referrer, referrer_code > 0) {
+            append_log(&AirdropLog::new(
+                referrer,
+                now_sec,
+                types::AirdropLogKind::ReferralBonus,
+                rebate_bonus,
+                0,
+                caller_code,
+            ))?;
+        }
+        Ok(types::AirdropLog::from((idx, log)))
+    }
+
+    pub fn prize(
+        user: Principal,
+        now_sec: u64,
+        amount: u64,
+        referrer_code: u32,
+    ) -> Result<(AirdropState, types::AirdropLog), String> {
+        let state = AIRDROP.with(|r| {
+            let mut m = r.borrow_mut();
+            match m.get(&user) {
+                None => Err("no claimable airdrop to harvest".to_string()),
+                Some(state) => {
+                    let state = AirdropState(state.0, state.1, state.2 + amount);
+                    m.insert(user, state.clone());
+                    certified::set_airdrop(&user, &state);
+                    Ok(state)
+                }
+            }
+        })?;
+        state::credit_claimable(amount);
+
+        let log = AirdropLog::new(
+            user,
+            now_sec,
+            types::AirdropLogKind::PrizeClaim,
+            amount,
+            0,
+            referrer_code,
+        );
+        let idx = append_log(&log)?;
+        Ok((state, types::AirdropLog::from((idx, log))))
+    }
+
+    // the amount of a harvest with the idempotency key (created_at, user).
+    pub fn harvested(user: Principal, created_at: u64) -> Option<u64> {
+        HARVEST_KEYS.with(|r| r.borrow().get(&(created_at, user)))
+    }
+
+    // remove at most `take` idempotency keys created before `before`, in nanoseconds.
+    pub fn prune_harvest_keys(before: u64, take: usize) {
+        HARVEST_KEYS.with(|r| {
+            let mut m = r.borrow_mut();
+            let keys: Vec<(u64, Principal)> = m
+                .range(..(before, Principal::management_canister()))
+                .take(take)
+                .map(|(k, _)| k)
+                .collect();
+            for k in keys {
+                m.remove(&k);
+            }
+        });
+    }
+
+    // debit a harvested amount from the user,
+    // the idempotency key is recorded with the debit so that a retry is not debited again.
+    pub fn harvest(
+        user: Principal,
+        now_sec: u64,
+        amount: u64,
+        to: Option<Account>,
+        created_at: Option<u64>,
+    ) -> Result<(AirdropState, types::AirdropLog), String> {
+        let state = AIRDROP.with(|r| {
+            let mut m = r.borrow_mut();
+            match m.get(&user) {
+                // should never happen, we have checked the state before calling this function
+                None => Err("no claimable airdrop to harvest".to_string()),
+                Some(state) => {
+                    let state = AirdropState(
+                        state.0,
+                        if state.1 >= TOKEN_1 {
+                            state.1.saturating_add(amount)
+                        } else {
+                            amount
+                        },
+                        state.2.saturating_sub(amount),
+                    );
+                    m.insert(user, state.clone());
+                    certified::set_airdrop(&user, &state);
+                    Ok(state)
+                }
+            }
+        })?;
+        state::debit_claimable(amount);
+        if let Some(created_at) = created_at {
+            HARVEST_KEYS.with(|r| r.borrow_mut().insert((created_at, user), amount));
+        }
+
+        let mut log = AirdropLog::new(user, now_sec, types::AirdropLogKind::Harvest, 0, amount, 0);
+        log.4 = to;
+        let idx = append_log(&log)?;
+        Ok((state, types::AirdropLog::from((idx, log))))
+    }
+
+    // append an airdrop log and index it by the user.
+    fn append_log(log: &AirdropLog) -> Result<u64, String> {
+        let idx = log_base()
+            + AIRDROP_LOGS
+                .with(|r| r.borrow_mut().append(log))
+                .map_err(|err| format!("failed to append airdrop log, error {:?}", err))?;
+        AIRDROP_LOG_USER_INDEX.with(|r| r.borrow_mut().insert((log.0, !idx), ()));
+        icrc3::append_airdrop(idx, log)?;
+        Ok(idx)
+    }
+
+    // get the airdrop logs of a user in reverse order.
+    pub fn logs_of(user: Principal, prev: Option<u64>, take: usize) -> Vec<types::AirdropLog> {
+        let ids = AIRDROP_LOG_USER_INDEX.with(|r| user_log_ids(&r.borrow(), user, prev, take));
+        let (base, first_id) = (log_base(), first_log_id());
+        AIRDROP_LOGS.with(|r| {
+            let log_store = r.borrow();
+            ids.into_iter()
+                .take_while(|id| *id >= first_id)
+                .filter_map(|id| {
+                    log_store
+                        .get(id.checked_sub(base)?)
+                        .map(|log| types::AirdropLog::from((id, log)))
+                })
+                .collect()
+        })
+    }
+
+    // the index of the next airdrop log.
+    pub fn next_log_id() -> u64 {
+        log_base() + AIRDROP_LOGS.with(|r| r.borrow().len())
+    }
+
+    // the id of the oldest airdrop log kept locally, the older ones have been archived.
+    pub fn first_log_id() -> u64 {
+        state::with(|r| r.airdrop_logs_archived.unwrap_or_default())
+    }
+
+    // the id of the first airdrop log in the active stable log,
+    // it is rebuilt from the first local log by compact_logs.
+    pub fn log_base() -> u64 {
+        state::with(|r| {
+            r.airdrop_logs_base
+                .or(r.airdrop_logs_archived)
+                .unwrap_or_default()
+        })
+    }
+
+    // get the local airdrop logs in [start, end) to move to the archive.
+    pub fn logs_range(start: u64, end: u64) -> Vec<types::AirdropLog> {
+        let (base, first_id) = (log_base(), first_log_id());
+        AIRDROP_LOGS.with(|r| {
+            let log_store = r.borrow();
+            (start.max(first_id)..end)
+                .map_while(|id| {
+                    log_store
+                        .get(id - base)
+                        .map(|log| types::AirdropLog::from((id, log)))
+                })
+                .collect()
+        })
+    }
+
+    // stop serving the local airdrop logs before `end` that have been archived,
+    // their index entries are removed by prune_index, then their stable memory is reclaimed
+    // by compact_logs. The logs are not truncated while the stable log is being rebuilt.
+    pub fn truncate_logs(end: u64) {
+        if compacting() {
+            return;
+        }
+        let base = log_base();
+        let end = end.clamp(first_log_id(), next_log_id());
+        state::with_mut(|r| {
+            r.airdrop_logs_base = Some(base);
+            r.airdrop_logs_archived = Some(end);
         });
+        state::save();
     }
 
-    pub fn with_secret<R>(f: impl FnOnce(&[u8; 32]) -> R) -> R {
-        CAPTCHA_SECRET.with(|r| f(&r.borrow()))
+    // whether the stable log is being rebuilt without the archived logs.
+    pub fn compacting() -> bool {
+        state::with(|r| r.airdrop_logs_compacting.unwrap_or_default())
     }
 
-    pub fn with_challenge_pub_key<R>(f: impl FnOnce(&[u8; 32]) -> R) -> R {
-        CHALLENGE_PUB_KEY.with(|r| f(&r.borrow()))
+    // rebuild the stable log from the first local log in the spare slot, at most `take` logs
+    // in a call, and switch to it when it has caught up. The rebuilding starts after the
+    // archived logs have been removed from the index, the memories of the former log are
+    // reused by the next rebuilding. Return true if the stable log is not being rebuilt.
+    pub fn compact_logs(take: u64) -> Result<bool, String> {
+        let (base, first_id) = (log_base(), first_log_id());
+        let slot = state::with(|r| r.airdrop_logs_slot.unwrap_or_default());
+        if !compacting() {
+            let pruned = state::with(|r| r.airdrop_logs_pruned).unwrap_or(base);
+            if first_id <= base || pruned < first_id {
+                return Ok(true);
+            }
+            AIRDROP_LOGS_SPARE.with(|r| *r.borrow_mut() = reset_log(&AIRDROP_LOG_SLOTS, 1 - slot));
+            state::with_mut(|r| r.airdrop_logs_compacting = Some(true));
+        }
+
+        let caught_up = AIRDROP_LOGS.with(|a| {
+            AIRDROP_LOGS_SPARE.with(|s| copy_logs(&a.borrow(), &s.borrow(), base, first_id, take))
+        })?;
+        if !caught_up {
+            return Ok(false);
+        }
+        AIRDROP_LOGS.with(|a| AIRDROP_LOGS_SPARE.with(|s| a.swap(s)));
+        AIRDROP_LOGS_SPARE.with(|r| *r.borrow_mut() = reset_log(&AIRDROP_LOG_SLOTS, slot));
+        state::with_mut(|r| {
+            r.airdrop_logs_slot = Some(1 - slot);
+            r.airdrop_logs_base = Some(first_id);
+            r.airdrop_logs_compacting = None;
+        });
+        state::save();
+        Ok(true)
     }
 
-    pub fn set_challenge_pub_key(key: [u8; 32]) {
-        CHALLENGE_PUB_KEY.with(|r| *r.borrow_mut() = key);
+    // remove the index entries of at most `take` archived airdrop logs,
+    // return true if all archived logs have been removed from the index.
+    pub fn prune_index(take: u64) -> bool {
+        let base = log_base();
+        let first_id = first_log_id();
+        let start = state::with(|r| r.airdrop_logs_pruned).unwrap_or(base);
+        let end = first_id.min(start + take);
+        if start >= end {
+            return true;
+        }
+        AIRDROP_LOGS.with(|r| {
+            AIRDROP_LOG_USER_INDEX.with(|m| {
+                prune_log_index(&r.borrow(), &mut m.borrow_mut(), base, start, end, |log| {
+                    log.0
+                })
+            })
+        });
+        state::with_mut(|r| r.airdrop_logs_pruned = Some(end));
+        end == first_id
     }
 
-    pub static AIRDROP_KEY: Lazy<[u8; 32]> =
-        Lazy::new(|| with_secret(|b| mac_256(b, b"AIRDROP_KEY")));
-    pub static PRIZE_KEY: Lazy<[u8; 32]> = Lazy::new(|| with_secret(|b| mac_256(b, b"PRIZE_KEY")));
+    // ban users by clearing their lucky code, the former code is kept in the ban registry.
+    pub fn ban_users(
+        users: Vec<Principal>,
+        reason: String,
+        moderator: Principal,
+        expire_at: Option<u64>,
+        now_sec: u64,
+    ) -> Result<(), String> {
+        let banned: Vec<(Principal, u32)> = AIRDROP.with(|r| {
+            let mut m = r.borrow_mut();
+            let mut banned = Vec::with_capacity(users.len());
+            for user in users {
+                if let Some(state) = m.get(&user) {
+                    if state.0 != 0 {
+                        let banned_state = AirdropState(0, state.1, state.2);
+                        m.insert(user, banned_state.clone());
+                        certified::set_airdrop(&user, &banned_state);
+                        banned.push((user, state.0));
+                    }
+                }
+            }
+            banned
+        });
+        BANS.with(|r| {
+            let mut m = r.borrow_mut();
+            for (user, code) in &banned {
+                m.insert(
+                    *user,
+                    BanState(
+                        *code,
+                        reason.clone(),
+                        moderator,
+                        now_sec,
+                        expire_at.unwrap_or_default(),
+                    ),
+                );
+            }
+        });
+        for (user, code) in banned {
+            append_log(&AirdropLog::new(
+                user,
+                now_sec,
+                types::AirdropLogKind::Ban,
+                0,
+                0,
+                code,
+            ))?;
+        }
+        Ok(())
+    }
+
+    // unban users and restore their former lucky codes, each user is unbanned independently.
+    // The former codes of the users banned before the ban registry are recorded in the registry
+    // by ban::backfill_legacy. Return the users that failed to be unbanned with the errors.
+    pub fn unban_users(users: Vec<Principal>, now_sec: u64) -> Vec<(Principal, String)> {
+        let mut failed = Vec::new();
+        for user in users {
+            if let Err(err) = unban_user(user, now_sec) {
+                failed.push((user, err));
+            }
+        }
+        failed
+    }
+
+    fn unban_user(user: Principal, now_sec: u64) -> Result<(), String> {
+        let AirdropState(_, claimed, claimable) = match state_of(&user) {
+            Some(state) if state.0 == 0 => state,
+            _ => {
+                BANS.with(|r| r.borrow_mut().remove(&user));
+                return Ok(());
+            }
+        };
+        let code = BANS
+            .with(|r| r.borrow().get(&user))
+            .map(|b| b.0)
+            .ok_or_else(|| format!("lucky code of {} not found, try again later", user))?;
+        append_log(&AirdropLog::new(
+            user,
+            now_sec,
+            types::AirdropLogKind::Unban,
+            0,
+            0,
+            code,
+        ))?;
+        BANS.with(|r| r.borrow_mut().remove(&user));
+        let state = AirdropState(code, claimed, claimable);
+        AIRDROP.with(|r| r.borrow_mut().insert(user, state.clone()));
+        certified::set_airdrop(&user, &state);
+        Ok(())
+    }
+
+    // lock the claimable tokens of a user as the budget of a prize.
+    pub fn lock_prize(user: Principal, now_sec: u64, amount: u64) -> Result<(), String> {
+        AIRDROP.with(|r| {
+            let mut m = r.borrow_mut();
+            match m.get(&user) {
+                Some(state) if state.2 >= amount => {
+                    let state = AirdropState(state.0, state.1, state.2 - amount);
+                    m.insert(user, state.clone());
+                    certified::set_airdrop(&user, &state);
+                    Ok(())
+                }
+                _ => Err("insufficient claimable tokens".to_string()),
+            }
+        })?;
+        state::with_mut(|r| {
+            r.total_claimable = Some(r.total_claimable.unwrap_or_default().saturating_sub(amount));
+        });
+        state::reserve_prize(amount);
+        append_log(&AirdropLog::new(
+            user,
+            now_sec,
+            types::AirdropLogKind::PrizeLock,
+            0,
+            amount,
+            0,
+        ))?;
+        Ok(())
+    }
+
+    // refund the unclaimed budget of a prize to the issuer's claimable balance.
+    pub fn refund_prize(user: Principal, now_sec: u64, amount: u64) -> Result<(), String> {
+        AIRDROP.with(|r| {
+            let mut m = r.borrow_mut();
+            let state = m.get(&user).unwrap_or_default();
+            let state = AirdropState(state.0, state.1, state.2.saturating_add(amount));
+            m.insert(user, state.clone());
+            certified::set_airdrop(&user, &state);
+        });
+        state::with_mut(|r| {
+            r.total_claimable = Some(r.total_claimable.unwrap_or_default().saturating_add(amount));
+        });
+        append_log(&AirdropLog::new(
+            user,
+            now_sec,
+            types::AirdropLogKind::PrizeRefund,
+            amount,
+            0,
+            0,
+        ))?;
+        Ok(())
+    }
+
+    // set the airdrop balance of the pool by a treasurer, the change is logged as a manual
+    // adjustment of the treasurer. Return the previous balance.
+    pub fn adjust_balance(manager: Principal, now_sec: u64, balance: u64) -> Result<u64, String> {
+        let prev = state::with(|r| r.airdrop_balance);
+        append_log(&AirdropLog::new(
+            manager,
+            now_sec,
+            types::AirdropLogKind::ManualAdjustment,
+            balance.saturating_sub(prev),
+            prev.saturating_sub(balance),
+            0,
+        ))?;
+        state::with_mut(|r| r.airdrop_balance = balance);
+        Ok(prev)
+    }
+
+    // the sum of all users' claimable tokens.
+    pub fn total_claimable() -> u64 {
+        AIRDROP.with(|r| {
+            r.borrow()
+                .iter()
+                .fold(0u64, |acc, (_, s)| acc.saturating_add(s.2))
+        })
+    }
+
+    // get the local airdrop logs in reverse order, starting before `prev`.
+    pub fn logs(prev: Option<u64>, take: usize) -> Vec<types::AirdropLog> {
+        let (base, first_id) = (log_base(), first_log_id());
+        AIRDROP_LOGS.with(|r| {
+            let log_store = r.borrow();
+            logs_before(&log_store, base, first_id, prev, take)
+                .into_iter()
+                .map(types::AirdropLog::from)
+                .collect()
+        })
+    }
 }
 
-pub mod luckycode {
+pub mod luckydraw {
     use super::*;
 
-    // pub fn get(code: u32) -> Option<Principal> {
-    //     LUCKYCODE.with(|r| r.borrow().get(&code))
-    // }
+    // append a luckydraw log.
+    // return the log or an error message when append failed.
+    pub fn insert(log: LuckyDrawLog) -> Result<types::LuckyDrawLog, String> {
+        let idx = log_base()
+            + LUCKYDRAW_LOGS
+                .with(|r| r.borrow_mut().append(&log))
+                .map_err(|err| format!("failed to append luckydraw log, error {:?}", err))?;
+        LUCKYDRAW_LOG_USER_INDEX.with(|r| r.borrow_mut().insert((log.0, !idx), ()));
+        icrc3::append_luckydraw(idx, &log)?;
+        Ok(types::LuckyDrawLog::from((idx, log)))
+    }
 
-    pub fn get_by_string(code: &str) -> Option<Principal> {
-        match luckycode_from_string(code) {
-            Ok(code) => LUCKYCODE.with(|r| r.borrow().get(&code)),
-            Err(_) => None,
+    // get the luckydraw logs of a user in reverse order.
+    pub fn logs_of(user: Principal, prev: Option<u64>, take: usize) -> Vec<types::LuckyDrawLog> {
+        let ids = LUCKYDRAW_LOG_USER_INDEX.with(|r| user_log_ids(&r.borrow(), user, prev, take));
+        let (base, first_id) = (log_base(), first_log_id());
+        LUCKYDRAW_LOGS.with(|r| {
+            let log_store = r.borrow();
+            ids.into_iter()
+                .take_while(|id| *id >= first_id)
+                .filter_map(|id| {
+                    log_store
+                        .get(id.checked_sub(base)?)
+                        .map(|log| types::LuckyDrawLog::from((id, log)))
+                })
+                .collect()
+        })
+    }
+
+    // the index of the next luckydraw log.
+    pub fn next_log_id() -> u64 {
+        log_base() + LUCKYDRAW_LOGS.with(|r| r.borrow().len())
+    }
+
+    // the id of the oldest luckydraw log kept locally, the older ones have been archived.
+    pub fn first_log_id() -> u64 {
+        state::with(|r| r.luckydraw_logs_archived.unwrap_or_default())
+    }
+
+    // the id of the first luckydraw log in the active stable log,
+    // it is rebuilt from the first local log by compact_logs.
+    pub fn log_base() -> u64 {
+        state::with(|r| {
+            r.luckydraw_logs_base
+                .or(r.luckydraw_logs_archived)
+                .unwrap_or_default()
+        })
+    }
+
+    // get the local luckydraw logs in [start, end) to move to the archive.
+    pub fn logs_range(start: u64, end: u64) -> Vec<types::LuckyDrawLog> {
+        let (base, first_id) = (log_base(), first_log_id());
+        LUCKYDRAW_LOGS.with(|r| {
+            let log_store = r.borrow();
+            (start.max(first_id)..end)
+                .map_while(|id| {
+                    log_store
+                        .get(id - base)
+                        .map(|log| types::LuckyDrawLog::from((id, log)))
+                })
+                .collect()
+        })
+    }
+
+    // stop serving the local luckydraw logs before `end` that have been archived,
+    // their index entries are removed by prune_index, then their stable memory is reclaimed
+    // by compact_logs. The logs are not truncated while the stable log is being rebuilt.
+    pub fn truncate_logs(end: u64) {
+        if compacting() {
+            return;
         }
+        let base = log_base();
+        let end = end.clamp(first_log_id(), next_log_id());
+        state::with_mut(|r| {
+            r.luckydraw_logs_base = Some(base);
+            r.luckydraw_logs_archived = Some(end);
+        });
+        state::save();
     }
 
-    pub fn new_from(user: Principal) -> u32 {
-        LUCKYCODE.with(|r| {
+    // whether the stable log is being rebuilt without the archived logs.
+    pub fn compacting() -> bool {
+        state::with(|r| r.luckydraw_logs_compacting.unwrap_or_default())
+    }
+
+    // rebuild the stable log from the first local log in the spare slot, at most `take` logs
+    // in a call, and switch to it when it has caught up. The rebuilding starts after the
+    // archived logs have been removed from the index, the memories of the former log are
+    // reused by the next rebuilding. Return true if the stable log is not being rebuilt.
+    pub fn compact_logs(take: u64) -> Result<bool, String> {
+        let (base, first_id) = (log_base(), first_log_id());
+        let slot = state::with(|r| r.luckydraw_logs_slot.unwrap_or_default());
+        if !compacting() {
+            let pruned = state::with(|r| r.luckydraw_logs_pruned).unwrap_or(base);
+            if first_id <= base || pruned < first_id {
+                return Ok(true);
+            }
+            LUCKYDRAW_LOGS_SPARE
+                .with(|r| *r.borrow_mut() = reset_log(&LUCKYDRAW_LOG_SLOTS, 1 - slot));
+            state::with_mut(|r| r.luckydraw_logs_compacting = Some(true));
+        }
+
+        let caught_up = LUCKYDRAW_LOGS.with(|a| {
+            LUCKYDRAW_LOGS_SPARE.with(|s| copy_logs(&a.borrow(), &s.borrow(), base, first_id, take))
+        })?;
+        if !caught_up {
+            return Ok(false);
+        }
+        LUCKYDRAW_LOGS.with(|a| LUCKYDRAW_LOGS_SPARE.with(|s| a.swap(s)));
+        LUCKYDRAW_LOGS_SPARE.with(|r| *r.borrow_mut() = reset_log(&LUCKYDRAW_LOG_SLOTS, slot));
+        state::with_mut(|r| {
+            r.luckydraw_logs_slot = Some(1 - slot);
+            r.luckydraw_logs_base = Some(first_id);
+            r.luckydraw_logs_compacting = None;
+        });
+        state::save();
+        Ok(true)
+    }
+
+    // remove the index entries of at most `take` archived luckydraw logs,
+    // return true if all archived logs have been removed from the index.
+    pub fn prune_index(take: u64) -> bool {
+        let base = log_base();
+        let first_id = first_log_id();
+        let start = state::with(|r| r.luckydraw_logs_pruned).unwrap_or(base);
+        let end = first_id.min(start + take);
+        if start >= end {
+            return true;
+        }
+        LUCKYDRAW_LOGS.with(|r| {
+            LUCKYDRAW_LOG_USER_INDEX.with(|m| {
+                prune_log_index(&r.borrow(), &mut m.borrow_mut(), base, start, end, |log| {
+                    log.0
+                })
+            })
+        });
+        state::with_mut(|r| r.luckydraw_logs_pruned = Some(end));
+        end == first_id
+    }
+
+    // add a journal entry when ICP is received, return the journal id.
+    // The journal id is an operation id, it is used in the memos of the payout and the refund.
+    pub fn journal_add(entry: LuckyDrawJournal) -> u64 {
+        let id = state::next_operation_id();
+        LUCKYDRAW_JOURNAL.with(|r| r.borrow_mut().insert(id, entry));
+        id
+    }
+
+    // the id after the last journal entry.
+    pub fn journal_next_id() -> u64 {
+        LUCKYDRAW_JOURNAL.with(|r| r.borrow().last_key_value().map(|(k, _)| k + 1).unwrap_or(0))
+    }
+
+    pub fn journal_update(
+        id: u64,
+        stage: types::LuckyDrawStage,
+        token_amount: u64,
+        jackpot_amount: u64,
+    ) {
+        LUCKYDRAW_JOURNAL.with(|r| {
             let mut m = r.borrow_mut();
-            let mut code = (m.len() as u32).saturating_add(1000000);
-            let mut i = 0u32;
-            while m.contains_key(&code) {
-                code = code.saturating_add(1);
-                i += 1;
-                if i > 100000 {
-                    ic_cdk::trap("failed to generate a lucky code");
-                }
+            if let Some(mut entry) = m.get(&id) {
+                entry.3 = token_amount;
+                entry.5 = stage;
+                entry.7 = jackpot_amount;
+                m.insert(id, entry);
             }
-            m.insert(code, user);
-            code
+        });
+    }
+
+    pub fn journal_get(id: u64) -> Option<LuckyDrawJournal> {
+        LUCKYDRAW_JOURNAL.with(|r| r.borrow().get(&id))
+    }
+
+    // remove the journal entry when the luckydraw is settled.
+    pub fn journal_remove(id: u64) {
+        LUCKYDRAW_JOURNAL.with(|r| r.borrow_mut().remove(&id));
+    }
+
+    pub fn journal_of(user: &Principal) -> Vec<types::LuckyDrawJournal> {
+        LUCKYDRAW_JOURNAL.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(_, entry)| &entry.0 == user)
+                .map(types::LuckyDrawJournal::from)
+                .collect()
+        })
+    }
+
+    // the tokens of the payouts which have not been settled.
+    pub fn journal_payouts() -> u64 {
+        LUCKYDRAW_JOURNAL.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(_, entry)| entry.5 != types::LuckyDrawStage::RefundOwed)
+                .fold(0u64, |acc, (_, entry)| acc.saturating_add(entry.3))
+        })
+    }
+
+    // get the unsettled journal entries that were created before `before_sec`.
+    // The entries in PayoutSent stage created before `resend_after` are skipped,
+    // their payout can not be resent safely and should be reviewed manually.
+    pub fn journal_stale(
+        resend_after: u64,
+        before_sec: u64,
+        take: usize,
+    ) -> Vec<(u64, LuckyDrawJournal)> {
+        LUCKYDRAW_JOURNAL.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(_, entry)| {
+                    entry.1 < before_sec
+                        && (entry.5 != types::LuckyDrawStage::PayoutSent || entry.1 >= resend_after)
+                })
+                .take(take)
+                .collect()
+        })
+    }
+
+    // get the entries in PayoutSent stage created before `resend_after`,
+    // they are skipped by journal_stale and should be resolved by a manager.
+    pub fn journal_unresolved(resend_after: u64) -> Vec<types::LuckyDrawJournal> {
+        LUCKYDRAW_JOURNAL.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(_, entry)| {
+                    entry.5 == types::LuckyDrawStage::PayoutSent && entry.1 < resend_after
+                })
+                .map(types::LuckyDrawJournal::from)
+                .collect()
+        })
+    }
+
+    pub fn get(id: u64) -> Option<LuckyDrawLog> {
+        if id < first_log_id() {
+            return None;
+        }
+        LUCKYDRAW_LOGS.with(|r| r.borrow().get(id - log_base()))
+    }
+
+    // get the local luckydraw logs in reverse order, starting before `prev`.
+    pub fn logs(prev: Option<u64>, take: usize) -> Vec<types::LuckyDrawLog> {
+        let (base, first_id) = (log_base(), first_log_id());
+        LUCKYDRAW_LOGS.with(|r| {
+            let log_store = r.borrow();
+            logs_before(&log_store, base, first_id, prev, take)
+                .into_iter()
+                .map(types::LuckyDrawLog::from)
+                .collect()
+        })
+    }
+}
+
+pub mod archive {
+    use super::*;
+
+    pub fn wasm() -> Vec<u8> {
+        ARCHIVE_WASM.with(|r| r.borrow().get().clone())
+    }
+
+    pub fn set_wasm(wasm: Vec<u8>) {
+        ARCHIVE_WASM.with(|r| {
+            r.borrow_mut()
+                .set(wasm)
+                .expect("failed to set ARCHIVE_WASM data");
+        });
+    }
+}
+
+pub mod icrc3 {
+    use super::*;
+    use types::ICRC3Value;
+
+    pub const BTYPE_CLAIM: &str = "panda_claim";
+    pub const BTYPE_REFERRAL: &str = "panda_referral";
+    pub const BTYPE_PRIZE: &str = "panda_prize";
+    pub const BTYPE_HARVEST: &str = "panda_harvest";
+    pub const BTYPE_ADJUST: &str = "panda_adjust";
+    pub const BTYPE_DRAW: &str = "panda_draw";
+    pub const BTYPE_PRIZE_LOCK: &str = "panda_prize_lock";
+    pub const BTYPE_PRIZE_REFUND: &str = "panda_prize_refund";
+
+    // append a block for an airdrop log, the logs that don't change balances are skipped.
+    pub fn append_airdrop(log_id: u64, log: &AirdropLog) -> Result<(), String> {
+        let btype = match log.kind() {
+            types::AirdropLogKind::Claim => BTYPE_CLAIM,
+            types::AirdropLogKind::ReferralBonus => BTYPE_REFERRAL,
+            types::AirdropLogKind::PrizeClaim => BTYPE_PRIZE,
+            types::AirdropLogKind::Harvest => BTYPE_HARVEST,
+            types::AirdropLogKind::ManualAdjustment => BTYPE_ADJUST,
+            types::AirdropLogKind::PrizeLock => BTYPE_PRIZE_LOCK,
+            types::AirdropLogKind::PrizeRefund => BTYPE_PRIZE_REFUND,
+            types::AirdropLogKind::Ban | types::AirdropLogKind::Unban => return Ok(()),
+        };
+
+        let mut tx = BTreeMap::from([
+            ("acc".to_string(), ICRC3Value::from(&Account::from(log.0))),
+            ("log".to_string(), ICRC3Value::Nat(Nat::from(log_id))),
+        ]);
+        if log.6 > 0 {
+            tx.insert("cred".to_string(), ICRC3Value::Nat(Nat::from(log.6)));
+        }
+        if log.2 > 0 {
+            tx.insert("deb".to_string(), ICRC3Value::Nat(Nat::from(log.2)));
+        }
+        if log.3 > 0 {
+            tx.insert(
+                "code".to_string(),
+                ICRC3Value::Text(luckycode_to_string(log.3)),
+            );
+        }
+        if let Some(to) = &log.4 {
+            tx.insert("to".to_string(), ICRC3Value::from(to));
+        }
+        append(btype, log.1, tx)
+    }
+
+    // append a block for a luckydraw log.
+    pub fn append_luckydraw(log_id: u64, log: &LuckyDrawLog) -> Result<(), String> {
+        let mut tx = BTreeMap::from([
+            ("acc".to_string(), ICRC3Value::from(&Account::from(log.0))),
+            ("log".to_string(), ICRC3Value::Nat(Nat::from(log_id))),
+            ("amt".to_string(), ICRC3Value::Nat(Nat::from(log.2))),
+            ("icp".to_string(), ICRC3Value::Nat(Nat::from(log.3))),
+            ("rnd".to_string(), ICRC3Value::Nat(Nat::from(log.4))),
+        ]);
+        if let Some(batch_id) = log.6 {
+            tx.insert("batch".to_string(), ICRC3Value::Nat(Nat::from(batch_id)));
+        }
+        if let Some(payment) = &log.7 {
+            tx.insert(
+                "pay".to_string(),
+                ICRC3Value::Map(BTreeMap::from([
+                    (
+                        "ledger".to_string(),
+                        ICRC3Value::Blob(ByteBuf::from(payment.0.as_slice())),
+                    ),
+                    ("amt".to_string(), ICRC3Value::Nat(Nat::from(payment.1))),
+                ])),
+            );
+        }
+        if let Some(to) = &log.8 {
+            tx.insert("to".to_string(), ICRC3Value::from(to));
+        }
+        append(BTYPE_DRAW, log.1, tx)
+    }
+
+    fn append(btype: &str, now_sec: u64, tx: BTreeMap<String, ICRC3Value>) -> Result<(), String> {
+        BLOCKS
+            .with(|r| {
+                let blocks = r.borrow_mut();
+                let mut block = BTreeMap::from([
+                    ("btype".to_string(), ICRC3Value::Text(btype.to_string())),
+                    (
+                        "ts".to_string(),
+                        ICRC3Value::Nat(Nat::from(now_sec * SECOND)),
+                    ),
+                    ("tx".to_string(), ICRC3Value::Map(tx)),
+                ]);
+                if let Some(prev) = blocks.len().checked_sub(1).and_then(|i| blocks.get(i)) {
+                    block.insert(
+                        "phash".to_string(),
+                        ICRC3Value::Blob(ByteBuf::from(prev.hash())),
+                    );
+                }
+                let block = ICRC3Value::Map(block);
+                let idx = blocks
+                    .append(&block)
+                    .map_err(|err| format!("failed to append block, error {:?}", err))?;
+                Ok::<_, String>((block.hash(), idx))
+            })
+            .map(|(hash, idx)| certified::set_tip(hash, idx))
+    }
+
+    pub fn len() -> u64 {
+        BLOCKS.with(|r| r.borrow().len())
+    }
+
+    // get at most `take` blocks starting from `start`.
+    pub fn blocks(start: u64, take: u64) -> Vec<(u64, ICRC3Value)> {
+        BLOCKS.with(|r| {
+            let blocks = r.borrow();
+            let end = blocks.len().min(start.saturating_add(take));
+            (start..end)
+                .filter_map(|i| blocks.get(i).map(|block| (i, block)))
+                .collect()
+        })
+    }
+
+    // the hash and the index of the last block.
+    pub fn tip() -> Option<([u8; 32], u64)> {
+        BLOCKS.with(|r| {
+            let blocks = r.borrow();
+            let idx = blocks.len().checked_sub(1)?;
+            blocks.get(idx).map(|block| (block.hash(), idx))
         })
     }
 }
 
-pub mod xauth {
+// The certified data of the canister is the root hash of the tree:
+//   airdrop/<user principal> -> hash of the user's AirdropStateOutput
+//   last_block_hash, last_block_index -> the ICRC-3 tip
+//   prizes/<issuer code in big-endian> -> hash of the issuer's prizes
+//   state -> hash of the StateSummary
+// The values are hashed as ICRC-3 values, see ICRC3Value::hash.
+pub mod certified {
     use super::*;
+    use ic_certification::{
+        fork, labeled, leaf, leaf_hash, pruned, AsHashTree, Hash, HashTree, RbTree,
+    };
 
-    // pub fn get(id: &String) -> Option<(Principal, u64)> {
-    //     X_AUTH.with(|r| r.borrow().get(id))
-    // }
-
-    // pub fn exists(id: &String) -> bool {
-    //     X_AUTH.with(|r| r.borrow().contains_key(id))
-    // }
+    #[derive(Default)]
+    pub struct CertifiedTree {
+        airdrop: RbTree<Vec<u8>, Hash>,
+        prizes: RbTree<Vec<u8>, Hash>,
+        tip: Option<(Hash, u64)>,
+        state: Hash,
+        rebuild: Option<Rebuild>,
+    }
 
-    pub fn try_set(id: String, user: Principal, now_sec: u64) -> bool {
-        X_AUTH.with(|r| {
-            let mut m = r.borrow_mut();
-            if m.contains_key(&id) {
-                return false;
-            }
+    // the progress of rebuilding the airdrop and prizes trees, the last key rebuilt.
+    #[derive(Clone, Copy)]
+    enum Rebuild {
+        Airdrop(Option<Principal>),
+        Prizes(Option<u32>),
+    }
 
-            m.insert(id, (user, now_sec));
-            true
-        })
+    // the paths to reveal in a witness, the others are pruned.
+    #[derive(Default)]
+    pub struct Reveal {
+        pub airdrop: Option<Principal>,
+        pub prizes: Option<u32>,
+        pub tip: bool,
+        pub state: bool,
     }
-}
 
-pub mod airdrop {
-    use crate::TOKEN_1;
+    impl CertifiedTree {
+        pub fn tree(&self, reveal: &Reveal) -> HashTree {
+            let reveal_leaf = |data: Vec<u8>, reveal: bool| {
+                if reveal {
+                    leaf(data)
+                } else {
+                    pruned(leaf_hash(&data))
+                }
+            };
 
-    use super::*;
+            let mut nodes = vec![labeled(
+                "airdrop",
+                match reveal.airdrop {
+                    Some(user) => self.airdrop.witness(user.as_slice()),
+                    None => pruned(self.airdrop.root_hash()),
+                },
+            )];
+            if let Some((hash, idx)) = self.tip {
+                nodes.push(labeled(
+                    "last_block_hash",
+                    reveal_leaf(hash.to_vec(), reveal.tip),
+                ));
+                nodes.push(labeled(
+                    "last_block_index",
+                    reveal_leaf(leb128(idx), reveal.tip),
+                ));
+            }
+            nodes.push(labeled(
+                "prizes",
+                match reveal.prizes {
+                    Some(code) => self.prizes.witness(&code.to_be_bytes()),
+                    None => pruned(self.prizes.root_hash()),
+                },
+            ));
+            nodes.push(labeled(
+                "state",
+                reveal_leaf(self.state.to_vec(), reveal.state),
+            ));
+            // the labels are sorted, so that the paths can be looked up in the tree
+            nodes
+                .into_iter()
+                .rev()
+                .reduce(|right, left| fork(left, right))
+                .expect("certified tree is not empty")
+        }
+    }
 
-    // check if a user has claimed airdrop.
-    pub fn state_of(user: &Principal) -> Option<AirdropState> {
-        AIRDROP.with(|r| r.borrow().get(user))
+    fn leb128(n: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Nat::from(n)
+            .encode(&mut buf)
+            .expect("failed to encode leb128");
+        buf
     }
 
-    // update luckydraw state and append a log.
-    // return the log or an error message when append failed.
-    pub fn insert(
-        user: Principal,
-        referrer: Option<Principal>,
-        now_sec: u64,
-        amount: u64,
-        rebate_bonus: u64,
-        caller_code: u32,
-    ) -> Result<types::AirdropLog, String> {
-        let referrer_code = AIRDROP.with(|r| {
-            let mut m = r.borrow_mut();
-            m.insert(user, AirdropState(caller_code, 0, amount));
+    // reset the tree after upgrading, the airdrop and prizes trees are rebuilt from the stores
+    // in chunks by `rebuild`, so that the work is bounded by the instruction limit of a message.
+    pub fn init() {
+        let tree = CertifiedTree {
+            tip: icrc3::tip(),
+            state: state_hash(),
+            rebuild: Some(Rebuild::Airdrop(None)),
+            ..Default::default()
+        };
+        CERTIFIED_TREE.with(|r| *r.borrow_mut() = tree);
+        certify();
+    }
 
-            match referrer {
-                None => 0,
-                Some(referrer) => match m.get(&referrer) {
-                    None => 0,
-                    Some(state) => {
-                        m.insert(
-                            referrer,
-                            AirdropState(state.0, state.1, state.2 + rebate_bonus),
-                        );
-                        state.0
+    // rebuild at most `take` entries of the airdrop and prizes trees,
+    // return true if the tree has been rebuilt.
+    pub fn rebuild(take: usize) -> bool {
+        let next = match CERTIFIED_TREE.with(|r| r.borrow().rebuild) {
+            None => return true,
+            Some(Rebuild::Airdrop(after)) => {
+                let entries: Vec<(Principal, AirdropState)> = AIRDROP.with(|r| {
+                    let m = r.borrow();
+                    match after {
+                        Some(user) => m
+                            .range((ops::Bound::Excluded(user), ops::Bound::Unbounded))
+                            .take(take)
+                            .collect(),
+                        None => m.iter().take(take).collect(),
                     }
-                },
+                });
+                let next = match entries.last() {
+                    Some((user, _)) if entries.len() == take => Rebuild::Airdrop(Some(*user)),
+                    _ => Rebuild::Prizes(None),
+                };
+                CERTIFIED_TREE.with(|r| {
+                    let mut tree = r.borrow_mut();
+                    for (user, state) in entries {
+                        tree.airdrop
+                            .insert(user.as_slice().to_vec(), airdrop_hash(&state));
+                    }
+                });
+                Some(next)
+            }
+            Some(Rebuild::Prizes(after)) => {
+                let entries: Vec<(u32, IssuerPrizes)> = ISSUER_PRIZE.with(|r| {
+                    let m = r.borrow();
+                    match after {
+                        Some(code) => m
+                            .range((ops::Bound::Excluded(code), ops::Bound::Unbounded))
+                            .take(take)
+                            .collect(),
+                        None => m.iter().take(take).collect(),
+                    }
+                });
+                let next = match entries.last() {
+                    Some((code, _)) if entries.len() == take => Some(Rebuild::Prizes(Some(*code))),
+                    _ => None,
+                };
+                CERTIFIED_TREE.with(|r| {
+                    let mut tree = r.borrow_mut();
+                    for (code, prizes) in entries {
+                        tree.prizes
+                            .insert(code.to_be_bytes().to_vec(), prizes_hash(code, &prizes));
+                    }
+                });
+                next
             }
+        };
+        CERTIFIED_TREE.with(|r| r.borrow_mut().rebuild = next);
+        certify();
+        next.is_none()
+    }
+
+    // the witnesses of the airdrop and prizes trees are incomplete until they are rebuilt.
+    pub fn is_ready() -> bool {
+        CERTIFIED_TREE.with(|r| r.borrow().rebuild.is_none())
+    }
+
+    pub fn set_airdrop(user: &Principal, state: &AirdropState) {
+        CERTIFIED_TREE.with(|r| {
+            r.borrow_mut()
+                .airdrop
+                .insert(user.as_slice().to_vec(), airdrop_hash(state))
         });
+        certify();
+    }
 
-        let log = AirdropLog(user, now_sec, 0, referrer_code);
-        let idx = AIRDROP_LOGS
-            .with(|r| r.borrow_mut().append(&log))
-            .map_err(|err| format!("failed to append airdrop log, error {:?}", err))?;
-        Ok(types::AirdropLog::from((idx, log)))
+    pub fn set_prizes(code: u32, prizes: &IssuerPrizes) {
+        CERTIFIED_TREE.with(|r| {
+            r.borrow_mut()
+                .prizes
+                .insert(code.to_be_bytes().to_vec(), prizes_hash(code, prizes))
+        });
+        certify();
     }
 
-    pub fn prize(
-        user: Principal,
-        now_sec: u64,
-        amount: u64,
-        referrer_code: u32,
-    ) -> Result<(AirdropState, types::AirdropLog), String> {
-        let state = AIRDROP.with(|r| {
-            let mut m = r.borrow_mut();
-            match m.get(&user) {
-                None => Err("no claimable airdrop to harvest".to_string()),
-                Some(state) => {
-                    let state = AirdropState(state.0, state.1, state.2 + amount);
-                    m.insert(user, state.clone());
-                    Ok(state)
-                }
-            }
-        })?;
+    pub fn set_tip(hash: Hash, idx: u64) {
+        CERTIFIED_TREE.with(|r| r.borrow_mut().tip = Some((hash, idx)));
+        certify();
+    }
 
-        let log = AirdropLog(user, now_sec, 0, referrer_code);
-        let idx = AIRDROP_LOGS
-            .with(|r| r.borrow_mut().append(&log))
-            .map_err(|err| format!("failed to append airdrop log, error {:?}", err))?;
-        Ok((state, types::AirdropLog::from((idx, log))))
+    pub fn refresh_state() {
+        let hash = state_hash();
+        CERTIFIED_TREE.with(|r| r.borrow_mut().state = hash);
+        certify();
     }
 
-    pub fn harvest(
-        user: Principal,
-        now_sec: u64,
-        amount: u64,
-    ) -> Result<(AirdropState, types::AirdropLog), String> {
-        let state = AIRDROP.with(|r| {
-            let mut m = r.borrow_mut();
-            match m.get(&user) {
-                // should never happen, we have checked the state before calling this function
-                None => Err("no claimable airdrop to harvest".to_string()),
-                Some(state) => {
-                    let state = AirdropState(
-                        state.0,
-                        if state.1 >= TOKEN_1 {
-                            state.1.saturating_add(amount)
-                        } else {
-                            amount
-                        },
-                        state.2.saturating_sub(amount),
-                    );
-                    m.insert(user, state.clone());
-                    Ok(state)
-                }
-            }
-        })?;
+    // the witness of the revealed paths, encoded in CBOR with the self-describe tag.
+    pub fn witness(reveal: &Reveal) -> Vec<u8> {
+        let tree = CERTIFIED_TREE.with(|r| r.borrow().tree(reveal));
+        let mut buf = vec![];
+        into_writer(&ciborium::tag::Required::<_, 55799>(&tree), &mut buf)
+            .expect("failed to encode witness");
+        buf
+    }
 
-        let log = AirdropLog(user, now_sec, amount, 0);
-        let idx = AIRDROP_LOGS
-            .with(|r| r.borrow_mut().append(&log))
-            .map_err(|err| format!("failed to append airdrop log, error {:?}", err))?;
-        Ok((state, types::AirdropLog::from((idx, log))))
+    pub fn has_tip() -> bool {
+        CERTIFIED_TREE.with(|r| r.borrow().tip.is_some())
     }
 
-    pub fn ban_users(users: Vec<Principal>) -> Result<(), String> {
-        AIRDROP.with(|r| {
-            let mut m = r.borrow_mut();
-            for user in users {
-                if let Some(state) = m.get(&user) {
-                    m.insert(user, AirdropState(0, state.1, state.2));
-                }
-            }
-        });
-        Ok(())
+    fn airdrop_hash(state: &AirdropState) -> Hash {
+        types::ICRC3Value::from(&types::AirdropStateOutput::from(state)).hash()
     }
 
-    // get airdrop logs in reverse order, return the next index to fetch.
-    pub fn logs(prev: Option<u64>, take: usize) -> Vec<types::AirdropLog> {
-        AIRDROP_LOGS.with(|r| {
-            let log_store = r.borrow();
-            let latest = log_store.len();
-            if latest == 0 {
-                return vec![];
-            }
+    fn prizes_hash(code: u32, prizes: &IssuerPrizes) -> Hash {
+        types::ICRC3Value::Array(
+            prizes
+                .to_output(code)
+                .into_iter()
+                .map(|p| {
+                    let mut vals = vec![
+                        types::ICRC3Value::Nat(Nat::from(p.0)),
+                        types::ICRC3Value::Nat(Nat::from(p.1)),
+                        types::ICRC3Value::Nat(Nat::from(p.2)),
+                        types::ICRC3Value::Nat(Nat::from(p.3)),
+                        types::ICRC3Value::Nat(Nat::from(p.4)),
+                        types::ICRC3Value::Nat(Nat::from(p.5)),
+                    ];
+                    vals.push(types::ICRC3Value::Text(p.6));
+                    if let Some((user, amount)) = p.7 {
+                        vals.push(types::ICRC3Value::Array(vec![
+                            types::ICRC3Value::Blob(ByteBuf::from(user.as_slice())),
+                            types::ICRC3Value::Nat(Nat::from(amount)),
+                        ]));
+                    }
+                    types::ICRC3Value::Array(vals)
+                })
+                .collect(),
+        )
+        .hash()
+    }
 
-            let prev = prev.unwrap_or(latest);
-            if prev > latest || prev == 0 {
-                return vec![];
-            }
+    fn state_hash() -> Hash {
+        let summary = state::with(|r| types::StateSummary::from(r));
+        types::ICRC3Value::from(&summary).hash()
+    }
+
+    // set the root hash of the tree as the certified data,
+    // it should be called in updates and after upgrading.
+    fn certify() {
+        let root = CERTIFIED_TREE.with(|r| r.borrow().tree(&Reveal::default()).digest());
+        set_certified_data(&root);
+    }
 
-            let mut idx = prev - 1;
-            let mut logs: Vec<types::AirdropLog> = Vec::with_capacity(take);
-            while let Some(log) = log_store.get(idx) {
-                logs.push(types::AirdropLog::from((idx, log)));
+    #[cfg(target_arch = "wasm32")]
+    fn set_certified_data(data: &[u8]) {
+        ic_cdk::api::set_certified_data(data);
+    }
 
-                if idx == 0 || logs.len() >= take {
-                    break;
-                }
-                idx -= 1;
-            }
+    // certified data is only available in the canister.
+    #[cfg(not(target_arch = "wasm32"))]
+    fn set_certified_data(_data: &[u8]) {}
+}
+
+pub mod luckydraw_table {
+    use super::*;
+
+    // get the active payout table and its version.
+    // The version 0 is the default table, it is not stored.
+    pub fn active() -> (u32, types::LuckyDrawTable) {
+        LUCKYDRAW_TABLE.with(|r| {
+            r.borrow()
+                .last_key_value()
+                .unwrap_or((0, types::LuckyDrawTable::default()))
+        })
+    }
+
+    pub fn get(version: u32) -> Option<types::LuckyDrawTable> {
+        if version == 0 {
+            return Some(types::LuckyDrawTable::default());
+        }
+        LUCKYDRAW_TABLE.with(|r| r.borrow().get(&version))
+    }
 
-            logs
+    // add a new version of payout table and make it active, return the version.
+    pub fn set(table: types::LuckyDrawTable) -> u32 {
+        LUCKYDRAW_TABLE.with(|r| {
+            let mut m = r.borrow_mut();
+            let version = m.last_key_value().map(|(k, _)| k + 1).unwrap_or(1);
+            m.insert(version, table);
+            version
         })
     }
 }
 
-pub mod luckydraw {
+pub mod payment_token {
     use super::*;
+    use crate::ICP_CANISTER;
 
-    // insert luckydraw state and append a log.
-    // return the log or an error message when append failed.
-    pub fn insert(
-        user: Principal,
-        now_sec: u64,
-        token_amount: u64,
-        icp_amount: u64,
-        random: u64,
-    ) -> Result<types::LuckyDrawLog, String> {
-        let log = LuckyDrawLog(user, now_sec, token_amount, icp_amount, random);
-        let idx = LUCKYDRAW_LOGS
-            .with(|r| r.borrow_mut().append(&log))
-            .map_err(|err| format!("failed to append luckydraw log, error {:?}", err))?;
-        Ok(types::LuckyDrawLog::from((idx, log)))
+    // get an accepted payment token, ICP is always accepted.
+    pub fn get(ledger: &Principal) -> Option<types::PaymentToken> {
+        if ledger == &ICP_CANISTER {
+            return Some(types::PaymentToken::icp());
+        }
+        PAYMENT_TOKEN.with(|r| r.borrow().get(ledger))
     }
 
-    // get luckydraw logs in reverse order, return the next index to fetch.
-    pub fn logs(
-        prev: Option<u64>,
-        take: usize,
-        user: Option<Principal>,
-    ) -> Vec<types::LuckyDrawLog> {
-        LUCKYDRAW_LOGS.with(|r| {
-            let log_store = r.borrow();
-            let latest = log_store.len();
-            if latest == 0 {
-                return vec![];
-            }
+    pub fn list() -> Vec<(Principal, types::PaymentToken)> {
+        let mut tokens = vec![(ICP_CANISTER, types::PaymentToken::icp())];
+        PAYMENT_TOKEN.with(|r| tokens.extend(r.borrow().iter()));
+        tokens
+    }
 
-            let prev = prev.unwrap_or(latest);
-            if prev > latest || prev == 0 {
-                return vec![];
-            }
+    pub fn set(ledger: Principal, token: types::PaymentToken) {
+        PAYMENT_TOKEN.with(|r| r.borrow_mut().insert(ledger, token));
+    }
 
-            let mut idx = prev - 1;
-            let mut max_loop = 1000;
-            let mut logs: Vec<types::LuckyDrawLog> = Vec::with_capacity(take);
-            while let Some(log) = log_store.get(idx) {
-                match user {
-                    Some(ref id) => {
-                        if &log.0 == id {
-                            logs.push(types::LuckyDrawLog::from((idx, log)));
-                        }
-                    }
-                    None => {
-                        logs.push(types::LuckyDrawLog::from((idx, log)));
-                    }
-                }
+    pub fn remove(ledger: &Principal) -> Option<types::PaymentToken> {
+        PAYMENT_TOKEN.with(|r| r.borrow_mut().remove(ledger))
+    }
+}
 
-                if idx == 0 || max_loop == 0 || logs.len() >= take {
-                    break;
-                }
-                idx -= 1;
-                max_loop -= 1;
-            }
+pub mod server_seed {
+    use super::*;
+
+    // initialize the first server seed if not exists.
+    pub async fn init() {
+        if SERVER_SEED.with(|r| r.borrow().is_empty()) {
+            rotate().await;
+        }
+    }
+
+    // rotate to a new server seed, the previous one will be revealed.
+    pub async fn rotate() {
+        let rr = match ic_cdk::api::management_canister::main::raw_rand().await {
+            Ok(rr) => rr,
+            Err(_) => return,
+        };
+        let seed = mac_256(&rr.0, b"SERVER_SEED");
+        let now_sec = ic_cdk::api::time() / SECOND;
+        SERVER_SEED.with(|r| {
+            let mut m = r.borrow_mut();
+            let epoch = m.last_key_value().map(|(k, _)| k + 1).unwrap_or(0);
+            m.insert(epoch, ServerSeed(ByteBuf::from(seed.to_vec()), now_sec, 0));
+        });
+    }
+
+    // take the next nonce of the active server seed, return (epoch, seed, nonce).
+    pub fn next_nonce() -> Option<(u32, ByteBuf, u64)> {
+        SERVER_SEED.with(|r| {
+            let mut m = r.borrow_mut();
+            let (epoch, mut seed) = m.last_key_value()?;
+            let nonce = seed.2;
+            seed.2 += 1;
+            m.insert(epoch, seed.clone());
+            Some((epoch, seed.0, nonce))
+        })
+    }
 
-            logs
+    // get the server seed by epoch, the active one if epoch is None.
+    // The seed is revealed only if it is not active.
+    pub fn get(epoch: Option<u32>) -> Option<(types::LuckyDrawSeed, Option<ByteBuf>)> {
+        SERVER_SEED.with(|r| {
+            let m = r.borrow();
+            let (active, _) = m.last_key_value()?;
+            let epoch = epoch.unwrap_or(active);
+            let seed = m.get(&epoch)?;
+            let reveal = epoch < active;
+            Some((
+                seed.to_output(epoch, reveal),
+                if reveal { Some(seed.0) } else { None },
+            ))
         })
     }
 }
@@ -616,13 +2829,17 @@ pub mod prize {
 
     use super::*;
 
+    // add a prize, args.claimable is 0 for airdrop.
+    // The depositor is the user who funded the prize by a PANDA deposit.
     pub fn try_add(
         issuer: u32,
         now_sec: u64,
-        expire: u16,
-        claimable: u32, // 0 for airdrop
-        quantity: u16,
+        args: &types::AddPrizeInput,
+        issuer_funded: bool,
+        depositor: Option<Principal>,
     ) -> Option<String> {
+        let (expire, claimable, quantity) = (args.expire, args.claimable, args.quantity);
+        let distribution = args.distribution.clone().unwrap_or_default();
         if quantity == 0 {
             return None;
         }
@@ -631,69 +2848,349 @@ pub mod prize {
         } else {
             *keys::AIRDROP_KEY
         };
-        let prize = Prize(issuer, (now_sec / 60) as u32, expire, claimable, quantity);
+        let mut prize = Prize(
+            issuer,
+            (now_sec / 60) as u32,
+            expire,
+            claimable,
+            quantity,
+            0,
+        );
+        let mut prizes = ISSUER_PRIZE.with(|r| r.borrow().get(&issuer).unwrap_or_default());
+        // the identical prizes issued in the same minute get the next free nonce
+        while PRIZE.with(|r| r.borrow().contains_key(&prize))
+            || prizes.0.contains_key(&prize.issuer_key())
+            || prizes.1.contains_key(&prize.issuer_key())
+        {
+            prize.5 = prize.5.checked_add(1)?;
+        }
         let ok = PRIZE.with(|r| {
             let mut m = r.borrow_mut();
             if m.contains_key(&prize) {
                 return false;
             }
-            m.insert(prize.clone(), Principals(BTreeSet::new()));
+            m.insert(prize.clone(), PrizeClaims::default());
             true
         });
         if ok {
+            ISSUER_PRIZE.with(|r| {
+                prizes.0.insert(prize.issuer_key(), 0);
+                certified::set_prizes(issuer, &prizes);
+                r.borrow_mut().insert(issuer, prizes);
+            });
+            if claimable > 0 {
+                let budget = claimable as u64 * TOKEN_1;
+                PRIZE_INFO.with(|r| {
+                    r.borrow_mut().insert(
+                        prize.clone(),
+                        PrizeInfo(
+                            issuer_funded,
+                            budget,
+                            0,
+                            distribution.clone(),
+                            None,
+                            depositor,
+                        ),
+                    )
+                });
+                if let Some(ref conditions) = args.conditions {
+                    PRIZE_CONDITIONS.with(|r| {
+                        r.borrow_mut()
+                            .insert(prize.clone(), conditions.clone().into())
+                    });
+                }
+            }
+            Some(PrizeCryptogram::new(prize, distribution).encode(&key, None))
+        } else {
+            None
+        }
+    }
+
+    // the conditions to claim a prize.
+    pub fn conditions_of(prize: &Prize) -> PrizeConditions {
+        PRIZE_CONDITIONS
+            .with(|r| r.borrow().get(prize))
+            .unwrap_or_default()
+    }
+
+    // check if a user has claimed any prize.
+    pub fn has_claimed(user: &Principal) -> bool {
+        PRIZE_CLAIMERS.with(|r| r.borrow().contains_key(user))
+    }
+
+    // the next airdrop log id to scan for the claimers of the prizes claimed before
+    // PRIZE_CLAIMERS, None if all logs have been scanned.
+    pub fn claimers_backfill_next() -> Option<u64> {
+        match state::with(|r| r.prize_claimers_backfilled) {
+            Some(u64::MAX) => None,
+            next => Some(next.unwrap_or_default()),
+        }
+    }
+
+    // record the claimers of the prize claim logs in [start, end) in PRIZE_CLAIMERS,
+    // return true if all logs have been scanned.
+    pub fn backfill_claimers(start: u64, end: u64, logs: &[types::AirdropLog]) -> bool {
+        PRIZE_CLAIMERS.with(|r| {
+            let mut m = r.borrow_mut();
+            for log in logs {
+                if log.kind == types::AirdropLogKind::PrizeClaim
+                    && log.credited > 0u64
+                    && !m.contains_key(&log.caller)
+                {
+                    m.insert(log.caller, log.ts);
+                }
+            }
+        });
+        let done = end.max(start) >= airdrop::next_log_id();
+        state::with_mut(|r| {
+            r.prize_claimers_backfilled = Some(if done { u64::MAX } else { end.max(start) })
+        });
+        done
+    }
+
+    pub fn claim(user: Principal, prize: Prize, now_sec: u64) -> Result<u64, String> {
+        let info = PRIZE_INFO.with(|r| r.borrow().get(&prize));
+        let amount = PRIZE.with(|r| {
+            let mut m = r.borrow_mut();
+            match m.get(&prize) {
+                Some(mut claims) => {
+                    if claims.0.len() >= prize.4 as usize {
+                        return Err("prize has been claimed".to_string());
+                    }
+                    if claims.0.contains_key(&user) {
+                        return Err("prize already claimed".to_string());
+                    }
+                    let amount = match info {
+                        Some(ref info) => info.share(&prize, &user, claims.0.len() as u16),
+                        None => prize.claim_amount(),
+                    };
+                    claims.0.insert(user, (amount, now_sec));
+                    m.insert(prize.clone(), claims);
+                    Ok(amount)
+                }
+                None => Err("prize not found".to_string()),
+            }
+        })?;
+        if let Some(mut info) = info {
+            info.2 = info.2.saturating_add(amount);
+            if info.3 != types::PrizeDistribution::Equal
+                && info.4.is_none_or(|(_, luckiest)| amount > luckiest)
+            {
+                info.4 = Some((user, amount));
+            }
+            PRIZE_INFO.with(|r| r.borrow_mut().insert(prize.clone(), info));
+        }
+        if prize.3 > 0 {
+            PRIZE_CLAIMERS.with(|r| {
+                let mut m = r.borrow_mut();
+                if !m.contains_key(&user) {
+                    m.insert(user, now_sec);
+                }
+            });
+        }
+        ISSUER_PRIZE.with(|r| {
+            let mut m = r.borrow_mut();
+            let mut prizes = m.get(&prize.0).unwrap_or_default();
+            prizes
+                .0
+                .entry(prize.issuer_key())
+                .and_modify(|curr| *curr += 1)
+                .or_insert(1);
+            certified::set_prizes(prize.0, &prizes);
+            m.insert(prize.0, prizes);
+        });
+        state::release_prize(amount);
+        Ok(amount)
+    }
+
+    // cancel an open prize by its issuer, the unclaimed budget is returned.
+    pub fn cancel(issuer: u32, prize: Prize, now_sec: u64) -> Result<u64, String> {
+        if prize.0 != issuer {
+            return Err("prize is not issued by the caller".to_string());
+        }
+        close(prize, true, now_sec)
+    }
+
+    // remove the expired prizes from PRIZE and return their unclaimed budgets.
+    // Return the number of swept prizes and the errors of the prizes that failed to be closed.
+    pub fn sweep_expired(now_sec: u64, take: usize) -> (usize, Vec<String>) {
+        let expired: Vec<Prize> = PRIZE.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(p, _)| !p.is_valid(now_sec))
+                .take(take)
+                .map(|(p, _)| p)
+                .collect()
+        });
+        let errors: Vec<String> = expired
+            .iter()
+            .filter_map(|prize| {
+                close(prize.clone(), false, now_sec)
+                    .err()
+                    .map(|err| format!("prize {}: {}", prize.id(), err))
+            })
+            .collect();
+        (expired.len() - errors.len(), errors)
+    }
+
+    // the unclaimed budgets of the open prizes.
+    pub fn unclaimed_budget(now_sec: u64) -> u64 {
+        PRIZE.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(p, _)| p.3 > 0 && p.4 > 0 && p.is_valid(now_sec))
+                .fold(0u64, |acc, (p, claims)| {
+                    let info = PRIZE_INFO.with(|r| r.borrow().get(&p));
+                    acc.saturating_add(unclaimed(&p, &claims, info.as_ref()))
+                })
+        })
+    }
+
+    fn unclaimed(prize: &Prize, claims: &PrizeClaims, info: Option<&PrizeInfo>) -> u64 {
+        match info {
+            Some(info) => info.1.saturating_sub(info.2),
+            None if prize.3 > 0 && prize.4 > 0 => {
+                let unclaimed = (prize.4 as u64).saturating_sub(claims.0.len() as u64);
+                prize.claim_amount() * unclaimed
+            }
+            None => 0,
+        }
+    }
+
+    // remove a prize from PRIZE and return its unclaimed budget to the issuer or the pool.
+    // The budget of a deposit funded prize is owed to the depositor and transferred back
+    // on the ledger by the refund timer. PrizeInfo is kept for the luckiest claimer.
+    // The fallible lookups are done before any change, a failed refund traps to roll back.
+    fn close(prize: Prize, cancelled: bool, now_sec: u64) -> Result<u64, String> {
+        let claims = PRIZE
+            .with(|r| r.borrow().get(&prize))
+            .ok_or("prize not found or closed".to_string())?;
+        let info = PRIZE_INFO.with(|r| r.borrow().get(&prize));
+        let refund = unclaimed(&prize, &claims, info.as_ref());
+        let depositor = info.as_ref().and_then(|info| info.5).filter(|_| refund > 0);
+        let issuer = if refund > 0 && depositor.is_none() && info.is_some_and(|info| info.0) {
+            Some(
+                LUCKYCODE
+                    .with(|r| r.borrow().get(&prize.0))
+                    .ok_or("issuer not found".to_string())?,
+            )
+        } else {
+            None
+        };
+
+        PRIZE.with(|r| r.borrow_mut().remove(&prize));
+        PRIZE_CONDITIONS.with(|r| r.borrow_mut().remove(&prize));
+        CLOSED_PRIZE.with(|r| r.borrow_mut().insert(prize.clone(), claims));
+        state::release_prize(refund);
+        if let Some(issuer) = issuer {
+            airdrop::refund_prize(issuer, now_sec, refund).unwrap_or_else(|err| ic_cdk::trap(&err));
+        }
+        if let Some(depositor) = depositor {
+            owe_refund(depositor, refund, now_sec);
+        }
+        if cancelled || refund > 0 {
             ISSUER_PRIZE.with(|r| {
                 let mut m = r.borrow_mut();
-                let mut prizes = m
-                    .get(&issuer)
-                    .unwrap_or_else(|| IssuerPrizes(BTreeMap::new()));
-                prizes.0.insert((prize.1, prize.2, prize.3, prize.4), 0);
-                m.insert(issuer, prizes);
+                let mut prizes = m.get(&prize.0).unwrap_or_default();
+                prizes.1.insert(prize.issuer_key(), (cancelled, refund));
+                certified::set_prizes(prize.0, &prizes);
+                m.insert(prize.0, prizes);
             });
-            Some(prize.encode(&key, None))
-        } else {
-            None
         }
+        Ok(refund)
     }
 
-    pub fn claim(user: Principal, prize: Prize) -> Result<u64, String> {
-        PRIZE.with(|r| {
+    // owe a PANDA deposit back to the depositor, the tokens are moved out of the airdrop balance
+    // and reserved until the refund is transferred. Return the operation id of the refund.
+    pub fn owe_refund(depositor: Principal, amount: u64, now_sec: u64) -> u64 {
+        let id = state::next_operation_id();
+        PRIZE_REFUNDS.with(|r| {
+            r.borrow_mut()
+                .insert(id, PrizeRefund(depositor, amount, now_sec))
+        });
+        state::with_mut(|r| r.airdrop_balance = r.airdrop_balance.saturating_sub(amount));
+        id
+    }
+
+    // the pending refunds of the deposit funded prizes, the oldest first.
+    pub fn pending_refunds(take: usize) -> Vec<(u64, PrizeRefund)> {
+        PRIZE_REFUNDS.with(|r| r.borrow().iter().take(take).collect())
+    }
+
+    // the tokens owed to the depositors, they are reserved from the airdrop balance.
+    pub fn refunds_owed() -> u64 {
+        PRIZE_REFUNDS.with(|r| {
+            r.borrow()
+                .iter()
+                .fold(0u64, |acc, (_, refund)| acc.saturating_add(refund.1))
+        })
+    }
+
+    // update a pending refund to be retried with a new created_at, or remove it when transferred.
+    pub fn update_refund(id: u64, created_at: Option<u64>) {
+        PRIZE_REFUNDS.with(|r| {
             let mut m = r.borrow_mut();
-            match m.get(&prize) {
-                Some(mut users) => {
-                    if users.0.len() >= prize.4 as usize {
-                        return Err("prize has been claimed".to_string());
-                    }
-                    if users.0.contains(&user) {
-                        return Err("prize already claimed".to_string());
-                    }
-                    users.0.insert(user);
-                    m.insert(prize.clone(), users);
-                    Ok(())
+            match (m.get(&id), created_at) {
+                (Some(refund), Some(created_at)) => {
+                    m.insert(id, PrizeRefund(refund.0, refund.1, created_at));
+                }
+                _ => {
+                    m.remove(&id);
                 }
-                None => Err("prize not found".to_string()),
             }
-        })?;
-        ISSUER_PRIZE.with(|r| {
+        });
+    }
+
+    // the closed prizes are kept for 30 days after they expired.
+    const CLOSED_PRIZE_RETENTION: u64 = 3600 * 24 * 30;
+
+    // remove the closed prizes past the retention, at most `take` closed prizes are scanned in
+    // a call, continuing from the last scanned one. Return the number of removed prizes.
+    pub fn prune_closed(now_sec: u64, take: usize) -> usize {
+        let after = CLOSED_PRIZE_CURSOR.with(|r| r.borrow().clone());
+        let scanned: Vec<Prize> = CLOSED_PRIZE.with(|r| {
+            let m = r.borrow();
+            match after {
+                Some(after) => m
+                    .range((ops::Bound::Excluded(after), ops::Bound::Unbounded))
+                    .take(take)
+                    .map(|(p, _)| p)
+                    .collect(),
+                None => m.iter().take(take).map(|(p, _)| p).collect(),
+            }
+        });
+        let cursor = if scanned.len() < take {
+            None
+        } else {
+            scanned.last().cloned()
+        };
+        let expired: Vec<Prize> = scanned
+            .into_iter()
+            .filter(|p| (p.1 as u64 + p.2 as u64) * 60 + CLOSED_PRIZE_RETENTION < now_sec)
+            .collect();
+        CLOSED_PRIZE.with(|r| {
             let mut m = r.borrow_mut();
-            let mut prizes = m
-                .get(&prize.0)
-                .unwrap_or_else(|| IssuerPrizes(BTreeMap::new()));
-            prizes
-                .0
-                .entry((prize.1, prize.2, prize.3, prize.4))
-                .and_modify(|curr| *curr += 1)
-                .or_insert(1);
-            m.insert(prize.0, prizes);
+            for prize in &expired {
+                m.remove(prize);
+            }
         });
-        Ok(prize.3 as u64 * TOKEN_1 / prize.4 as u64)
+        CLOSED_PRIZE_CURSOR.with(|r| *r.borrow_mut() = cursor);
+        expired.len()
+    }
+
+    // the claims of an open or closed prize, the prizes closed before CLOSED_PRIZE or past
+    // the retention are not found.
+    pub fn claims_of(prize: &Prize, detail: bool) -> Option<types::PrizeClaimsOutput> {
+        let (claims, closed) = match PRIZE.with(|r| r.borrow().get(prize)) {
+            Some(claims) => (claims, false),
+            None => (CLOSED_PRIZE.with(|r| r.borrow().get(prize))?, true),
+        };
+        let info = PRIZE_INFO.with(|r| r.borrow().get(prize));
+        Some(claims.to_output(prize, info.as_ref(), closed, detail))
     }
 
     pub fn list(issuer: u32) -> IssuerPrizes {
-        ISSUER_PRIZE.with(|r| {
-            r.borrow()
-                .get(&issuer)
-                .unwrap_or(IssuerPrizes(BTreeMap::new()))
-        })
+        ISSUER_PRIZE.with(|r| r.borrow().get(&issuer).unwrap_or_default())
     }
 }
 
@@ -708,21 +3205,142 @@ pub mod user {
     pub fn deactive(user: Principal) {
         ACTIVE_USERS.with(|r| r.borrow_mut().remove(&user));
     }
+
+    // mark a harvest transfer as started, it should be paired with harvest_finished.
+    pub fn harvest_started() {
+        HARVESTS.with(|r| r.borrow_mut().0 += 1);
+    }
+
+    pub fn harvest_finished() {
+        HARVESTS.with(|r| r.borrow_mut().1 += 1);
+    }
+
+    // the number of harvests started and finished.
+    pub fn harvests() -> (u64, u64) {
+        HARVESTS.with(|r| *r.borrow())
+    }
 }
 
 pub mod state {
     use super::*;
 
-    pub fn is_manager(caller: &Principal) -> bool {
+    // has_role checks the scoped roles of the caller.
+    // The legacy managers have all roles until the roles are set.
+    pub fn has_role(caller: &Principal, role: types::Role) -> bool {
+        STATE_HEAP.with(|r| {
+            let s = r.borrow();
+            match s.roles {
+                Some(ref roles) => roles.get(caller).is_some_and(|rs| rs.contains(&role)),
+                None => s
+                    .managers
+                    .as_ref()
+                    .map(|ms| ms.contains(caller))
+                    .unwrap_or(false),
+            }
+        })
+    }
+
+    pub fn luckydraw_max_expected() -> u64 {
         STATE_HEAP.with(|r| {
             r.borrow()
-                .managers
-                .as_ref()
-                .map(|ms| ms.contains(caller))
-                .unwrap_or(false)
+                .luckydraw_max_expected
+                .unwrap_or_else(|| types::LuckyDrawTable::default().expected())
+        })
+    }
+
+    // take all tokens from the progressive jackpot pool.
+    pub fn take_jackpot() -> u64 {
+        with_mut(|r| r.jackpot_pool.take().unwrap_or_default())
+    }
+
+    pub fn add_jackpot(amount: u64) {
+        with_mut(|r| {
+            r.jackpot_pool = Some(r.jackpot_pool.unwrap_or_default().saturating_add(amount));
+        });
+    }
+
+    // track the tokens credited to the claimable balances.
+    pub fn credit_claimable(amount: u64) {
+        with_mut(|r| {
+            r.total_credited = Some(r.total_credited.unwrap_or_default().saturating_add(amount));
+            r.total_claimable = Some(r.total_claimable.unwrap_or_default().saturating_add(amount));
+        });
+    }
+
+    // track the tokens harvested from the claimable balances.
+    pub fn debit_claimable(amount: u64) {
+        with_mut(|r| {
+            r.total_harvested = Some(r.total_harvested.unwrap_or_default().saturating_add(amount));
+            r.total_claimable = Some(r.total_claimable.unwrap_or_default().saturating_sub(amount));
+        });
+    }
+
+    // reserve the budget of a prize funded by the pool.
+    pub fn reserve_prize(amount: u64) {
+        with_mut(|r| {
+            r.prize_reserved = Some(r.prize_reserved.unwrap_or_default().saturating_add(amount));
+        });
+    }
+
+    // release the reserved budget of a prize, when it is claimed or returned to the pool.
+    pub fn release_prize(amount: u64) {
+        with_mut(|r| {
+            r.prize_reserved = Some(r.prize_reserved.unwrap_or_default().saturating_sub(amount));
+        });
+    }
+
+    // the outstanding claimable tokens and the reserved prize budgets.
+    pub fn liability() -> u64 {
+        with(|r| {
+            r.total_claimable
+                .unwrap_or_default()
+                .saturating_add(r.prize_reserved.unwrap_or_default())
+        })
+    }
+
+    // check that the airdrop balance can honor the liability after crediting or reserving amount.
+    pub fn check_liability(amount: u64) -> Result<(), String> {
+        let liability = liability().saturating_add(amount);
+        with(|r| {
+            if liability > r.airdrop_balance {
+                Err("airdrop pool is insufficient for the claimable tokens".to_string())
+            } else {
+                Ok(())
+            }
         })
     }
 
+    // initialize the claimable totals from the airdrop states and the reserved prize budgets
+    // if they are not tracked yet, the tokens credited and harvested before are not counted.
+    pub fn init_claimable_totals() {
+        if with(|r| r.total_claimable.is_none()) {
+            let total = airdrop::total_claimable();
+            with_mut(|r| {
+                r.total_credited = Some(total);
+                r.total_harvested = Some(0);
+                r.total_claimable = Some(total);
+            });
+        }
+        if with(|r| r.prize_reserved.is_none()) {
+            let reserved = prize::unclaimed_budget(ic_cdk::api::time() / SECOND);
+            with_mut(|r| r.prize_reserved = Some(reserved));
+        }
+    }
+
+    // take the next operation id for the memo of a ledger operation, it is never reused.
+    // It starts after the log and journal ids used as operation ids before.
+    pub fn next_operation_id() -> u64 {
+        let id = STATE_HEAP
+            .with(|r| r.borrow().next_operation_id)
+            .unwrap_or_else(|| {
+                airdrop::next_log_id()
+                    .max(luckydraw::next_log_id())
+                    .max(luckydraw::journal_next_id())
+            });
+        STATE_HEAP.with(|r| r.borrow_mut().next_operation_id = Some(id + 1));
+        id
+    }
+
     pub fn airdrop_amount_balance() -> (u64, u64) {
         STATE_HEAP.with(|r| {
             let s = r.borrow();
@@ -735,8 +3353,11 @@ pub mod state {
         STATE_HEAP.with(|r| f(&r.borrow()))
     }
 
+    // the certified state summary is refreshed after every change.
     pub fn with_mut<R>(f: impl FnOnce(&mut State) -> R) -> R {
-        STATE_HEAP.with(|r| f(&mut r.borrow_mut()))
+        let res = STATE_HEAP.with(|r| f(&mut r.borrow_mut()));
+        certified::refresh_state();
+        res
     }
 
     pub fn load() {
@@ -762,18 +3383,983 @@ pub mod notification {
     use super::*;
 
     pub fn list() -> Vec<types::Notification> {
-        NOTIFICATIONS.with(|r| r.borrow().values().cloned().collect())
+        NOTIFICATIONS_STORE.with(|r| r.borrow().get().0.clone())
     }
 
-    pub fn add(arg: types::Notification) {
-        NOTIFICATIONS.with(|r| {
-            r.borrow_mut().insert(arg.id, arg);
+    // list the notifications active at now_sec for the user, without audience.
+    pub fn list_active(user: &Principal, now_sec: u64) -> Vec<types::Notification> {
+        let mut has_code: Option<bool> = None;
+        NOTIFICATIONS_STORE.with(|r| {
+            r.borrow()
+                .get()
+                .0
+                .iter()
+                .filter(|n| n.is_active(now_sec))
+                .filter(|n| match &n.audience {
+                    None | Some(types::NotificationAudience::All) => true,
+                    Some(types::NotificationAudience::LuckyCodeHolders) => *has_code
+                        .get_or_insert_with(|| airdrop::state_of(user).is_some_and(|s| s.0 != 0)),
+                    Some(types::NotificationAudience::Principals(users)) => users.contains(user),
+                })
+                .map(|n| types::Notification {
+                    audience: None,
+                    ..n.clone()
+                })
+                .collect()
+        })
+    }
+
+    // add or replace a notification by id, and drop the expired ones.
+    pub fn add(arg: types::Notification, now_sec: u64) {
+        update(|ns| {
+            ns.retain(|n| n.id != arg.id && !n.is_expired(now_sec));
+            ns.push(arg);
+            ns.sort_by_key(|n| n.id);
         });
     }
 
     pub fn remove(ids: Vec<u8>) {
-        NOTIFICATIONS.with(|r| {
-            r.borrow_mut().retain(|k, _| !ids.contains(k));
+        update(|ns| ns.retain(|n| !ids.contains(&n.id)));
+    }
+
+    fn update(f: impl FnOnce(&mut Vec<types::Notification>)) {
+        NOTIFICATIONS_STORE.with(|r| {
+            let mut ns = r.borrow().get().0.clone();
+            f(&mut ns);
+            r.borrow_mut()
+                .set(Notifications(ns))
+                .expect("failed to set NOTIFICATIONS_STORE data");
+        });
+    }
+}
+
+pub mod ban {
+    use super::*;
+
+    pub fn is_banned(user: &Principal) -> bool {
+        BANS.with(|r| r.borrow().contains_key(user))
+            || airdrop::state_of(user).is_some_and(|s| s.0 == 0)
+    }
+
+    pub fn get(user: &Principal) -> Option<BanState> {
+        BANS.with(|r| r.borrow().get(user))
+    }
+
+    // list the banned users in the registry, starting after `prev`.
+    pub fn list(prev: Option<Principal>, take: usize) -> Vec<types::BannedUser> {
+        BANS.with(|r| {
+            let m = r.borrow();
+            let iter: Box<dyn Iterator<Item = (Principal, BanState)>> = match prev {
+                None => Box::new(m.iter()),
+                Some(prev) => {
+                    Box::new(m.range((std::ops::Bound::Excluded(prev), std::ops::Bound::Unbounded)))
+                }
+            };
+            iter.take(take)
+                .map(|(user, state)| state.into_output(user))
+                .collect()
+        })
+    }
+
+    // record the users banned before the ban registry with their former lucky codes, in batches.
+    // Return true when all the lucky codes have been scanned.
+    pub fn backfill_legacy(take: usize) -> bool {
+        let start = match state::with(|r| r.legacy_bans_backfilled) {
+            Some(u64::MAX) => return true,
+            Some(next) => next as u32,
+            None => 0,
+        };
+        let codes: Vec<(u32, Principal)> =
+            LUCKYCODE.with(|r| r.borrow().range(start..).take(take).collect());
+        BANS.with(|r| {
+            let mut m = r.borrow_mut();
+            for (code, user) in &codes {
+                if !m.contains_key(user) && airdrop::state_of(user).is_some_and(|s| s.0 == 0) {
+                    m.insert(
+                        *user,
+                        BanState(
+                            *code,
+                            "banned before the ban registry".to_string(),
+                            Principal::anonymous(),
+                            0,
+                            0,
+                        ),
+                    );
+                }
+            }
+        });
+        let next = match codes.last() {
+            Some((code, _)) if codes.len() == take && *code < u32::MAX => *code as u64 + 1,
+            _ => u64::MAX,
+        };
+        state::with_mut(|r| r.legacy_bans_backfilled = Some(next));
+        next == u64::MAX
+    }
+
+    // the users whose bans expired at now_sec.
+    pub fn expired(now_sec: u64) -> Vec<Principal> {
+        BANS.with(|r| {
+            r.borrow()
+                .iter()
+                .filter(|(_, b)| b.4 > 0 && b.4 <= now_sec)
+                .map(|(user, _)| user)
+                .collect()
+        })
+    }
+}
+
+pub mod audit {
+    use super::*;
+
+    // append an audit log of a privileged call, return the log id.
+    pub fn append<A: Serialize, P: Serialize>(
+        caller: Principal,
+        method: &str,
+        args: &A,
+        prev_value: Option<&P>,
+        now_sec: u64,
+    ) -> u64 {
+        AUDIT_LOGS.with(|r| {
+            let logs = r.borrow_mut();
+            let phash = match logs.len() {
+                0 => [0u8; 32],
+                n => logs.get(n - 1).map(|log| log.hash()).unwrap_or_default(),
+            };
+            let log = AuditLog(
+                now_sec,
+                caller,
+                method.to_string(),
+                sha3_256(&lib_panda::to_cbor_bytes(args)),
+                prev_value.map(|v| ByteBuf::from(lib_panda::to_cbor_bytes(v))),
+                phash,
+            );
+            logs.append(&log).expect("failed to append AuditLog")
+        })
+    }
+
+    pub fn logs(prev: Option<u64>, take: usize) -> Vec<types::AuditLog> {
+        AUDIT_LOGS.with(|r| {
+            logs_before(&r.borrow(), 0, 0, prev, take)
+                .into_iter()
+                .map(|(id, log)| log.into_output(id))
+                .collect()
+        })
+    }
+}
+
+// save the heap state and keys into stable memory,
+// so that an upgrade does not depend on pre_upgrade to keep them.
+pub fn checkpoint(now_sec: u64) {
+    state::with_mut(|r| r.checkpointed_at = Some(now_sec));
+    state::save();
+    keys::save();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use lib_panda::Cryptogram;
+
+    fn prize_input(
+        expire: u16,
+        claimable: u32,
+        quantity: u16,
+        distribution: Option<types::PrizeDistribution>,
+        conditions: Option<types::PrizeConditions>,
+    ) -> types::AddPrizeInput {
+        types::AddPrizeInput {
+            expire,
+            claimable,
+            quantity,
+            distribution,
+            conditions,
+        }
+    }
+
+    #[test]
+    fn test_luckydraw_log_compatibility() {
+        let user = Principal::anonymous();
+        let mut buf = vec![];
+        into_writer(&(user, 1u64, 2u64, 3u64, 4u64), &mut buf).unwrap();
+        let log = LuckyDrawLog::from_bytes(Cow::Owned(buf));
+        assert_eq!(log.0, user);
+        assert_eq!(log.4, 4);
+        assert!(log.5.is_none());
+        assert!(log.6.is_none());
+
+        let log = LuckyDrawLog(
+            user,
+            1,
+            2,
+            3,
+            4,
+            Some(ProvablyFair(0, "seed".to_string(), 5, 1)),
+            Some(7),
+            None,
+            None,
+        );
+        let log = LuckyDrawLog::from_bytes(log.to_bytes());
+        let pf = log.5.unwrap();
+        assert_eq!(pf.2, 5);
+        assert_eq!(pf.3, 1);
+        assert_eq!(log.6, Some(7));
+    }
+
+    #[test]
+    fn test_airdrop_log_compatibility() {
+        let user = Principal::anonymous();
+        let mut buf = vec![];
+        into_writer(&(user, 1u64, 0u64, 1000001u32), &mut buf).unwrap();
+        let log = types::AirdropLog::from((0, AirdropLog::from_bytes(Cow::Owned(buf))));
+        assert_eq!(log.kind, types::AirdropLogKind::Claim);
+        assert_eq!(log.lucky_code, luckycode_to_string(1000001));
+
+        let mut buf = vec![];
+        into_writer(&(user, 1u64, 5u64, 0u32), &mut buf).unwrap();
+        let log = types::AirdropLog::from((1, AirdropLog::from_bytes(Cow::Owned(buf))));
+        assert_eq!(log.kind, types::AirdropLogKind::Harvest);
+        assert_eq!(log.debited, Nat::from(5u64));
+
+        let log = AirdropLog::new(user, 1, types::AirdropLogKind::PrizeClaim, 7, 0, 1000001);
+        let log = types::AirdropLog::from((2, AirdropLog::from_bytes(log.to_bytes())));
+        assert_eq!(log.kind, types::AirdropLogKind::PrizeClaim);
+        assert_eq!(log.credited, Nat::from(7u64));
+        assert_eq!(log.debited, Nat::from(0u64));
+    }
+
+    #[test]
+    fn test_luckydraw_logs_of() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        for i in 0..10u64 {
+            let user = if i % 3 == 0 { alice } else { bob };
+            luckydraw::insert(LuckyDrawLog(user, i, i, i, i, None, None, None, None)).unwrap();
+        }
+        let ids = |logs: Vec<types::LuckyDrawLog>| -> Vec<u64> {
+            logs.iter().map(|log| log.ts).collect()
+        };
+        assert_eq!(ids(luckydraw::logs_of(alice, None, 10)), vec![9, 6, 3, 0]);
+        assert_eq!(ids(luckydraw::logs_of(alice, None, 2)), vec![9, 6]);
+        assert_eq!(ids(luckydraw::logs_of(alice, Some(6), 10)), vec![3, 0]);
+        assert_eq!(ids(luckydraw::logs_of(bob, Some(5), 2)), vec![4, 2]);
+        assert!(luckydraw::logs_of(bob, Some(0), 10).is_empty());
+    }
+
+    #[test]
+    fn test_icrc3_blocks() {
+        let alice = Principal::from_slice(&[1]);
+        luckydraw::insert(LuckyDrawLog(alice, 1, 100, 10, 7, None, None, None, None)).unwrap();
+        luckydraw::insert(LuckyDrawLog(alice, 2, 200, 10, 8, None, None, None, None)).unwrap();
+        assert_eq!(icrc3::len(), 2);
+
+        let blocks = icrc3::blocks(0, 10);
+        assert_eq!(blocks.len(), 2);
+        let field = |block: &types::ICRC3Value, key: &str| match block {
+            types::ICRC3Value::Map(m) => m.get(key).cloned(),
+            _ => None,
+        };
+        assert_eq!(field(&blocks[0].1, "phash"), None);
+        assert_eq!(
+            field(&blocks[1].1, "phash"),
+            Some(types::ICRC3Value::Blob(ByteBuf::from(blocks[0].1.hash())))
+        );
+        assert_eq!(
+            field(&blocks[1].1, "btype"),
+            Some(types::ICRC3Value::Text(icrc3::BTYPE_DRAW.to_string()))
+        );
+        assert_eq!(icrc3::blocks(1, 10).len(), 1);
+        assert!(icrc3::blocks(2, 10).is_empty());
+        assert_eq!(icrc3::tip().map(|(_, idx)| idx), Some(1));
+        assert!(certified::has_tip());
+    }
+
+    #[test]
+    fn test_adjust_balance() {
+        let treasurer = Principal::from_slice(&[1]);
+        state::with_mut(|r| r.airdrop_balance = 100);
+        assert_eq!(airdrop::adjust_balance(treasurer, 1, 70).unwrap(), 100);
+        assert_eq!(airdrop::adjust_balance(treasurer, 2, 90).unwrap(), 70);
+        assert_eq!(state::with(|r| r.airdrop_balance), 90);
+
+        let logs = airdrop::logs_of(treasurer, None, 10);
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].kind, types::AirdropLogKind::ManualAdjustment);
+        assert_eq!(logs[0].credited, Nat::from(20u64));
+        assert_eq!(logs[1].debited, Nat::from(30u64));
+
+        let blocks = icrc3::blocks(0, 10);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|(_, block)| match block {
+            types::ICRC3Value::Map(m) =>
+                m.get("btype") == Some(&types::ICRC3Value::Text(icrc3::BTYPE_ADJUST.to_string())),
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_truncate_logs() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        for i in 0..10u64 {
+            let user = if i % 2 == 0 { alice } else { bob };
+            luckydraw::insert(LuckyDrawLog(user, i, i, i, i, None, None, None, None)).unwrap();
+        }
+        luckydraw::truncate_logs(6);
+        assert_eq!(luckydraw::first_log_id(), 6);
+        assert_eq!(luckydraw::next_log_id(), 10);
+        assert!(luckydraw::get(5).is_none());
+        assert_eq!(luckydraw::get(6).unwrap().1, 6);
+
+        let ids = |logs: Vec<types::LuckyDrawLog>| -> Vec<u64> {
+            logs.iter().map(|log| log.ts).collect()
+        };
+        assert_eq!(ids(luckydraw::logs(None, 10)), vec![9, 8, 7, 6]);
+        assert!(luckydraw::logs(Some(6), 10).is_empty());
+        assert_eq!(ids(luckydraw::logs_of(alice, None, 10)), vec![8, 6]);
+        assert_eq!(ids(luckydraw::logs_range(4, 8)), vec![6, 7]);
+
+        let log =
+            luckydraw::insert(LuckyDrawLog(bob, 10, 0, 0, 0, None, None, None, None)).unwrap();
+        assert_eq!(log.id, Nat::from(10u64));
+        assert_eq!(ids(luckydraw::logs_of(bob, None, 2)), vec![10, 9]);
+
+        let index_len = || LUCKYDRAW_LOG_USER_INDEX.with(|r| r.borrow().len());
+        assert_eq!(index_len(), 11);
+        assert!(!luckydraw::prune_index(4));
+        assert_eq!(index_len(), 7);
+        assert!(luckydraw::prune_index(4));
+        assert_eq!(index_len(), 5);
+
+        luckydraw::truncate_logs(8);
+        assert_eq!(luckydraw::log_base(), 0);
+        assert_eq!(luckydraw::first_log_id(), 8);
+        assert_eq!(luckydraw::next_log_id(), 11);
+        assert!(luckydraw::get(7).is_none());
+        assert_eq!(luckydraw::get(10).unwrap().1, 10);
+        assert_eq!(ids(luckydraw::logs(None, 10)), vec![10, 9, 8]);
+        assert_eq!(ids(luckydraw::logs_of(alice, None, 10)), vec![8]);
+        assert!(luckydraw::prune_index(10));
+        assert_eq!(index_len(), 3);
+
+        assert_eq!(luckydraw::compact_logs(2), Ok(false));
+        assert!(luckydraw::compacting());
+        luckydraw::insert(LuckyDrawLog(alice, 11, 0, 0, 0, None, None, None, None)).unwrap();
+        luckydraw::truncate_logs(10);
+        assert_eq!(luckydraw::first_log_id(), 8);
+        assert_eq!(ids(luckydraw::logs(None, 10)), vec![11, 10, 9, 8]);
+        assert_eq!(luckydraw::compact_logs(2), Ok(true));
+        assert!(!luckydraw::compacting());
+        assert_eq!(luckydraw::log_base(), 8);
+        assert_eq!(LUCKYDRAW_LOGS.with(|r| r.borrow().len()), 4);
+        assert_eq!(LUCKYDRAW_LOGS_SPARE.with(|r| r.borrow().len()), 0);
+        assert_eq!(luckydraw::next_log_id(), 12);
+        assert_eq!(luckydraw::get(8).unwrap().1, 8);
+        assert_eq!(ids(luckydraw::logs(None, 10)), vec![11, 10, 9, 8]);
+        assert_eq!(ids(luckydraw::logs_of(alice, None, 10)), vec![11, 8]);
+        let log =
+            luckydraw::insert(LuckyDrawLog(bob, 12, 0, 0, 0, None, None, None, None)).unwrap();
+        assert_eq!(log.id, Nat::from(12u64));
+        assert_eq!(ids(luckydraw::logs_of(bob, None, 10)), vec![12, 10, 9]);
+        assert_eq!(luckydraw::compact_logs(2), Ok(true));
+        assert_eq!(luckydraw::log_base(), 8);
+    }
+
+    #[test]
+    fn test_certified_witness() {
+        let alice = Principal::from_slice(&[1]);
+        let state = AirdropState(1000001, 0, 100 * TOKEN_1);
+        for i in 2..4u8 {
+            AIRDROP.with(|r| {
+                r.borrow_mut()
+                    .insert(Principal::from_slice(&[i]), AirdropState(0, 0, i as u64))
+            });
+        }
+        AIRDROP.with(|r| r.borrow_mut().insert(alice, state.clone()));
+        certified::init();
+        assert!(!certified::is_ready());
+        assert!(!certified::rebuild(2));
+        assert!(!certified::rebuild(2));
+        assert!(certified::rebuild(2));
+        assert!(certified::is_ready());
+        state::with_mut(|r| r.total_airdrop = 100);
+
+        let root = CERTIFIED_TREE.with(|r| r.borrow().tree(&certified::Reveal::default()).digest());
+        let reveal = certified::Reveal {
+            airdrop: Some(alice),
+            state: true,
+            ..Default::default()
+        };
+        let tree = CERTIFIED_TREE.with(|r| r.borrow().tree(&reveal));
+        assert_eq!(tree.digest(), root);
+
+        let hash = types::ICRC3Value::from(&types::AirdropStateOutput::from(&state)).hash();
+        assert_eq!(
+            tree.lookup_path([b"airdrop".as_slice(), alice.as_slice()]),
+            ic_certification::LookupResult::Found(hash.as_slice())
+        );
+        let summary = state::with(|r| types::StateSummary::from(r));
+        let hash = types::ICRC3Value::from(&summary).hash();
+        assert_eq!(
+            tree.lookup_path([b"state".as_slice()]),
+            ic_certification::LookupResult::Found(hash.as_slice())
+        );
+    }
+
+    #[test]
+    fn test_notifications() {
+        let user = Principal::from_slice(&[1; 29]);
+        let other = Principal::from_slice(&[2; 29]);
+        let n = |id: u8, start_at, end_at, audience| types::Notification {
+            id,
+            level: 0,
+            timeout: 0,
+            dismiss: true,
+            message: format!("message {}", id),
+            start_at,
+            end_at,
+            audience,
+            locales: None,
+        };
+
+        notification::add(n(1, None, None, None), 100);
+        notification::add(n(2, Some(200), Some(300), None), 100);
+        notification::add(n(3, None, Some(150), None), 100);
+        notification::add(
+            n(
+                4,
+                None,
+                None,
+                Some(types::NotificationAudience::Principals(BTreeSet::from([
+                    user,
+                ]))),
+            ),
+            100,
+        );
+        notification::add(
+            n(
+                5,
+                None,
+                None,
+                Some(types::NotificationAudience::LuckyCodeHolders),
+            ),
+            100,
+        );
+
+        let ids = |ns: Vec<types::Notification>| ns.iter().map(|n| n.id).collect::<Vec<_>>();
+        assert_eq!(ids(notification::list_active(&user, 100)), vec![1, 3, 4]);
+        assert_eq!(ids(notification::list_active(&other, 100)), vec![1, 3]);
+        assert_eq!(ids(notification::list_active(&user, 200)), vec![1, 2, 4]);
+        assert_eq!(ids(notification::list_active(&user, 300)), vec![1, 4]);
+        assert!(notification::list_active(&user, 100)
+            .iter()
+            .all(|n| n.audience.is_none()));
+
+        AIRDROP.with(|r| r.borrow_mut().insert(user, AirdropState(1, 0, 0)));
+        assert_eq!(ids(notification::list_active(&user, 100)), vec![1, 3, 4, 5]);
+
+        // expired notifications are dropped when a new one is added
+        notification::add(n(1, None, None, None), 200);
+        assert_eq!(ids(notification::list()), vec![1, 2, 4, 5]);
+        notification::remove(vec![2, 4]);
+        assert_eq!(ids(notification::list()), vec![1, 5]);
+    }
+
+    #[test]
+    fn test_roles() {
+        let manager = Principal::from_slice(&[1; 29]);
+        let moderator = Principal::from_slice(&[2; 29]);
+        state::with_mut(|r| r.managers = Some(BTreeSet::from([manager])));
+        assert!(state::has_role(&manager, types::Role::KeyAdmin));
+        assert!(!state::has_role(&moderator, types::Role::Moderator));
+
+        state::with_mut(|r| {
+            r.roles = Some(BTreeMap::from([(
+                moderator,
+                BTreeSet::from([types::Role::Moderator]),
+            )]))
         });
+        assert!(!state::has_role(&manager, types::Role::KeyAdmin));
+        assert!(state::has_role(&moderator, types::Role::Moderator));
+        assert!(!state::has_role(&moderator, types::Role::KeyAdmin));
+    }
+
+    #[test]
+    fn test_audit_logs() {
+        let caller = Principal::from_slice(&[1; 29]);
+        assert_eq!(audit::append(caller, "a", &1u64, Some(&0u64), 100), 0);
+        assert_eq!(
+            audit::append(caller, "b", &(2u64, "x"), None::<&()>, 101),
+            1
+        );
+        assert_eq!(audit::append(caller, "c", &3u64, Some(&2u64), 102), 2);
+
+        let logs = audit::logs(None, 10);
+        assert_eq!(logs.iter().map(|l| l.id).collect::<Vec<_>>(), vec![2, 1, 0]);
+        assert_eq!(logs[2].phash.as_slice(), &[0u8; 32]);
+        assert_eq!(logs[1].phash, logs[2].hash);
+        assert_eq!(logs[0].phash, logs[1].hash);
+        assert_eq!(logs[1].method, "b");
+        assert!(logs[1].prev_value.is_none());
+        assert_eq!(
+            logs[2].prev_value.as_ref().unwrap().as_slice(),
+            lib_panda::to_cbor_bytes(&0u64)
+        );
+
+        let logs = audit::logs(Some(1), 10);
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].id, 0);
+    }
+
+    #[test]
+    fn test_ban_users() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let moderator = Principal::from_slice(&[3; 29]);
+        let code = luckycode::new_from(alice);
+        airdrop::insert(alice, None, 100, 10, 0, code).unwrap();
+        airdrop::insert(bob, None, 100, 10, 0, luckycode::new_from(bob)).unwrap();
+
+        airdrop::ban_users(vec![alice], "bot".to_string(), moderator, Some(300), 200).unwrap();
+        assert!(ban::is_banned(&alice));
+        assert!(!ban::is_banned(&bob));
+        assert_eq!(airdrop::state_of(&alice).unwrap().0, 0);
+        let banned = ban::list(None, 10);
+        assert_eq!(banned.len(), 1);
+        assert_eq!(banned[0].user, alice);
+        assert_eq!(banned[0].lucky_code, luckycode_to_string(code));
+        assert_eq!(banned[0].reason, "bot");
+        assert_eq!(banned[0].moderator, moderator);
+        assert_eq!(banned[0].expire_at, Some(300));
+        assert!(ban::list(Some(alice), 10).is_empty());
+
+        // no referral bonus for a banned referrer
+        let carol = Principal::from_slice(&[4; 29]);
+        airdrop::insert(carol, Some(alice), 201, 15, 5, luckycode::new_from(carol)).unwrap();
+        assert_eq!(airdrop::state_of(&alice).unwrap().2, 10);
+
+        assert!(ban::expired(299).is_empty());
+        assert_eq!(ban::expired(300), vec![alice]);
+        assert!(airdrop::unban_users(vec![alice], 300).is_empty());
+        assert!(!ban::is_banned(&alice));
+        assert_eq!(airdrop::state_of(&alice).unwrap().0, code);
+        assert!(ban::list(None, 10).is_empty());
+
+        // a user banned before the ban registry
+        AIRDROP.with(|r| r.borrow_mut().insert(bob, AirdropState(0, 0, 10)));
+        // a failed user does not abort the others
+        let dave = Principal::from_slice(&[5; 29]);
+        AIRDROP.with(|r| r.borrow_mut().insert(dave, AirdropState(0, 0, 10)));
+        assert!(!ban::backfill_legacy(1));
+        assert!(ban::backfill_legacy(10));
+        assert!(ban::backfill_legacy(10));
+        assert!(ban::is_banned(&bob));
+        assert!(ban::get(&bob).is_some());
+        assert!(ban::get(&dave).is_none());
+        let failed = airdrop::unban_users(vec![dave, bob], 300);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, dave);
+        assert!(airdrop::state_of(&bob).unwrap().0 != 0);
+        assert_eq!(airdrop::state_of(&dave).unwrap().0, 0);
+    }
+
+    #[test]
+    fn test_claimable_totals() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        airdrop::insert(alice, None, 100, 10, 0, luckycode::new_from(alice)).unwrap();
+        airdrop::insert(bob, Some(alice), 100, 15, 5, luckycode::new_from(bob)).unwrap();
+        airdrop::prize(bob, 101, 7, 0).unwrap();
+        assert_eq!(state::with(|r| r.total_credited), Some(37));
+        assert_eq!(state::with(|r| r.total_claimable), Some(37));
+
+        airdrop::harvest(bob, 102, 20, None, None).unwrap();
+        assert_eq!(state::with(|r| r.total_harvested), Some(20));
+        assert_eq!(state::with(|r| r.total_claimable), Some(17));
+        assert_eq!(airdrop::total_claimable(), 17);
+
+        // the idempotency key is recorded with the debit
+        airdrop::harvest(bob, 103, 2, None, Some(103 * SECOND)).unwrap();
+        assert_eq!(airdrop::harvested(bob, 103 * SECOND), Some(2));
+        assert_eq!(airdrop::harvested(alice, 103 * SECOND), None);
+        airdrop::prune_harvest_keys(103 * SECOND, 10);
+        assert_eq!(airdrop::harvested(bob, 103 * SECOND), Some(2));
+        airdrop::prune_harvest_keys(103 * SECOND + 1, 10);
+        assert_eq!(airdrop::harvested(bob, 103 * SECOND), None);
+        assert_eq!(airdrop::total_claimable(), 15);
+
+        state::with_mut(|r| r.airdrop_balance = 27);
+        assert!(state::check_liability(12).is_ok());
+        assert!(state::check_liability(13).is_err());
+    }
+
+    #[test]
+    fn test_prize_lifecycle() {
+        let v1: BTreeMap<(u32, u16, u32, u16), u16> = BTreeMap::from([((1, 10, 100, 10), 2)]);
+        let mut buf = vec![];
+        into_writer(&v1, &mut buf).unwrap();
+        let decoded = IssuerPrizes::from_bytes(Cow::Owned(buf.clone()));
+        assert_eq!(
+            decoded.0,
+            BTreeMap::from([(IssuerPrizeKey(1, 10, 100, 10, 0), 2)])
+        );
+        assert!(decoded.1.is_empty());
+        // the keys without nonce keep their encoding
+        let mut buf2 = vec![];
+        into_writer(&decoded.0, &mut buf2).unwrap();
+        assert_eq!(buf2, buf);
+        let prize = Prize(1, 10, 100, 10, 2, 0);
+        assert_eq!(
+            prize.to_bytes().to_vec(),
+            lib_panda::to_cbor_bytes(&(1u32, 10u32, 100u16, 10u32, 2u16))
+        );
+        assert!(Prize::from_id(&prize.id()).unwrap() == prize);
+        let prize = Prize(1, 10, 100, 10, 2, 3);
+        assert!(Prize::from_id(&prize.id()).unwrap() == prize);
+
+        let alice = Principal::from_slice(&[1; 29]);
+        let issuer = 1000001u32;
+        let open = Prize(issuer, 1, 60, 100, 10, 0);
+        let expired = Prize(issuer, 0, 1, 50, 5, 0);
+        for p in [&open, &expired] {
+            PRIZE.with(|r| r.borrow_mut().insert(p.clone(), PrizeClaims::default()));
+        }
+        state::reserve_prize(150 * TOKEN_1);
+        assert_eq!(prize::unclaimed_budget(120), 100 * TOKEN_1);
+
+        assert_eq!(
+            prize::claim(alice, expired.clone(), 120).unwrap(),
+            10 * TOKEN_1
+        );
+        assert_eq!(state::with(|r| r.prize_reserved), Some(140 * TOKEN_1));
+
+        assert_eq!(prize::sweep_expired(120, 10), (1, vec![]));
+        assert_eq!(state::with(|r| r.prize_reserved), Some(100 * TOKEN_1));
+        assert!(prize::claim(alice, expired, 120).is_err());
+
+        assert!(prize::cancel(issuer + 1, open.clone(), 120).is_err());
+        assert_eq!(
+            prize::cancel(issuer, open.clone(), 120).unwrap(),
+            100 * TOKEN_1
+        );
+        assert_eq!(state::with(|r| r.prize_reserved), Some(0));
+        assert!(prize::cancel(issuer, open, 120).is_err());
+
+        let prizes = prize::list(issuer);
+        assert_eq!(prizes.0.get(&IssuerPrizeKey(0, 1, 50, 5, 0)), Some(&1));
+        assert_eq!(
+            prizes.1.get(&IssuerPrizeKey(0, 1, 50, 5, 0)),
+            Some(&(false, 40 * TOKEN_1))
+        );
+        assert_eq!(
+            prizes.1.get(&IssuerPrizeKey(1, 60, 100, 10, 0)),
+            Some(&(true, 100 * TOKEN_1))
+        );
+    }
+
+    #[test]
+    fn test_user_funded_prize() {
+        let issuer = Principal::from_slice(&[1; 29]);
+        let alice = Principal::from_slice(&[2; 29]);
+        let code = luckycode::new_from(issuer);
+        airdrop::insert(issuer, None, 100, 100 * TOKEN_1, 0, code).unwrap();
+        airdrop::insert(alice, None, 100, TOKEN_1, 0, luckycode::new_from(alice)).unwrap();
+
+        assert!(
+            prize::try_add(code, 120, &prize_input(10, 30, 3, None, None), true, None).is_some()
+        );
+        assert!(airdrop::lock_prize(issuer, 120, 300 * TOKEN_1).is_err());
+        airdrop::lock_prize(issuer, 120, 30 * TOKEN_1).unwrap();
+        assert_eq!(airdrop::state_of(&issuer).unwrap().2, 70 * TOKEN_1);
+        assert_eq!(state::with(|r| r.total_claimable), Some(71 * TOKEN_1));
+        assert_eq!(state::with(|r| r.prize_reserved), Some(30 * TOKEN_1));
+
+        let p = Prize(code, 2, 10, 30, 3, 0);
+        assert_eq!(prize::claim(alice, p.clone(), 120).unwrap(), 10 * TOKEN_1);
+        airdrop::prize(alice, 121, 10 * TOKEN_1, code).unwrap();
+        assert_eq!(state::with(|r| r.prize_reserved), Some(20 * TOKEN_1));
+
+        // a failed closing changes nothing
+        let lucky_code = LUCKYCODE.with(|r| r.borrow_mut().remove(&code)).unwrap();
+        assert!(prize::cancel(code, p.clone(), 122).is_err());
+        assert!(PRIZE.with(|r| r.borrow().contains_key(&p)));
+        assert_eq!(state::with(|r| r.prize_reserved), Some(20 * TOKEN_1));
+        LUCKYCODE.with(|r| r.borrow_mut().insert(code, lucky_code));
+
+        assert_eq!(prize::cancel(code, p, 122).unwrap(), 20 * TOKEN_1);
+        assert_eq!(airdrop::state_of(&issuer).unwrap().2, 90 * TOKEN_1);
+        assert_eq!(state::with(|r| r.total_claimable), Some(101 * TOKEN_1));
+        assert_eq!(state::with(|r| r.prize_reserved), Some(0));
+        assert_eq!(
+            airdrop::logs_of(issuer, None, 1)[0].kind,
+            types::AirdropLogKind::PrizeRefund
+        );
+
+        // an identical deposit funded prize in the same minute gets a nonce,
+        // its unclaimed budget is owed to the depositor on the ledger
+        state::with_mut(|r| r.airdrop_balance = 100 * TOKEN_1);
+        let args = prize_input(10, 30, 3, None, None);
+        let c1 = prize::try_add(code, 180, &args, true, Some(issuer)).unwrap();
+        let c2 = prize::try_add(code, 180, &args, true, Some(issuer)).unwrap();
+        assert!(c1 != c2);
+        state::reserve_prize(60 * TOKEN_1);
+        let p1 = Prize(code, 3, 10, 30, 3, 0);
+        let p2 = Prize(code, 3, 10, 30, 3, 1);
+        assert!(prize::list(code).0.contains_key(&p2.issuer_key()));
+        assert_eq!(prize::cancel(code, p2, 181).unwrap(), 30 * TOKEN_1);
+        assert_eq!(airdrop::state_of(&issuer).unwrap().2, 90 * TOKEN_1);
+        assert_eq!(state::with(|r| r.airdrop_balance), 70 * TOKEN_1);
+        assert_eq!(prize::refunds_owed(), 30 * TOKEN_1);
+        let refunds = prize::pending_refunds(10);
+        assert_eq!(refunds.len(), 1);
+        assert!(refunds[0].1 .0 == issuer);
+        prize::update_refund(refunds[0].0, Some(200));
+        assert_eq!(prize::pending_refunds(10)[0].1 .2, 200);
+        prize::update_refund(refunds[0].0, None);
+        assert_eq!(prize::refunds_owed(), 0);
+        assert!(PRIZE.with(|r| r.borrow().contains_key(&p1)));
+    }
+
+    #[test]
+    fn test_prize_distribution() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let v1 = BTreeSet::from([alice]);
+        let mut buf = vec![];
+        into_writer(&v1, &mut buf).unwrap();
+        let claims = PrizeClaims::from_bytes(Cow::Owned(buf));
+        assert_eq!(claims.0.get(&alice), Some(&(0, 0)));
+
+        let key = [8u8; 32];
+        let p = Prize(1, 2, 10, 30, 3, 0);
+        let v1 = p.encode(&key, None);
+        assert!(PrizeCryptogram::decode(&key, None, &v1).unwrap().prize() == p);
+        let v2 =
+            PrizeCryptogram::new(p.clone(), types::PrizeDistribution::Random).encode(&key, None);
+        assert!(Prize::decode(&key, None, &v2).is_err());
+        match PrizeCryptogram::decode(&key, None, &v2).unwrap() {
+            PrizeCryptogram::V2(prize, types::PrizeDistribution::Random) => assert!(prize == p),
+            _ => panic!("expected V2 cryptogram"),
+        }
+
+        let issuer = 1000001u32;
+        let users: Vec<Principal> = (1..=5u8).map(|i| Principal::from_slice(&[i; 29])).collect();
+        let args = prize_input(10, 100, 5, Some(types::PrizeDistribution::Random), None);
+        assert!(prize::try_add(issuer, 60, &args, false, None).is_some());
+        let random = Prize(issuer, 1, 10, 100, 5, 0);
+        let amounts: Vec<u64> = users
+            .iter()
+            .map(|u| prize::claim(*u, random.clone(), 120).unwrap())
+            .collect();
+        assert!(amounts.iter().all(|a| *a > 0));
+        assert_eq!(amounts.iter().sum::<u64>(), 100 * TOKEN_1);
+
+        let tiers = types::PrizeDistribution::Tiers(vec![(1, 5), (2, 1)]);
+        assert!(tiers.validate(4).is_err());
+        let args = prize_input(10, 70, 3, Some(tiers), None);
+        assert!(prize::try_add(issuer, 120, &args, false, None).is_some());
+        let tiered = Prize(issuer, 2, 10, 70, 3, 0);
+        assert_eq!(
+            prize::claim(users[0], tiered.clone(), 120).unwrap(),
+            50 * TOKEN_1
+        );
+        assert_eq!(
+            prize::claim(users[1], tiered.clone(), 120).unwrap(),
+            10 * TOKEN_1
+        );
+
+        let output = prize::list(issuer).to_output(issuer);
+        let max = amounts.iter().max().unwrap();
+        let luckiest = users[amounts.iter().position(|a| a == max).unwrap()];
+        assert_eq!(output[0].7, Some((luckiest, *max)));
+        assert_eq!(output[1].7, Some((users[0], 50 * TOKEN_1)));
+        assert_eq!(prize::cancel(issuer, tiered, 130).unwrap(), 10 * TOKEN_1);
+        assert_eq!(
+            prize::list(issuer).to_output(issuer)[1].7,
+            Some((users[0], 50 * TOKEN_1))
+        );
+    }
+
+    #[test]
+    fn test_prize_conditions() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let bob = Principal::from_slice(&[2; 29]);
+        let bob_code = luckycode::new_from(bob);
+        airdrop::insert(bob, None, 100, TOKEN_1, 0, bob_code).unwrap();
+
+        let invalid = types::PrizeConditions {
+            lucky_codes: Some(BTreeSet::from(["invalid code".to_string()])),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+
+        let issuer = 1000001u32;
+        let conditions = types::PrizeConditions {
+            min_balance: Some(0),
+            lucky_codes: Some(BTreeSet::from([luckycode_to_string(bob_code)])),
+            xauth: true,
+            new_user: true,
+            ..Default::default()
+        };
+        let args = prize_input(10, 20, 2, None, Some(conditions));
+        assert!(prize::try_add(issuer, 60, &args, false, None).is_some());
+        let p = Prize(issuer, 1, 10, 20, 2, 0);
+        let c = prize::conditions_of(&p);
+        assert_eq!(c.0, 0);
+        assert_eq!(
+            c.check(&alice, 1).unwrap_err(),
+            "you are not in the allowlist of the prize"
+        );
+        assert_eq!(
+            c.check(&bob, bob_code).unwrap_err(),
+            "the prize requires a verified XAuth identity"
+        );
+        assert!(xauth::try_set("bob".to_string(), bob, 100));
+        assert_eq!(
+            c.check(&bob, bob_code).unwrap_err(),
+            "the prize is not open yet, try again later"
+        );
+
+        // the logs of the prizes claimed before PRIZE_CLAIMERS
+        airdrop::prize(bob, 110, 10 * TOKEN_1, 0).unwrap();
+        assert!(!prize::has_claimed(&bob));
+        assert_eq!(
+            c.check(&bob, bob_code).unwrap_err(),
+            "the prize is not open yet, try again later"
+        );
+        let next = airdrop::next_log_id();
+        assert!(!prize::backfill_claimers(
+            0,
+            next - 1,
+            &airdrop::logs_range(0, next - 1)
+        ));
+        assert!(!prize::has_claimed(&bob));
+        assert_eq!(prize::claimers_backfill_next(), Some(next - 1));
+        assert!(prize::backfill_claimers(
+            next - 1,
+            next,
+            &airdrop::logs_range(next - 1, next)
+        ));
+        assert!(prize::has_claimed(&bob));
+        assert_eq!(prize::claimers_backfill_next(), None);
+        assert_eq!(
+            c.check(&bob, bob_code).unwrap_err(),
+            "the prize is only for first-time users"
+        );
+
+        assert_eq!(prize::claim(alice, p.clone(), 120).unwrap(), 10 * TOKEN_1);
+        assert!(prize::has_claimed(&alice));
+        assert_eq!(
+            prize::conditions_of(&Prize(issuer, 2, 10, 20, 2, 0)).0,
+            10 * TOKEN_1
+        );
+        prize::cancel(issuer, p.clone(), 130).unwrap();
+        assert_eq!(prize::conditions_of(&p).0, 10 * TOKEN_1);
+    }
+
+    #[test]
+    fn test_prize_claims() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let v2 = BTreeMap::from([(alice, 5 * TOKEN_1)]);
+        let mut buf = vec![];
+        into_writer(&v2, &mut buf).unwrap();
+        let claims = PrizeClaims::from_bytes(Cow::Owned(buf));
+        assert_eq!(claims.0.get(&alice), Some(&(5 * TOKEN_1, 0)));
+
+        let issuer = 1000001u32;
+        let p = Prize(issuer, 2, 10, 30, 3, 0);
+        assert!(Prize::from_id(&p.id()).unwrap() == p);
+        assert!(Prize::from_id("invalid").is_err());
+
+        let bob = Principal::from_slice(&[2; 29]);
+        assert!(prize::try_add(
+            issuer,
+            120,
+            &prize_input(10, 30, 3, None, None),
+            false,
+            None
+        )
+        .is_some());
+        assert!(prize::claims_of(&Prize(issuer, 3, 10, 30, 3, 0), true).is_none());
+        prize::claim(bob, p.clone(), 150).unwrap();
+        prize::claim(alice, p.clone(), 140).unwrap();
+        assert_eq!(prize::list(issuer).to_output(issuer)[0].6, p.id());
+
+        let output = prize::claims_of(&p, false).unwrap();
+        assert_eq!(output.id, p.id());
+        assert_eq!(output.filled, 2);
+        assert_eq!(output.claimed, Nat::from(20 * TOKEN_1));
+        assert!(!output.closed);
+        assert!(output.claims.is_none());
+
+        prize::cancel(issuer, p.clone(), 160).unwrap();
+        let output = prize::claims_of(&p, true).unwrap();
+        assert!(output.closed);
+        let claims = output.claims.unwrap();
+        assert_eq!(claims.len(), 2);
+        assert_eq!(claims[0].user, alice);
+        assert_eq!(claims[0].claimed_at, Some(140));
+        assert_eq!(claims[1].user, bob);
+        assert_eq!(claims[1].amount, Nat::from(10 * TOKEN_1));
+
+        // the closed prizes are removed after the retention
+        let expired_at = (p.1 as u64 + p.2 as u64) * 60;
+        assert_eq!(prize::prune_closed(expired_at + 3600 * 24 * 30, 10), 0);
+        assert_eq!(prize::prune_closed(expired_at + 3600 * 24 * 30 + 1, 10), 1);
+        assert!(prize::claims_of(&p, true).is_none());
+    }
+
+    #[test]
+    fn test_luckydraw_journal_unresolved() {
+        let alice = Principal::from_slice(&[1; 29]);
+        let entry = |ts: u64, stage: types::LuckyDrawStage| {
+            LuckyDrawJournal(
+                alice,
+                ts,
+                crate::ICP_1,
+                TOKEN_1,
+                0,
+                stage,
+                None,
+                0,
+                vec![],
+                None,
+                None,
+            )
+        };
+        let sent = luckydraw::journal_add(entry(100, types::LuckyDrawStage::PayoutSent));
+        luckydraw::journal_add(entry(100, types::LuckyDrawStage::RefundOwed));
+        luckydraw::journal_add(entry(300, types::LuckyDrawStage::PayoutSent));
+
+        // the payout sent before the resend window is only resolved by a manager
+        assert_eq!(luckydraw::journal_stale(200, 400, 10).len(), 2);
+        let unresolved = luckydraw::journal_unresolved(200);
+        assert_eq!(unresolved.len(), 1);
+        assert_eq!(unresolved[0].id, Nat::from(sent));
+    }
+
+    #[test]
+    fn test_next_operation_id() {
+        let alice = Principal::from_slice(&[1; 29]);
+        airdrop::insert(alice, None, 100, 10, 0, luckycode::new_from(alice)).unwrap();
+        // starts after the log ids used as operation ids before
+        assert_eq!(state::next_operation_id(), 1);
+        let entry = LuckyDrawJournal(
+            alice,
+            100,
+            0,
+            0,
+            0,
+            types::LuckyDrawStage::IcpReceived,
+            None,
+            0,
+            vec![],
+            None,
+            None,
+        );
+        let id = luckydraw::journal_add(entry.clone());
+        assert_eq!(id, 2);
+        luckydraw::journal_remove(id);
+        // a removed journal id is not reused
+        assert_eq!(luckydraw::journal_add(entry), 3);
+        assert_eq!(state::next_operation_id(), 4);
     }
 }
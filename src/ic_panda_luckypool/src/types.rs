@@ -1,7 +1,17 @@
 use base64::{engine::general_purpose, Engine};
-use candid::{CandidType, Nat, Principal};
+use candid::{CandidType, Int, Nat, Principal};
+use icrc_ledger_types::{
+    icrc::generic_value::Value, icrc1::account::Account, icrc3::archive::QueryArchiveFn,
+};
 use lib_panda::Challenge;
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{utils::luckycode_from_string, ICP_1, TOKEN_1, TRANS_FEE};
+
+// 344693032001 from b"PANDA"
+pub const LUCKYDRAW_DIVISOR: u64 = u64::from_be_bytes([0, 0, 0, b'P', b'A', b'N', b'D', b'A']);
 
 #[derive(CandidType, Clone, Serialize)]
 pub struct CaptchaOutput {
@@ -22,6 +32,47 @@ pub struct AirdropHarvestInput {
     // Token amount in E8
     pub amount: Nat,
     pub recaptcha: Option<String>, // deprecated
+    // the account to receive tokens, the caller's default account if None
+    pub to: Option<Account>,
+    // the idempotency key of the harvest, in nanoseconds within the last 23 hours.
+    // A retry with the same created_at is deduplicated and not debited again.
+    pub created_at: Option<u64>,
+}
+
+// the public state of the pool, without the internal counters of State.
+#[derive(CandidType, Clone, Serialize)]
+pub struct StateOutput {
+    pub airdrop_balance: u64,
+    pub total_airdrop: u64,
+    pub total_airdrop_count: u64,
+    pub total_luckydraw: u64,
+    pub total_luckydraw_icp: u64,
+    pub total_luckydraw_count: u64,
+    pub total_prize: Option<u64>,
+    pub total_prize_count: Option<u64>,
+    pub latest_airdrop_logs: Vec<AirdropLog>, // latest 10 airdrop logs
+    pub luckiest_luckydraw_logs: Vec<LuckyDrawLog>, // latest 10 luckiest luckydraw logs
+    pub latest_luckydraw_logs: Vec<LuckyDrawLog>, // latest 10 luckydraw logs
+    pub managers: Option<BTreeSet<Principal>>,
+    pub airdrop_amount: Option<u64>,
+    pub luckydraw_max_expected: Option<u64>, // max expected tokens of the payout table for 1 ICP
+    pub jackpot_pool: Option<u64>, // progressive jackpot tokens in E8, paid out entirely on a jackpot hit
+    pub jackpot_share: Option<u16>, // share of each draw's ICP in PANDA equivalent added to the jackpot pool, in basis points
+    pub archive_canister: Option<Principal>, // the spawned archive canister of old logs
+    pub archive_threshold: Option<u64>, // local logs of each kind that trigger archiving, None disables it
+    pub airdrop_logs_archived: Option<u64>, // number of airdrop logs moved to the archive, the id of the oldest local log
+    pub luckydraw_logs_archived: Option<u64>, // number of luckydraw logs moved to the archive, the id of the oldest local log
+    pub checkpointed_at: Option<u64>, // the time in seconds of the last checkpoint into stable memory
+    pub roles: Option<BTreeMap<Principal, BTreeSet<Role>>>, // scoped roles of managers, replace managers once set
+    pub ledger_balance: Option<u64>, // PANDA balance of the pool on the ledger at the last reconciliation
+    pub reconciled_at: Option<u64>, // the time in seconds of the last reconciliation against the ledger
+    pub liability_alert: Option<bool>, // the outstanding claimable tokens exceed the airdrop balance
+    pub prize_reserved: Option<u64>,   // unclaimed budgets of the open prizes funded by the pool
+    pub total_credited: Option<u64>, // tokens credited to claimable balances by claims, referrals and prizes
+    pub total_harvested: Option<u64>, // tokens harvested from claimable balances
+    pub total_claimable: Option<u64>, // outstanding claimable tokens of all users, the liability of the pool
+    pub luckydraw_burn_pending: Option<u64>, // PANDA paid for luckydraws that is waiting to be burned
+    pub total_luckydraw_burned: Option<u64>, // PANDA paid for luckydraws that has been burned
 }
 
 #[derive(CandidType, Clone, Serialize)]
@@ -33,11 +84,140 @@ pub struct AirdropStateOutput {
     pub claimable: Nat,
 }
 
+// (Issuer code, Issue time, Expire, Claimable amount, Quantity, Filled quantity, Prize id,
+// Luckiest claimer with the claimed tokens in E8 for the random and tiers distributions)
+pub type PrizeOutput = (
+    u32,
+    u32,
+    u16,
+    u32,
+    u16,
+    u16,
+    String,
+    Option<(Principal, u64)>,
+);
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct PrizeClaimsOutput {
+    pub id: String,
+    pub issuer: String, // the lucky code of the issuer
+    pub issued_at: u64, // in seconds
+    pub expire: u16,    // in minutes
+    pub claimable: u32, // in tokens
+    pub quantity: u16,
+    pub filled: u16,
+    pub claimed: Nat, // claimed tokens in E8
+    pub closed: bool,
+    // the claims in the claiming order, only for the issuer
+    pub claims: Option<Vec<PrizeClaim>>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct PrizeClaim {
+    pub user: Principal,
+    pub amount: Nat,             // in E8
+    pub claimed_at: Option<u64>, // in seconds, absent for the claims before it was recorded
+}
+
+// the certified subset of the state.
+#[derive(CandidType, Clone, Serialize)]
+pub struct StateSummary {
+    pub airdrop_balance: u64,
+    pub total_airdrop: u64,
+    pub total_airdrop_count: u64,
+    pub total_luckydraw: u64,
+    pub total_luckydraw_icp: u64,
+    pub total_luckydraw_count: u64,
+    pub total_prize: u64,
+    pub total_prize_count: u64,
+    pub jackpot_pool: u64,
+}
+
+impl From<&StateSummary> for ICRC3Value {
+    fn from(s: &StateSummary) -> Self {
+        ICRC3Value::Map(BTreeMap::from([
+            ("airdrop_balance".to_string(), s.airdrop_balance.into()),
+            ("total_airdrop".to_string(), s.total_airdrop.into()),
+            (
+                "total_airdrop_count".to_string(),
+                s.total_airdrop_count.into(),
+            ),
+            ("total_luckydraw".to_string(), s.total_luckydraw.into()),
+            (
+                "total_luckydraw_icp".to_string(),
+                s.total_luckydraw_icp.into(),
+            ),
+            (
+                "total_luckydraw_count".to_string(),
+                s.total_luckydraw_count.into(),
+            ),
+            ("total_prize".to_string(), s.total_prize.into()),
+            ("total_prize_count".to_string(), s.total_prize_count.into()),
+            ("jackpot_pool".to_string(), s.jackpot_pool.into()),
+        ]))
+    }
+}
+
+impl From<&AirdropStateOutput> for ICRC3Value {
+    fn from(s: &AirdropStateOutput) -> Self {
+        let mut m = BTreeMap::from([
+            ("claimed".to_string(), ICRC3Value::Nat(s.claimed.clone())),
+            (
+                "claimable".to_string(),
+                ICRC3Value::Nat(s.claimable.clone()),
+            ),
+        ]);
+        if let Some(code) = &s.lucky_code {
+            m.insert("lucky_code".to_string(), ICRC3Value::Text(code.clone()));
+        }
+        ICRC3Value::Map(m)
+    }
+}
+
+// the certificate of the canister and the witness of the certified data in a response.
+#[derive(CandidType, Clone, Serialize)]
+pub struct Certification {
+    pub certificate: ByteBuf,
+    // CBOR encoded hash tree
+    pub witness: ByteBuf,
+}
+
 #[derive(CandidType, Clone, Deserialize)]
 pub struct LuckyDrawInput {
     // ICP tokens to be used for luckydraw, [0.1, 10]
     pub icp: u8,
     pub amount: Option<Nat>,
+    // draw in provably fair mode with the client seed, at most 64 bytes
+    pub client_seed: Option<String>,
+    // the ledger of an accepted payment token, ICP if None.
+    // amount is in the smallest units of the token.
+    pub ledger: Option<Principal>,
+    // the account to receive tokens, the caller's default account if None
+    pub to: Option<Account>,
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub struct LuckyDrawBatchInput {
+    // tokens in the smallest units to be used for each draw, [0.1, 100] ICP equivalent
+    pub amount: Nat,
+    // number of draws, [1, 10]
+    pub count: u8,
+    // draw in provably fair mode with the client seed, at most 64 bytes
+    pub client_seed: Option<String>,
+    // the ledger of an accepted payment token, ICP if None
+    pub ledger: Option<Principal>,
+    // the account to receive tokens, the caller's default account if None
+    pub to: Option<Account>,
+}
+
+#[derive(CandidType, Clone, Serialize)]
+pub struct LuckyDrawBatchOutput {
+    // the journal id of the batch, shared by all draws in the batch
+    pub batch_id: Nat,
+    // total token amount in E8
+    pub amount: Nat,
+    pub logs: Vec<LuckyDrawLog>,
+    pub airdrop_cryptogram: Option<String>,
 }
 
 #[derive(CandidType, Clone, Serialize)]
@@ -51,11 +231,111 @@ pub struct LuckyDrawOutput {
     pub airdrop_cryptogram: Option<String>,
 }
 
-#[derive(CandidType, Clone, Deserialize)]
+#[derive(CandidType, Clone, Deserialize, Serialize)]
 pub struct AddPrizeInput {
     pub expire: u16,    // in minutes, should be less than 60*24*30
     pub claimable: u32, // in tokens, should be less than 100_000
     pub quantity: u16,  // should be less than 10000
+    pub distribution: Option<PrizeDistribution>, // equal split when absent
+    pub conditions: Option<PrizeConditions>, // at least 10 tokens to claim when absent
+}
+
+impl AddPrizeInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.expire < 10 {
+            return Err("expire should be at least 10 minutes".to_string());
+        }
+        if self.claimable == 0 {
+            return Err("claimable should be at least 1 token".to_string());
+        }
+        if self.quantity == 0 {
+            return Err("quantity should be at least 1".to_string());
+        }
+        if self.expire > 60 * 24 * 30 {
+            return Err("expire should be less than 60*24*30".to_string());
+        }
+        if self.claimable > 100_000 {
+            return Err("claimable should be less than 100_000".to_string());
+        }
+        if self.quantity > 10_000 {
+            return Err("quantity should be less than 10_000".to_string());
+        }
+        if let Some(ref distribution) = self.distribution {
+            distribution.validate(self.quantity)?;
+        }
+        if let Some(ref conditions) = self.conditions {
+            conditions.validate()?;
+        }
+        Ok(())
+    }
+}
+
+// The conditions to claim a prize, a claimer should meet all of them.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PrizeConditions {
+    // in tokens, the claimable tokens and the PANDA balance of the claimer, 10 when absent
+    pub min_balance: Option<u32>,
+    // the claimer should be in one of the allowlists when any of them is present
+    pub principals: Option<BTreeSet<Principal>>,
+    pub lucky_codes: Option<BTreeSet<String>>,
+    // the claimer should have a verified XAuth identity
+    pub xauth: bool,
+    // the claimer should not have claimed any prize before
+    pub new_user: bool,
+}
+
+impl PrizeConditions {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_balance.is_some_and(|b| b > 100_000_000) {
+            return Err("min_balance should be less than 100_000_000".to_string());
+        }
+        let principals = self.principals.as_ref().map_or(0, |s| s.len());
+        let lucky_codes = self.lucky_codes.as_ref().map_or(0, |s| s.len());
+        if principals + lucky_codes > 1000 {
+            return Err("allowlists should have at most 1000 entries".to_string());
+        }
+        if let Some(ref codes) = self.lucky_codes {
+            for code in codes {
+                luckycode_from_string(code)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// How the claimable tokens of a prize are split between the claimers.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub enum PrizeDistribution {
+    #[default]
+    Equal, // each claimer gets claimable / quantity
+    Random, // each claimer gets a random share, the last claimer gets the remainder
+    // (quantity, weight) tiers in the claiming order, the tiers' quantities sum to the prize quantity,
+    // a claimer gets claimable * weight / sum(quantity * weight) of the tier.
+    Tiers(Vec<(u16, u16)>),
+}
+
+impl PrizeDistribution {
+    pub fn validate(&self, quantity: u16) -> Result<(), String> {
+        if let PrizeDistribution::Tiers(tiers) = self {
+            if tiers.is_empty() || tiers.len() > 10 {
+                return Err("tiers should be 1 to 10".to_string());
+            }
+            if tiers.iter().any(|(q, w)| *q == 0 || *w == 0) {
+                return Err("tier quantity and weight should be at least 1".to_string());
+            }
+            if tiers.iter().map(|(q, _)| *q as u32).sum::<u32>() != quantity as u32 {
+                return Err("tiers quantity should sum to the prize quantity".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+// The funding of a prize created by a user.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum PrizeFunding {
+    Claimable, // lock the issuer's claimable tokens
+    Deposit,   // transfer PANDA tokens from the issuer with ICRC-2 approval
 }
 
 #[derive(CandidType, Clone, Deserialize, Serialize)]
@@ -65,6 +345,87 @@ pub struct Notification {
     pub timeout: u16,
     pub dismiss: bool,
     pub message: String,
+    // active from start_at (in seconds), always active when absent.
+    pub start_at: Option<u64>,
+    // expired at end_at (in seconds), never expired when absent.
+    pub end_at: Option<u64>,
+    // the users to notify, all users when absent.
+    pub audience: Option<NotificationAudience>,
+    // localized messages by language tag, such as "zh" or "zh-CN".
+    pub locales: Option<BTreeMap<String, String>>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct BannedUser {
+    pub user: Principal,
+    // the former lucky code, restored on unban
+    pub lucky_code: String,
+    pub reason: String,
+    pub moderator: Principal,
+    pub banned_at: u64,
+    pub expire_at: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct AuditLog {
+    pub id: u64,
+    pub ts: u64,
+    pub caller: Principal,
+    pub method: String,
+    // SHA3-256 digest of the CBOR encoded arguments
+    pub args_digest: ByteBuf,
+    // CBOR encoded value replaced by the call, if any
+    pub prev_value: Option<ByteBuf>,
+    // SHA3-256 hash of the previous audit log, zeros for the first log
+    pub phash: ByteBuf,
+    // SHA3-256 hash of this audit log, the phash of the next log
+    pub hash: ByteBuf,
+}
+
+// The scoped roles of managers.
+#[derive(
+    CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum Role {
+    Notifier,    // manages notifications
+    PrizeIssuer, // issues prizes
+    Moderator,   // bans users
+    Treasurer,   // updates the airdrop balance and amount
+    KeyAdmin,    // reads the airdrop key and sets the challenge public key
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub enum NotificationAudience {
+    All,
+    LuckyCodeHolders,
+    Principals(BTreeSet<Principal>),
+}
+
+impl Notification {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.message.is_empty() {
+            return Err("message should not be empty".to_string());
+        }
+        if let (Some(start_at), Some(end_at)) = (self.start_at, self.end_at) {
+            if end_at <= start_at {
+                return Err("end_at should be greater than start_at".to_string());
+            }
+        }
+        if let Some(locales) = &self.locales {
+            if locales.iter().any(|(k, v)| k.is_empty() || v.is_empty()) {
+                return Err("locale and localized message should not be empty".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_active(&self, now_sec: u64) -> bool {
+        self.start_at.is_none_or(|t| t <= now_sec) && self.end_at.is_none_or(|t| now_sec < t)
+    }
+
+    pub fn is_expired(&self, now_sec: u64) -> bool {
+        self.end_at.is_some_and(|t| t <= now_sec)
+    }
 }
 
 #[derive(CandidType, Clone, Deserialize, Serialize)]
@@ -72,8 +433,31 @@ pub struct AirdropLog {
     pub id: Nat,
     pub ts: u64,
     pub caller: Principal,
+    // debited tokens in E8, kept for compatibility
     pub amount: Nat,
+    // the lucky code of the counterparty: the referrer of a claim, the issuer of a prize,
+    // the referred user of a referral bonus, the former code of a banned user
     pub lucky_code: String,
+    // the account received tokens if it is not the caller's default account
+    pub to: Option<Account>,
+    pub kind: AirdropLogKind,
+    // tokens in E8 added to the claimable balance
+    pub credited: Nat,
+    // tokens in E8 removed from the claimable balance
+    pub debited: Nat,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum AirdropLogKind {
+    Claim,
+    ReferralBonus,
+    PrizeClaim,
+    Harvest,
+    Ban,
+    ManualAdjustment,
+    Unban,
+    PrizeLock,
+    PrizeRefund,
 }
 
 #[derive(CandidType, Clone, Deserialize, Serialize)]
@@ -84,6 +468,357 @@ pub struct LuckyDrawLog {
     pub amount: Nat,
     pub icp_amount: Nat,
     pub random: u64,
+    pub provably_fair: Option<ProvablyFair>,
+    pub batch_id: Option<Nat>,
+    // the payment in a token other than ICP, icp_amount is its ICP equivalent
+    pub payment: Option<LuckyDrawPayment>,
+    // the account received tokens if it is not the caller's default account
+    pub to: Option<Account>,
+}
+
+candid::define_function!(pub AirdropLogsFn : (Option<Nat>, Option<Nat>) -> (Vec<AirdropLog>) query);
+candid::define_function!(pub LuckyDrawLogsFn : (Option<Nat>, Option<Nat>) -> (Vec<LuckyDrawLog>) query);
+
+#[derive(CandidType, Clone)]
+pub struct AirdropLogsOutput {
+    pub logs: Vec<AirdropLog>,
+    // the older logs that have been moved to the archive canister
+    pub archived: Option<ArchivedAirdropLogs>,
+}
+
+// call the callback with (opt prev, opt take) to get the archived logs.
+#[derive(CandidType, Clone)]
+pub struct ArchivedAirdropLogs {
+    pub prev: Nat,
+    pub take: Nat,
+    pub callback: AirdropLogsFn,
+}
+
+#[derive(CandidType, Clone)]
+pub struct LuckyDrawLogsOutput {
+    pub logs: Vec<LuckyDrawLog>,
+    // the older logs that have been moved to the archive canister
+    pub archived: Option<ArchivedLuckyDrawLogs>,
+}
+
+// call the callback with (opt prev, opt take) to get the archived logs.
+#[derive(CandidType, Clone)]
+pub struct ArchivedLuckyDrawLogs {
+    pub prev: Nat,
+    pub take: Nat,
+    pub callback: LuckyDrawLogsFn,
+}
+
+// the init args of the archive canister.
+#[derive(CandidType, Clone)]
+pub struct ArchiveInitArgs {
+    pub pool: Principal,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct LuckyDrawPayment {
+    pub ledger: Principal,
+    // tokens paid in the smallest units, including the ledger fee
+    pub amount: Nat,
+}
+
+// PaymentToken is an ICRC-2 token accepted for luckydraw besides ICP.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct PaymentToken {
+    pub symbol: String,
+    // transfer fee of the ledger in the smallest units
+    pub fee: u64,
+    // the price ratio: tokens in the smallest units equivalent to 1 ICP
+    pub per_icp: u64,
+}
+
+impl PaymentToken {
+    pub fn icp() -> Self {
+        PaymentToken {
+            symbol: "ICP".to_string(),
+            fee: TRANS_FEE,
+            per_icp: ICP_1,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.symbol.is_empty() || self.symbol.len() > 16 {
+            return Err("symbol should be 1 to 16 bytes".to_string());
+        }
+        if self.per_icp < 10 {
+            return Err("per_icp should be at least 10".to_string());
+        }
+        // a draw of 0.1 ICP should afford the fees of the intake and a refund
+        if self.fee.saturating_mul(2) >= self.per_icp / 10 {
+            return Err("fee is too high for a draw of 0.1 ICP".to_string());
+        }
+        Ok(())
+    }
+}
+
+// The random bytes of a provably fair draw are
+// HMAC-SHA3-256(server_seed, CBOR([client_seed, nonce])).
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct ProvablyFair {
+    pub seed_epoch: u32,
+    pub client_seed: String,
+    pub nonce: u64,
+    // the version of the payout table used by the draw
+    pub table_version: u32,
+}
+
+// LuckyDrawTable is the payout schedule of luckydraw.
+// The lucky number of a draw is (random % modulus) / TOKEN_1, and its prize (tokens for 1 ICP) is:
+// the jackpot if the lucky number is in [0, jackpot_max],
+// the amount of the first band whose max lucky number is not less than it,
+// or the lucky number itself if it is greater than all bands.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct LuckyDrawTable {
+    pub modulus: u64,
+    pub jackpot_max: u64,
+    // jackpot tokens for 1 ICP
+    pub jackpot: u64,
+    // (max lucky number, tokens for 1 ICP), sorted by max lucky number
+    pub bands: Vec<(u64, u64)>,
+    // lifetime cap of tokens drawn from the pool
+    pub total_cap: u64,
+}
+
+impl Default for LuckyDrawTable {
+    fn default() -> Self {
+        LuckyDrawTable {
+            modulus: LUCKYDRAW_DIVISOR,
+            jackpot_max: 5,
+            jackpot: 100_000,
+            bands: vec![(1000, 1000)],
+            total_cap: 420_000_000,
+        }
+    }
+}
+
+impl LuckyDrawTable {
+    // the prize of a lucky number in tokens for 1 ICP.
+    pub fn prize(&self, lucky_number: u64) -> u64 {
+        if lucky_number <= self.jackpot_max {
+            return self.jackpot;
+        }
+        for (max, amount) in &self.bands {
+            if lucky_number <= *max {
+                return *amount;
+            }
+        }
+        lucky_number
+    }
+
+    pub fn is_jackpot(&self, x: u64) -> bool {
+        x / TOKEN_1 <= self.jackpot_max
+    }
+
+    // the expected tokens for 1 ICP.
+    pub fn expected(&self) -> u64 {
+        // lucky numbers in [0, n) have the same weight TOKEN_1, the lucky number n has weight rem.
+        let n = self.modulus / TOKEN_1;
+        let rem = self.modulus % TOKEN_1;
+        let mut segments: Vec<(u64, u64, Option<u64>)> =
+            vec![(0, self.jackpot_max, Some(self.jackpot))];
+        let mut lo = self.jackpot_max.saturating_add(1);
+        for (max, amount) in &self.bands {
+            segments.push((lo, *max, Some(*amount)));
+            lo = max.saturating_add(1);
+        }
+        segments.push((lo, u64::MAX, None));
+
+        let mut total: u128 = 0;
+        for (lo, hi, amount) in segments {
+            let hi = hi.min(n.saturating_sub(1));
+            if n == 0 || lo > hi {
+                continue;
+            }
+            let count = (hi - lo + 1) as u128;
+            total += match amount {
+                Some(amount) => count * amount as u128,
+                None => (lo as u128 + hi as u128) * count / 2,
+            };
+        }
+        let total = total * TOKEN_1 as u128 + rem as u128 * self.prize(n) as u128;
+        (total / self.modulus.max(1) as u128) as u64
+    }
+
+    pub fn validate(&self, max_expected: u64) -> Result<(), String> {
+        if self.modulus < TOKEN_1 * 100 {
+            return Err("modulus should be at least 100 tokens".to_string());
+        }
+        if self.jackpot_max >= self.modulus / TOKEN_1 {
+            return Err("jackpot_max should be less than the max lucky number".to_string());
+        }
+        if self.jackpot == 0 {
+            return Err("jackpot should be at least 1 token".to_string());
+        }
+        if self.bands.len() > 100 {
+            return Err("bands should be at most 100".to_string());
+        }
+        let mut prev = self.jackpot_max;
+        for (max, amount) in &self.bands {
+            if *max <= prev {
+                return Err("bands should be sorted by max lucky number".to_string());
+            }
+            if *amount == 0 || *amount > self.jackpot {
+                return Err("band amount should be in [1, jackpot]".to_string());
+            }
+            prev = *max;
+        }
+        if self.total_cap == 0 {
+            return Err("total_cap should be at least 1 token".to_string());
+        }
+        let expected = self.expected();
+        if expected > max_expected {
+            return Err(format!(
+                "expected payout {} tokens per ICP exceeds the limit {}",
+                expected, max_expected
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct LuckyDrawSeed {
+    pub epoch: u32,
+    pub ts: u64,
+    // SHA3-256 hash of the server seed in hex, published before the seed is used
+    pub commitment: String,
+    // server seed in hex, revealed after the seed is rotated
+    pub seed: Option<String>,
+    // how many provably fair draws used the seed
+    pub nonce: u64,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct LuckyDrawVerification {
+    pub log: LuckyDrawLog,
+    pub seed: LuckyDrawSeed,
+    // random lucky number recomputed from the revealed server seed
+    pub random: u64,
+    // token amount in E8 recomputed for the ICP paid
+    pub amount: Nat,
+    pub verified: bool,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum LuckyDrawStage {
+    // ICP has been received, the payout has not been sent yet
+    IcpReceived,
+    // the payout has been sent to the ledger, waiting for the result
+    PayoutSent,
+    // the payout was not delivered, the payment should be refunded to the user
+    RefundOwed,
+}
+
+// How a manager resolves a luckydraw whose payout can not be resent.
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum LuckyDrawResolution {
+    Delivered, // the payout is found on the ledger, settle the luckydraw
+    Refund,    // the payout is not found on the ledger, refund the payment
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct LuckyDrawJournal {
+    pub id: Nat,
+    pub ts: u64,
+    pub caller: Principal,
+    // ICP received by the canister in E8s, or the ICP equivalent of the payment in another token
+    pub icp_amount: Nat,
+    // Token amount to pay out in E8s
+    pub amount: Nat,
+    pub stage: LuckyDrawStage,
+    // the payment in a token other than ICP, with the ledger and the amount paid
+    pub payment: Option<LuckyDrawPayment>,
+}
+
+// ICRC3Value is the generic value of an ICRC-3 block.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ICRC3Value {
+    Blob(ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<ICRC3Value>),
+    Map(BTreeMap<String, ICRC3Value>),
+}
+
+impl ICRC3Value {
+    // the representation-independent hash of the value.
+    pub fn hash(&self) -> [u8; 32] {
+        Value::from(self.clone()).hash()
+    }
+}
+
+impl From<ICRC3Value> for Value {
+    fn from(value: ICRC3Value) -> Self {
+        match value {
+            ICRC3Value::Blob(v) => Value::Blob(v),
+            ICRC3Value::Text(v) => Value::Text(v),
+            ICRC3Value::Nat(v) => Value::Nat(v),
+            ICRC3Value::Int(v) => Value::Int(v),
+            ICRC3Value::Array(v) => Value::Array(v.into_iter().map(Value::from).collect()),
+            ICRC3Value::Map(v) => Value::Map(v.into_iter().map(|(k, v)| (k, v.into())).collect()),
+        }
+    }
+}
+
+impl From<u64> for ICRC3Value {
+    fn from(n: u64) -> Self {
+        ICRC3Value::Nat(Nat::from(n))
+    }
+}
+
+impl From<&Account> for ICRC3Value {
+    fn from(account: &Account) -> Self {
+        let mut parts = vec![ICRC3Value::Blob(ByteBuf::from(account.owner.as_slice()))];
+        if let Some(subaccount) = account.subaccount {
+            parts.push(ICRC3Value::Blob(ByteBuf::from(subaccount)));
+        }
+        ICRC3Value::Array(parts)
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct GetBlocksArgs {
+    pub start: Nat,
+    pub length: Nat,
+}
+
+#[derive(CandidType, Clone)]
+pub struct BlockWithId {
+    pub id: Nat,
+    pub block: ICRC3Value,
+}
+
+#[derive(CandidType, Clone)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: QueryArchiveFn<Vec<GetBlocksArgs>, GetBlocksResult>,
+}
+
+#[derive(CandidType, Clone)]
+pub struct GetBlocksResult {
+    pub log_length: Nat,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Clone, Serialize)]
+pub struct ICRC3DataCertificate {
+    // the certificate of the canister's certified data
+    pub certificate: ByteBuf,
+    // CBOR encoded hash tree of last_block_index and last_block_hash
+    pub hash_tree: ByteBuf,
+}
+
+#[derive(CandidType, Clone, Serialize)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
 }
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -123,6 +858,39 @@ impl ChallengeState {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_luckydraw_table() {
+        let table = LuckyDrawTable::default();
+        assert_eq!(table.prize(0), 100_000);
+        assert_eq!(table.prize(5), 100_000);
+        assert_eq!(table.prize(6), 1000);
+        assert_eq!(table.prize(1000), 1000);
+        assert_eq!(table.prize(1001), 1001);
+        assert_eq!(table.expected(), 2040);
+        assert!(table.validate(2040).is_ok());
+        assert!(table.validate(2039).is_err());
+
+        let table = LuckyDrawTable {
+            bands: vec![(1000, 1000), (500, 500)],
+            ..Default::default()
+        };
+        assert!(table.validate(10000).is_err());
+    }
+
+    #[test]
+    fn test_payment_token() {
+        assert!(PaymentToken::icp().validate().is_ok());
+
+        let token = PaymentToken {
+            symbol: "ckBTC".to_string(),
+            fee: 10,
+            per_icp: 15_000,
+        };
+        assert!(token.validate().is_ok());
+        let token = PaymentToken { fee: 750, ..token };
+        assert!(token.validate().is_err());
+    }
+
     #[test]
     fn test_challenge() {
         let key = b"secret key";
@@ -0,0 +1,86 @@
+use candid::{Nat, Principal};
+use num_traits::cast::ToPrimitive;
+
+mod store;
+mod types;
+
+fn nat_to_u64(nat: &Nat) -> u64 {
+    nat.0.to_u64().unwrap_or(0)
+}
+
+fn is_pool() -> Result<(), String> {
+    if ic_cdk::caller() == store::state::pool() {
+        Ok(())
+    } else {
+        Err("user is not the luckypool".to_string())
+    }
+}
+
+#[ic_cdk::init]
+fn init(args: types::InitArgs) {
+    store::state::init(args.pool);
+}
+
+#[ic_cdk::query]
+fn api_version() -> u16 {
+    1
+}
+
+#[ic_cdk::query]
+fn state() -> Result<types::StateOutput, ()> {
+    Ok(store::state::output())
+}
+
+// append airdrop logs moved from the luckypool, return the next log id.
+#[ic_cdk::update(guard = "is_pool")]
+fn append_airdrop_logs(logs: Vec<types::AirdropLog>) -> Result<u64, String> {
+    store::airdrop::append(logs)
+}
+
+// append luckydraw logs moved from the luckypool, return the next log id.
+#[ic_cdk::update(guard = "is_pool")]
+fn append_luckydraw_logs(logs: Vec<types::LuckyDrawLog>) -> Result<u64, String> {
+    store::luckydraw::append(logs)
+}
+
+// get archived airdrop logs in reverse order, it is the callback of the luckypool's airdrop_logs.
+#[ic_cdk::query]
+fn airdrop_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<types::AirdropLog> {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::airdrop::logs(prev, take)
+}
+
+// get archived luckydraw logs in reverse order, it is the callback of the luckypool's luckydraw_logs.
+#[ic_cdk::query]
+fn luckydraw_logs(prev: Option<Nat>, take: Option<Nat>) -> Vec<types::LuckyDrawLog> {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::luckydraw::logs(prev, take)
+}
+
+// get the archived airdrop logs of a user in reverse order, starting before `prev`.
+#[ic_cdk::query]
+fn airdrop_logs_of(
+    owner: Principal,
+    prev: Option<Nat>,
+    take: Option<Nat>,
+) -> Vec<types::AirdropLog> {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::airdrop::logs_of(owner, prev, take)
+}
+
+// get the archived luckydraw logs of a user in reverse order, starting before `prev`.
+#[ic_cdk::query]
+fn luckydraw_logs_of(
+    owner: Principal,
+    prev: Option<Nat>,
+    take: Option<Nat>,
+) -> Vec<types::LuckyDrawLog> {
+    let prev = prev.as_ref().map(nat_to_u64);
+    let take = take.as_ref().map(nat_to_u64).unwrap_or(10).min(100) as usize;
+    store::luckydraw::logs_of(owner, prev, take)
+}
+
+ic_cdk::export_candid!();
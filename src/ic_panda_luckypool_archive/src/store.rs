@@ -0,0 +1,305 @@
+use candid::Principal;
+use ciborium::{from_reader, into_writer};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    storable::Bound,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+};
+use serde::{Deserialize, Serialize};
+use std::{borrow::Cow, cell::RefCell};
+
+use crate::types;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct State {
+    pub pool: Principal,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State {
+            pool: Principal::anonymous(),
+        }
+    }
+}
+
+impl Storable for State {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode State data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode State data")
+    }
+}
+
+impl Storable for types::AirdropLog {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode AirdropLog data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode AirdropLog data")
+    }
+}
+
+impl Storable for types::LuckyDrawLog {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode LuckyDrawLog data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode LuckyDrawLog data")
+    }
+}
+
+const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const AIRDROP_LOGS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const LUCKYDRAW_LOGS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const AIRDROP_LOG_USER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const LUCKYDRAW_LOG_USER_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE: RefCell<StableCell<State, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
+            State::default()
+        ).expect("failed to init STATE store")
+    );
+
+    // log id -> airdrop log
+    static AIRDROP_LOGS: RefCell<StableBTreeMap<u64, types::AirdropLog, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AIRDROP_LOGS_MEMORY_ID)),
+        )
+    );
+
+    // log id -> luckydraw log
+    static LUCKYDRAW_LOGS: RefCell<StableBTreeMap<u64, types::LuckyDrawLog, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYDRAW_LOGS_MEMORY_ID)),
+        )
+    );
+
+    // (user, !log id) -> (), the airdrop logs of a user in reverse order
+    static AIRDROP_LOG_USER_INDEX: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AIRDROP_LOG_USER_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // (user, !log id) -> (), the luckydraw logs of a user in reverse order
+    static LUCKYDRAW_LOG_USER_INDEX: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(LUCKYDRAW_LOG_USER_INDEX_MEMORY_ID)),
+        )
+    );
+}
+
+// the next log id of an archive.
+fn next_id<T: Storable>(logs: &StableBTreeMap<u64, T, Memory>) -> u64 {
+    logs.last_key_value().map(|(k, _)| k + 1).unwrap_or(0)
+}
+
+// append logs in order and index them by the user, the logs that have been archived
+// are skipped so that the pool can retry a batch safely. Return the next log id.
+fn append<T: Storable>(
+    logs: &mut StableBTreeMap<u64, T, Memory>,
+    index: &mut StableBTreeMap<(Principal, u64), (), Memory>,
+    entries: Vec<T>,
+    id_of: impl Fn(&T) -> u64,
+    user_of: impl Fn(&T) -> Principal,
+) -> Result<u64, String> {
+    let mut next = next_id(logs);
+    for entry in entries {
+        let id = id_of(&entry);
+        if id < next {
+            continue;
+        }
+        if id > next {
+            return Err(format!("log {} is not continuous, expected {}", id, next));
+        }
+        index.insert((user_of(&entry), !id), ());
+        logs.insert(id, entry);
+        next += 1;
+    }
+    Ok(next)
+}
+
+// get logs in reverse order, starting before `prev`.
+fn logs_before<T: Storable>(
+    logs: &StableBTreeMap<u64, T, Memory>,
+    prev: Option<u64>,
+    take: usize,
+) -> Vec<T> {
+    let latest = next_id(logs);
+    let prev = prev.unwrap_or(latest).min(latest);
+    (0..prev)
+        .rev()
+        .take(take)
+        .filter_map(|id| logs.get(&id))
+        .collect()
+}
+
+// get the logs of a user in reverse order from a per-user index, starting before `prev`.
+fn logs_of<T: Storable>(
+    logs: &StableBTreeMap<u64, T, Memory>,
+    index: &StableBTreeMap<(Principal, u64), (), Memory>,
+    user: Principal,
+    prev: Option<u64>,
+    take: usize,
+) -> Vec<T> {
+    let start = match prev {
+        None => 0,
+        Some(0) => return vec![],
+        Some(prev) => !(prev - 1),
+    };
+    index
+        .range((user, start)..=(user, u64::MAX))
+        .take(take)
+        .filter_map(|((_, id), _)| logs.get(&!id))
+        .collect()
+}
+
+pub mod state {
+    use super::*;
+
+    pub fn pool() -> Principal {
+        STATE.with(|r| r.borrow().get().pool)
+    }
+
+    pub fn init(pool: Principal) {
+        STATE.with(|r| {
+            r.borrow_mut()
+                .set(State { pool })
+                .expect("failed to set STATE data");
+        });
+    }
+
+    pub fn output() -> types::StateOutput {
+        types::StateOutput {
+            pool: pool(),
+            airdrop_logs: AIRDROP_LOGS.with(|r| next_id(&r.borrow())),
+            luckydraw_logs: LUCKYDRAW_LOGS.with(|r| next_id(&r.borrow())),
+        }
+    }
+}
+
+pub mod airdrop {
+    use super::*;
+    use crate::nat_to_u64;
+
+    pub fn append(logs: Vec<types::AirdropLog>) -> Result<u64, String> {
+        AIRDROP_LOGS.with(|r| {
+            AIRDROP_LOG_USER_INDEX.with(|m| {
+                super::append(
+                    &mut r.borrow_mut(),
+                    &mut m.borrow_mut(),
+                    logs,
+                    |log| nat_to_u64(&log.id),
+                    |log| log.caller,
+                )
+            })
+        })
+    }
+
+    pub fn logs(prev: Option<u64>, take: usize) -> Vec<types::AirdropLog> {
+        AIRDROP_LOGS.with(|r| logs_before(&r.borrow(), prev, take))
+    }
+
+    pub fn logs_of(user: Principal, prev: Option<u64>, take: usize) -> Vec<types::AirdropLog> {
+        AIRDROP_LOGS.with(|r| {
+            AIRDROP_LOG_USER_INDEX
+                .with(|m| super::logs_of(&r.borrow(), &m.borrow(), user, prev, take))
+        })
+    }
+}
+
+pub mod luckydraw {
+    use super::*;
+    use crate::nat_to_u64;
+
+    pub fn append(logs: Vec<types::LuckyDrawLog>) -> Result<u64, String> {
+        LUCKYDRAW_LOGS.with(|r| {
+            LUCKYDRAW_LOG_USER_INDEX.with(|m| {
+                super::append(
+                    &mut r.borrow_mut(),
+                    &mut m.borrow_mut(),
+                    logs,
+                    |log| nat_to_u64(&log.id),
+                    |log| log.caller,
+                )
+            })
+        })
+    }
+
+    pub fn logs(prev: Option<u64>, take: usize) -> Vec<types::LuckyDrawLog> {
+        LUCKYDRAW_LOGS.with(|r| logs_before(&r.borrow(), prev, take))
+    }
+
+    pub fn logs_of(user: Principal, prev: Option<u64>, take: usize) -> Vec<types::LuckyDrawLog> {
+        LUCKYDRAW_LOGS.with(|r| {
+            LUCKYDRAW_LOG_USER_INDEX
+                .with(|m| super::logs_of(&r.borrow(), &m.borrow(), user, prev, take))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use candid::Nat;
+
+    fn log(id: u64) -> types::LuckyDrawLog {
+        types::LuckyDrawLog {
+            id: Nat::from(id),
+            ts: id,
+            caller: Principal::from_slice(&[(id % 2) as u8]),
+            amount: Nat::from(0u64),
+            icp_amount: Nat::from(0u64),
+            random: 0,
+            provably_fair: None,
+            batch_id: None,
+            payment: None,
+            to: None,
+        }
+    }
+
+    #[test]
+    fn test_append_logs() {
+        assert_eq!(luckydraw::append((0..5).map(log).collect()), Ok(5));
+        // retried logs are skipped
+        assert_eq!(luckydraw::append((3..8).map(log).collect()), Ok(8));
+        assert!(luckydraw::append(vec![log(9)]).is_err());
+
+        let ids = |logs: Vec<types::LuckyDrawLog>| -> Vec<u64> {
+            logs.iter().map(|log| log.ts).collect()
+        };
+        assert_eq!(ids(luckydraw::logs(None, 3)), vec![7, 6, 5]);
+        assert_eq!(ids(luckydraw::logs(Some(2), 10)), vec![1, 0]);
+        assert!(luckydraw::logs(Some(0), 10).is_empty());
+
+        let (alice, bob) = (Principal::from_slice(&[0]), Principal::from_slice(&[1]));
+        assert_eq!(ids(luckydraw::logs_of(alice, None, 10)), vec![6, 4, 2, 0]);
+        assert_eq!(ids(luckydraw::logs_of(bob, Some(5), 10)), vec![3, 1]);
+        assert_eq!(ids(luckydraw::logs_of(bob, None, 2)), vec![7, 5]);
+        assert!(luckydraw::logs_of(alice, Some(0), 10).is_empty());
+    }
+}
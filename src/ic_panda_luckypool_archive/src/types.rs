@@ -0,0 +1,74 @@
+use candid::{CandidType, Nat, Principal};
+use icrc_ledger_types::icrc1::account::Account;
+use serde::{Deserialize, Serialize};
+
+// The log types are the same as the luckypool's outputs, so that the archived logs
+// can be fetched with the same candid types.
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct AirdropLog {
+    pub id: Nat,
+    pub ts: u64,
+    pub caller: Principal,
+    pub amount: Nat,
+    pub lucky_code: String,
+    pub to: Option<Account>,
+    pub kind: AirdropLogKind,
+    pub credited: Nat,
+    pub debited: Nat,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum AirdropLogKind {
+    Claim,
+    ReferralBonus,
+    PrizeClaim,
+    Harvest,
+    Ban,
+    ManualAdjustment,
+    Unban,
+    PrizeLock,
+    PrizeRefund,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct LuckyDrawLog {
+    pub id: Nat,
+    pub ts: u64,
+    pub caller: Principal,
+    pub amount: Nat,
+    pub icp_amount: Nat,
+    pub random: u64,
+    pub provably_fair: Option<ProvablyFair>,
+    pub batch_id: Option<Nat>,
+    pub payment: Option<LuckyDrawPayment>,
+    pub to: Option<Account>,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct LuckyDrawPayment {
+    pub ledger: Principal,
+    pub amount: Nat,
+}
+
+#[derive(CandidType, Clone, Deserialize, Serialize)]
+pub struct ProvablyFair {
+    pub seed_epoch: u32,
+    pub client_seed: String,
+    pub nonce: u64,
+    pub table_version: u32,
+}
+
+#[derive(CandidType, Clone, Deserialize)]
+pub struct InitArgs {
+    // the luckypool canister that appends logs
+    pub pool: Principal,
+}
+
+#[derive(CandidType, Clone, Serialize)]
+pub struct StateOutput {
+    pub pool: Principal,
+    // the number of archived airdrop logs, it is also the next log id
+    pub airdrop_logs: u64,
+    pub luckydraw_logs: u64,
+}